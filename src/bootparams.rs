@@ -66,6 +66,25 @@ pub unsafe fn get_cmdline<'a>(boot_params: *const c_void) -> Option<&'a str> {
   None
 }
 
+// the initrd loaded by the boot loader, if any
+pub unsafe fn get_ramdisk<'a>(boot_params: *const c_void) -> Option<&'a [u8]> {
+  let offset_ramdisk_image = 0x0218;
+  let offset_ramdisk_size = 0x021c;
+  let offset_ext_ramdisk_image = 0x00c0;
+  let offset_ext_ramdisk_size = 0x00c4;
+  let ramdisk_image =
+    (*((boot_params as *const u8).offset(offset_ext_ramdisk_image) as *const u32) as u64) << 32 |
+    *((boot_params as *const u8).offset(offset_ramdisk_image) as *const u32) as u64;
+  let ramdisk_size =
+    (*((boot_params as *const u8).offset(offset_ext_ramdisk_size) as *const u32) as u64) << 32 |
+    *((boot_params as *const u8).offset(offset_ramdisk_size) as *const u32) as u64;
+
+  if ramdisk_image == 0 || ramdisk_size == 0 {
+    return None;
+  }
+  Some(core::slice::from_raw_parts(ramdisk_image as *const u8, ramdisk_size as usize))
+}
+
 pub unsafe fn get_e820<'a>(boot_params: *const c_void) -> &'a [self::E820Entry] {
   let offset_e820_entries = 0x01e8;
   let offset_e820_table = 0x02d0;
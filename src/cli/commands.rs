@@ -0,0 +1,448 @@
+// commands of the cli. they read and change the same tables as the packet processing.
+
+use core::time::Duration;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::asynchronous::timer::TimerFuture;
+use crate::arch::x86_64::kvmclock::get_monotonic_time;
+use crate::cmdline;
+use crate::console;
+use crate::cmdline::IpPrefix;
+use crate::devices::netif::Netif;
+use crate::net::address;
+use crate::net::bond;
+use crate::net::bridge;
+use crate::net::lacp;
+use crate::net::lldp;
+use crate::net::rstp;
+use crate::net::vlan;
+use crate::net::ping;
+use crate::net::static_route;
+use crate::net::ethernet::MacAddress;
+use crate::net::ipv4::Ipv4Address;
+use crate::net::ipv6::Ipv6Address;
+use crate::net::fib::{FIBType, NeighborState, IPV4_ADJACENT, IPV6_ADJACENT, MAC_ADDR_TABLE, find_ipv4_fib_exact, find_ipv6_fib_exact};
+use crate::net::rib::{RibRoute, RouteSource, IPV4_RIB, IPV6_RIB, withdraw_ipv4_route, withdraw_ipv6_route, get_ipv4_routes, get_ipv6_routes};
+use crate::NET_IFACES;
+
+const HELP: &str = "\
+show ip route                              IPv4 routes of every source and what is installed
+show ipv6 route                            IPv6 routes of every source and what is installed
+show arp                                   IPv4 neighbors
+show ipv6 neighbors                        IPv6 neighbors
+show mac-address-table                     learned and local MAC addresses
+show interfaces                            interfaces and their addresses
+show bridge                                bridge domains, their ports and learned MAC addresses
+show spanning-tree                         root bridge, port roles and states of the rapid spanning tree
+show lacp                                  bond members, their LACP partners and which members are active
+show lldp neighbors                        neighbors announced by LLDP on each interface
+show console                               bytes dropped by the serial console
+ping <address> [<count>]                   send echo requests
+route add <prefix> <next hop> [<distance>] add a static route. next hops are as in urchin.route=
+route del <prefix>                         remove a static route
+address add <interface> <prefix>           add an address to an interface (index or MAC address)
+address del <interface> <address>          remove an address from an interface";
+
+const PING_TIMEOUT: Duration = Duration::from_secs(1);
+const PING_WAIT_INTERVAL: Duration = Duration::from_millis(10);
+const PING_PAYLOAD_LENGTH: usize = 56;
+
+// a line of output. unlike println!, which drops what doesn't fit in the tx ring, this waits for room.
+macro_rules! outln {
+  ($($arg:tt)*) => (output(format!($($arg)*)).await);
+}
+
+async fn output(mut line: String) {
+  if let Some(console) = console::get_console() {
+    line.push('\n');
+    console.write(line.as_bytes()).await;
+  }
+}
+
+fn netif_name(netif: &Arc<dyn Netif>) -> String {
+  match netif.get_drivername() {
+    "null" => String::from("null0"),
+    _ => format!("eth{}", netif.get_id()),
+  }
+}
+
+fn fib_type_name(fib_type: FIBType) -> &'static str {
+  match fib_type {
+    FIBType::Remote => "resolved",
+    FIBType::RemoteUnresolved => "unresolved",
+    FIBType::Adjacent => "connected",
+    FIBType::AdjacentResolved => "neighbor",
+    FIBType::Local => "local",
+    FIBType::Blackhole => "blackhole",
+    FIBType::Reject => "reject",
+  }
+}
+
+// seconds until a dynamic entry expires
+fn expire_text(expire_time: Option<u64>) -> String {
+  match expire_time {
+    Some(expire_time) => format!("{}s", expire_time.saturating_sub(get_monotonic_time()) / 1_000_000_000),
+    None => String::from("permanent"),
+  }
+}
+
+async fn print_route_candidates<A: Copy + core::fmt::Display>(prefix: String, candidates: &[RibRoute<A>], best: Option<RouteSource>) {
+  for (i, route) in candidates.iter().enumerate() {
+    let mut nexthops = String::new();
+    for nexthop in route.get_nexthops().iter() {
+      if nexthops.len() > 0 {
+        nexthops.push_str(", ");
+      }
+      match (route.get_fib_type(), nexthop.get_netif()) {
+        (FIBType::Blackhole, _) | (FIBType::Reject, _) => nexthops.push_str(fib_type_name(route.get_fib_type())),
+        (FIBType::Local, Some(netif)) => nexthops.push_str(&format!("local {}", netif_name(netif))),
+        (FIBType::Adjacent, Some(netif)) => nexthops.push_str(&format!("connected {}", netif_name(netif))),
+        (_, Some(netif)) => nexthops.push_str(&format!("via {} {}", nexthop.get_address(), netif_name(netif))),
+        (_, None) => nexthops.push_str(&format!("via {} (recursive)", nexthop.get_address())),
+      }
+      if nexthop.get_weight() > 1 {
+        nexthops.push_str(&format!(" weight {}", nexthop.get_weight()));
+      }
+    }
+    outln!(
+      "{}{:<42} {:<10} {:>3}/{:<6} {}",
+      if best == Some(route.get_source()) { "*" } else { " " },
+      if i == 0 { prefix.as_str() } else { "" },
+      route.get_source().get_name(), route.get_distance(), route.get_metric(), nexthops,
+    );
+  }
+}
+
+async fn show_ip_route() {
+  let entries: Vec<(Ipv4Address, u32, Vec<RibRoute<Ipv4Address>>, Option<RouteSource>)> = {
+    let rib = IPV4_RIB.lock();
+    rib.iter().map(|((prefix, prefix_length), candidates)| {
+      let best = rib.get_best_route(*prefix, *prefix_length).map(|route| route.get_source());
+      (*prefix, *prefix_length, candidates.clone(), best)
+    }).collect()
+  };
+
+  outln!(" {:<42} {:<10} {:>3}/{:<6} {}", "Prefix", "Source", "AD", "Metric", "Next hops");
+  for (prefix, prefix_length, candidates, best) in entries.iter() {
+    print_route_candidates(format!("{}/{}", prefix, prefix_length), candidates, *best).await;
+    let mask = 0xffffffffu32.checked_shl(32 - prefix_length).unwrap_or(0);
+    if let (Some(_), Some(group)) = (best, find_ipv4_fib_exact(prefix, mask)) {
+      for (fib, weight) in group.get_nexthops().iter() {
+        outln!(
+          "{:>55}-> {} {} {} weight {}",
+          "", fib.get_nexthop_address(), netif_name(fib.get_netif()), fib_type_name(fib.get_fib_type()), weight,
+        );
+      }
+    }
+  }
+  outln!("{} prefixes", entries.len());
+}
+
+async fn show_ipv6_route() {
+  let entries: Vec<(Ipv6Address, u32, Vec<RibRoute<Ipv6Address>>, Option<RouteSource>)> = {
+    let rib = IPV6_RIB.lock();
+    rib.iter().map(|((prefix, prefix_length), candidates)| {
+      let best = rib.get_best_route(*prefix, *prefix_length).map(|route| route.get_source());
+      (*prefix, *prefix_length, candidates.clone(), best)
+    }).collect()
+  };
+
+  outln!(" {:<42} {:<10} {:>3}/{:<6} {}", "Prefix", "Source", "AD", "Metric", "Next hops");
+  for (prefix, prefix_length, candidates, best) in entries.iter() {
+    print_route_candidates(format!("{}/{}", prefix, prefix_length), candidates, *best).await;
+    if let (Some(_), Some(group)) = (best, find_ipv6_fib_exact(prefix, *prefix_length)) {
+      for (fib, weight) in group.get_nexthops().iter() {
+        outln!(
+          "{:>55}-> {} {} {} weight {}",
+          "", fib.get_nexthop_address(), netif_name(fib.get_netif()), fib_type_name(fib.get_fib_type()), weight,
+        );
+      }
+    }
+  }
+  outln!("{} prefixes", entries.len());
+}
+
+async fn show_arp() {
+  let entries: Vec<(Ipv4Address, MacAddress, String, Option<u64>)> = IPV4_ADJACENT.lock().iter()
+    .filter(|(_, adj)| !adj.is_local())
+    .map(|(address, adj)| (*address, adj.get_mac_address(), netif_name(adj.get_netif()), adj.get_expire_time()))
+    .collect();
+
+  outln!("{:<16} {:<18} {:<10} {}", "Address", "MAC Address", "Interface", "Expires");
+  for (address, macaddr, netif, expire_time) in entries.iter() {
+    outln!("{:<16} {:<18} {:<10} {}", format!("{}", address), format!("{}", macaddr), netif, expire_text(*expire_time));
+  }
+}
+
+async fn show_ipv6_neighbors() {
+  let entries: Vec<(Ipv6Address, MacAddress, String, NeighborState)> = IPV6_ADJACENT.lock().iter()
+    .filter(|(_, adj)| !adj.is_local())
+    .map(|(address, adj)| (*address, adj.get_mac_address(), netif_name(adj.get_netif()), adj.get_state()))
+    .collect();
+
+  outln!("{:<40} {:<18} {:<10} {}", "Address", "MAC Address", "Interface", "State");
+  for (address, macaddr, netif, state) in entries.iter() {
+    let state = match state {
+      NeighborState::Incomplete(_) => "incomplete",
+      NeighborState::Reachable => "reachable",
+      NeighborState::Stale => "stale",
+      NeighborState::Delay => "delay",
+      NeighborState::Probe(_) => "probe",
+    };
+    outln!("{:<40} {:<18} {:<10} {}", format!("{}", address), format!("{}", macaddr), netif, state);
+  }
+}
+
+async fn show_mac_address_table() {
+  let entries: Vec<(MacAddress, String, bool, Option<u64>)> = MAC_ADDR_TABLE.lock().iter()
+    .map(|(macaddr, adj)| (*macaddr, netif_name(adj.get_netif()), adj.is_local(), adj.get_expire_time()))
+    .collect();
+
+  outln!("{:<18} {:<10} {:<8} {}", "MAC Address", "Interface", "Type", "Expires");
+  for (macaddr, netif, is_local, expire_time) in entries.iter() {
+    outln!(
+      "{:<18} {:<10} {:<8} {}",
+      format!("{}", macaddr), netif, if *is_local { "local" } else { "learned" }, expire_text(*expire_time),
+    );
+  }
+}
+
+async fn show_interfaces() {
+  for netif in unsafe { NET_IFACES.iter() } {
+    outln!(
+      "{}: driver {} mac {} mtu {}",
+      netif_name(netif), netif.get_drivername(), netif.get_macaddress(), netif.get_mtu(),
+    );
+    if let Some(vlan) = vlan::get_vlan(netif.get_id()) {
+      match vlan.get_outer_vid() {
+        Some(outer_vid) => outln!("  vlan {} in {} on {}", vlan.get_vid(), outer_vid, netif_name(vlan.get_parent())),
+        None => outln!("  vlan {} on {}", vlan.get_vid(), netif_name(vlan.get_parent())),
+      }
+    }
+    if let Some(bond) = bond::get_bond(netif.get_id()) {
+      let members: Vec<String> = bond.get_members().iter().map(|member| netif_name(member)).collect();
+      outln!("  bond of {}", members.join(" "));
+    }
+    for a in address::get_ipv4_addresses(netif.get_id()).iter() {
+      outln!("  inet {}/{}", a.get_address(), a.get_prefix_length());
+    }
+    for a in address::get_ipv6_addresses(netif.get_id()).iter() {
+      outln!("  inet6 {}/{}", a.get_address(), a.get_prefix_length());
+    }
+  }
+}
+
+async fn show_bridge() {
+  let bridges = bridge::get_bridges();
+  let now = get_monotonic_time();
+  for bridge in bridges.iter() {
+    outln!("br{}: aging {}s", bridge.get_id(), bridge.get_aging_time().as_secs());
+    for port in bridge.get_ports().iter() {
+      outln!("  port {} {} {}", netif_name(port.get_netif()), port.get_mode(), port.get_state());
+    }
+    outln!("  {:<6} {:<18} {:<10} {}", "VLAN", "MAC Address", "Port", "Expires");
+    for ((vid, macaddr), entry) in bridge.get_fdb().iter() {
+      outln!(
+        "  {:<6} {:<18} {:<10} {}s",
+        vid, format!("{}", macaddr), format!("eth{}", entry.get_port()), entry.get_expire_time().saturating_sub(now) / 1_000_000_000,
+      );
+    }
+  }
+}
+
+fn format_bridge_id(id: u64) -> String {
+  let (priority, macaddr) = rstp::format_bridge_id(id);
+  format!("{}.{}", priority, macaddr)
+}
+
+async fn show_spanning_tree() {
+  for tree in rstp::get_spanning_trees().iter() {
+    let root = tree.get_root();
+    outln!("br{}: bridge {}", tree.get_bridge_id(), format_bridge_id(tree.get_id()));
+    match tree.get_root_port() {
+      Some(port) => outln!(
+        "  root {} cost {} via {}",
+        format_bridge_id(root.get_root_id()), root.get_root_path_cost(), netif_name(port.get_netif()),
+      ),
+      None => outln!("  root {} (this bridge)", format_bridge_id(root.get_root_id())),
+    }
+    outln!("  {:<10} {:<8} {:<12} {:<12} {:<8} {}", "Port", "Id", "Role", "State", "Cost", "Edge");
+    for port in tree.get_ports().iter() {
+      outln!(
+        "  {:<10} {:<8} {:<12} {:<12} {:<8} {}",
+        netif_name(port.get_netif()), format!("{:04x}", port.get_port_id()), port.get_role().get_name(),
+        format!("{}", port.get_state()), port.get_path_cost(), if port.is_edge() { "yes" } else { "no" },
+      );
+    }
+  }
+}
+
+async fn show_lacp() {
+  for lacp_bond in lacp::get_lacp_bonds().iter() {
+    let bond = lacp_bond.get_bond();
+    outln!("eth{}: system {} key {}", bond.get_id(), bond.get_macaddress(), bond.get_id());
+    for member in lacp_bond.get_members().iter() {
+      let netif = member.get_netif();
+      let status = match (bond.is_active(netif.get_id()), member.is_selected()) {
+        (true, _) => "active",
+        (false, true) => "selected",
+        (false, false) => "standby",
+      };
+      outln!("  {} {}", netif_name(netif), status);
+      outln!("    actor   port {} state {}", member.get_actor().get_port(), lacp::get_state_names(member.get_actor().get_state()).join(","));
+      match member.get_partner() {
+        Some(partner) => outln!(
+          "    partner system {},{} key {} port {} state {}",
+          partner.get_system_priority(), partner.get_system(), partner.get_key(), partner.get_port(),
+          lacp::get_state_names(partner.get_state()).join(","),
+        ),
+        None => outln!("    partner none"),
+      }
+    }
+  }
+}
+
+async fn show_lldp_neighbors() {
+  let now = get_monotonic_time();
+  outln!("system name {}", lldp::get_system_name());
+  outln!("{:<10} {:<20} {:<20} {:<20} {}", "Interface", "Chassis", "Port", "System", "Expires");
+  for neighbor in lldp::get_neighbors().iter() {
+    outln!(
+      "{:<10} {:<20} {:<20} {:<20} {}s",
+      format!("eth{}", neighbor.get_netif_id()), format!("{}", neighbor.get_chassis_id()), format!("{}", neighbor.get_port_id()),
+      neighbor.get_system_name().unwrap_or("-"), neighbor.get_expire_time().saturating_sub(now) / 1_000_000_000,
+    );
+    if let Some(description) = neighbor.get_port_description() {
+      outln!("  port description {}", description);
+    }
+    for addr in neighbor.get_management_addresses().iter() {
+      outln!("  management address {}", addr);
+    }
+  }
+}
+
+async fn show_console() {
+  if let Some(console) = console::get_console() {
+    outln!("rx dropped {} bytes, tx dropped {} bytes", console.get_rx_dropped(), console.get_tx_dropped());
+  }
+}
+
+async fn ping(dest: &str, count: &str) {
+  let count = match cmdline::parse_integer(count) {
+    Ok(count) if count > 0 => count as u16,
+    _ => {
+      outln!("% invalid count");
+      return;
+    },
+  };
+  let dest_text = String::from(dest);
+  let (ipv4, ipv6) = (Ipv4Address::parse(dest), Ipv6Address::parse(dest));
+  let identifier = ping::new_identifier();
+  let mut received = 0;
+
+  outln!("PING {}: {} data bytes", dest_text, PING_PAYLOAD_LENGTH);
+  for sequence in 0..count {
+    let sent_time = get_monotonic_time();
+    let result = match (ipv4, ipv6) {
+      (Some(dest), _) => ping::send_ipv4_echo_request(dest, identifier, sequence, PING_PAYLOAD_LENGTH),
+      (_, Some(dest)) => ping::send_ipv6_echo_request(dest, identifier, sequence, PING_PAYLOAD_LENGTH),
+      _ => Err("invalid address"),
+    };
+    if let Err(msg) = result {
+      outln!("% {}", msg);
+      break;
+    }
+
+    let mut reply = None;
+    while get_monotonic_time() - sent_time < PING_TIMEOUT.as_nanos() as u64 {
+      TimerFuture::new(PING_WAIT_INTERVAL).await;
+      reply = ping::take_echo_reply(identifier, sequence);
+      if reply.is_some() {
+        break;
+      }
+    }
+    match reply {
+      Some(received_time) => {
+        let rtt = received_time.saturating_sub(sent_time) / 1000;
+        outln!("reply from {}: seq={} time={}.{:03} ms", dest_text, sequence, rtt / 1000, rtt % 1000);
+        received += 1;
+      },
+      None => outln!("request timeout for seq={}", sequence),
+    }
+  }
+  ping::release_identifier(identifier);
+  outln!("--- {} ping statistics: {} transmitted, {} received", dest_text, count, received);
+}
+
+fn route_del(prefix: &str) -> Result<(), &'static str> {
+  match cmdline::parse_ip_prefix(prefix)? {
+    IpPrefix::V4(prefix, prefix_length) => {
+      let mask = 0xffffffffu32.checked_shl(32 - prefix_length).unwrap_or(0);
+      let prefix = prefix.masked(prefix_length);
+      if !get_ipv4_routes(prefix, mask).iter().any(|r| r.get_source() == RouteSource::Static) {
+        return Err("no such static route");
+      }
+      withdraw_ipv4_route(prefix, mask, RouteSource::Static);
+    },
+    IpPrefix::V6(prefix, prefix_length) => {
+      let prefix = prefix.masked(prefix_length);
+      if !get_ipv6_routes(prefix, prefix_length).iter().any(|r| r.get_source() == RouteSource::Static) {
+        return Err("no such static route");
+      }
+      withdraw_ipv6_route(prefix, prefix_length, RouteSource::Static);
+    },
+  }
+  Ok(())
+}
+
+fn address_del(name: &str, address: &str) -> Result<(), &'static str> {
+  let netif = address::find_netif(name).ok_or("no such interface")?;
+  if let Some(address) = Ipv4Address::parse(address) {
+    address::remove_ipv4_address(netif.get_id(), address)
+  } else if let Some(address) = Ipv6Address::parse(address) {
+    address::remove_ipv6_address(netif.get_id(), address)
+  } else {
+    Err("invalid address")
+  }
+}
+
+fn address_add(name: &str, prefix: &str) -> Result<(), &'static str> {
+  let netif = address::find_netif(name).ok_or("no such interface")?;
+  address::add_address(&netif, prefix)
+}
+
+async fn report(result: Result<(), &'static str>) {
+  if let Err(msg) = result {
+    outln!("% {}", msg);
+  }
+}
+
+pub async fn execute(line: &str) {
+  let words: Vec<&str> = line.split_whitespace().collect();
+  match words.as_slice() {
+    ["help"] | ["?"] => outln!("{}", HELP),
+    ["show", "ip", "route"] => show_ip_route().await,
+    ["show", "ipv6", "route"] => show_ipv6_route().await,
+    ["show", "arp"] => show_arp().await,
+    ["show", "ipv6", "neighbors"] => show_ipv6_neighbors().await,
+    ["show", "mac-address-table"] => show_mac_address_table().await,
+    ["show", "interfaces"] => show_interfaces().await,
+    ["show", "bridge"] => show_bridge().await,
+    ["show", "spanning-tree"] => show_spanning_tree().await,
+    ["show", "lacp"] => show_lacp().await,
+    ["show", "lldp", "neighbors"] => show_lldp_neighbors().await,
+    ["show", "console"] => show_console().await,
+    ["ping", dest] => ping(dest, "5").await,
+    ["ping", dest, count] => ping(dest, count).await,
+    ["route", "add", prefix, nexthop] => report(static_route::add_static_route(prefix, nexthop, None)).await,
+    ["route", "add", prefix, nexthop, distance] => report(static_route::add_static_route(prefix, nexthop, Some(*distance))).await,
+    ["route", "del", prefix] => report(route_del(prefix)).await,
+    ["address", "add", name, prefix] => report(address_add(name, prefix)).await,
+    ["address", "del", name, address] => report(address_del(name, address)).await,
+    _ => outln!("% unknown command. type help for commands."),
+  }
+}
@@ -0,0 +1,214 @@
+// management cli on the serial console.
+// a line editor with history in front of the commands. vt100 keys are understood:
+// arrows, home/end, delete, backspace, ctrl-a/e/u/c.
+
+pub mod commands;
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::console;
+
+const PROMPT: &str = "urchin> ";
+const MAX_LINE_LENGTH: usize = 256;
+const MAX_HISTORY: usize = 32;
+
+enum EscapeState {
+  Normal,
+  Escape, // ESC
+  Csi(u8), // ESC [ and the numeric parameter so far
+}
+
+struct LineEditor {
+  line: Vec<u8>,
+  cursor: usize,
+  history: VecDeque<String>,
+  history_index: Option<usize>, // the history entry shown, counted from the newest
+  editing: Vec<u8>, // the line being typed while browsing the history
+  escape: EscapeState,
+  after_cr: bool, // to take \r\n as one enter
+}
+
+impl LineEditor {
+  fn new() -> LineEditor {
+    LineEditor {
+      line: Vec::with_capacity(MAX_LINE_LENGTH),
+      cursor: 0,
+      history: VecDeque::with_capacity(MAX_HISTORY),
+      history_index: None,
+      editing: Vec::new(),
+      escape: EscapeState::Normal,
+      after_cr: false,
+    }
+  }
+
+  fn redraw(&self) {
+    print!("\r{}{}\x1b[K", PROMPT, core::str::from_utf8(&self.line).unwrap_or(""));
+    if self.cursor < self.line.len() {
+      print!("\x1b[{}D", self.line.len() - self.cursor);
+    }
+  }
+
+  fn set_line(&mut self, line: &[u8]) {
+    self.line.clear();
+    self.line.extend_from_slice(line);
+    self.cursor = self.line.len();
+    self.redraw();
+  }
+
+  fn history_up(&mut self) {
+    let index = match self.history_index {
+      None if self.history.len() > 0 => {
+        self.editing = self.line.clone();
+        0
+      },
+      Some(index) if index + 1 < self.history.len() => index + 1,
+      _ => return,
+    };
+    self.history_index = Some(index);
+    let entry = self.history[self.history.len() - 1 - index].clone();
+    self.set_line(entry.as_bytes());
+  }
+
+  fn history_down(&mut self) {
+    match self.history_index {
+      Some(0) => {
+        self.history_index = None;
+        let editing = core::mem::replace(&mut self.editing, Vec::new());
+        self.set_line(&editing);
+      },
+      Some(index) => {
+        self.history_index = Some(index - 1);
+        let entry = self.history[self.history.len() - index].clone();
+        self.set_line(entry.as_bytes());
+      },
+      None => (),
+    }
+  }
+
+  fn insert(&mut self, c: u8) {
+    if self.line.len() >= MAX_LINE_LENGTH {
+      return;
+    }
+    self.line.insert(self.cursor, c);
+    self.cursor += 1;
+    if self.cursor == self.line.len() {
+      print!("{}", c as char);
+    } else {
+      self.redraw();
+    }
+  }
+
+  fn backspace(&mut self) {
+    if self.cursor > 0 {
+      self.cursor -= 1;
+      self.line.remove(self.cursor);
+      self.redraw();
+    }
+  }
+
+  fn delete(&mut self) {
+    if self.cursor < self.line.len() {
+      self.line.remove(self.cursor);
+      self.redraw();
+    }
+  }
+
+  fn move_cursor(&mut self, cursor: usize) {
+    if cursor <= self.line.len() && cursor != self.cursor {
+      self.cursor = cursor;
+      self.redraw();
+    }
+  }
+
+  // the line is complete. remember it unless it repeats the last one.
+  fn accept(&mut self) -> String {
+    println!("");
+    let line = String::from(core::str::from_utf8(&self.line).unwrap_or("").trim());
+    if line.len() > 0 && self.history.back() != Some(&line) {
+      if self.history.len() == MAX_HISTORY {
+        self.history.pop_front();
+      }
+      self.history.push_back(line.clone());
+    }
+    self.line.clear();
+    self.cursor = 0;
+    self.history_index = None;
+    line
+  }
+
+  fn csi(&mut self, param: u8, c: u8) {
+    match (c, param) {
+      (b'A', _) => self.history_up(),
+      (b'B', _) => self.history_down(),
+      (b'C', _) => self.move_cursor(self.cursor + 1),
+      (b'D', _) => if self.cursor > 0 { self.move_cursor(self.cursor - 1) },
+      (b'H', _) | (b'~', 1) | (b'~', 7) => self.move_cursor(0),
+      (b'F', _) | (b'~', 4) | (b'~', 8) => self.move_cursor(self.line.len()),
+      (b'~', 3) => self.delete(),
+      _ => (),
+    }
+  }
+
+  // feed a received byte. returns the line when enter is hit.
+  fn feed(&mut self, c: u8) -> Option<String> {
+    match self.escape {
+      EscapeState::Escape => {
+        self.escape = if c == b'[' || c == b'O' { EscapeState::Csi(0) } else { EscapeState::Normal };
+        return None;
+      },
+      EscapeState::Csi(param) => {
+        if c.is_ascii_digit() {
+          self.escape = EscapeState::Csi(param.saturating_mul(10).saturating_add(c - b'0'));
+        } else {
+          self.escape = EscapeState::Normal;
+          self.csi(param, c);
+        }
+        return None;
+      },
+      EscapeState::Normal => (),
+    }
+
+    let after_cr = core::mem::replace(&mut self.after_cr, c == b'\r');
+    match c {
+      b'\n' if after_cr => (),
+      b'\r' | b'\n' => return Some(self.accept()),
+      0x1b => self.escape = EscapeState::Escape,
+      0x7f | 0x08 => self.backspace(),
+      0x01 => self.move_cursor(0), // ctrl-a
+      0x05 => self.move_cursor(self.line.len()), // ctrl-e
+      0x15 => self.set_line(&[]), // ctrl-u
+      0x03 => {
+        // ctrl-c
+        println!("^C");
+        self.line.clear();
+        self.cursor = 0;
+        self.history_index = None;
+        print!("{}", PROMPT);
+      },
+      0x20..=0x7e => self.insert(c),
+      _ => (),
+    }
+    None
+  }
+}
+
+pub async fn run() {
+  let console = match console::get_console() {
+    Some(console) => console,
+    None => return,
+  };
+  let mut editor = LineEditor::new();
+  console.write(b"Type help for commands.\n").await;
+  console.write(PROMPT.as_bytes()).await;
+  loop {
+    let c = console.read().await;
+    if let Some(line) = editor.feed(c) {
+      if line.len() > 0 {
+        commands::execute(&line).await;
+      }
+      console.write(PROMPT.as_bytes()).await;
+    }
+  }
+}
@@ -0,0 +1,202 @@
+// kernel cmdline.
+// options are separated by spaces. an option is `key` or `key=value`, and double quotes keep spaces in a value,
+// e.g. `key="a b"` or `"key=a b"`. a key may be repeated, each occurrence is kept in order.
+//
+// typed getters report malformed values on the console and skip them rather than guessing.
+
+use alloc::vec::Vec;
+
+use crate::net::ethernet::MacAddress;
+use crate::net::ipv4::Ipv4Address;
+use crate::net::ipv6::Ipv6Address;
+
+#[derive(Debug, Copy, Clone)]
+pub struct CmdlineOption<'a> {
+  key: &'a str,
+  value: Option<&'a str>,
+}
+
+impl<'a> CmdlineOption<'a> {
+  pub fn get_key(&self) -> &'a str {
+    self.key
+  }
+
+  pub fn get_value(&self) -> Option<&'a str> {
+    self.value
+  }
+
+  // print why the option is ignored
+  pub fn report(&self, msg: &str) {
+    match self.value {
+      Some(value) => println!("Error: cmdline {}={}: {}", self.key, value, msg),
+      None => println!("Error: cmdline {}: {}", self.key, msg),
+    }
+  }
+
+  // the value parsed by `parse`. a missing or malformed value is reported.
+  pub fn parse_value<T>(&self, parse: fn(&str) -> Result<T, &'static str>) -> Option<T> {
+    match self.value {
+      Some(value) => match parse(value) {
+        Ok(v) => Some(v),
+        Err(msg) => {
+          self.report(msg);
+          None
+        },
+      },
+      None => {
+        self.report("value is missing");
+        None
+      },
+    }
+  }
+}
+
+pub struct Cmdline<'a> {
+  options: Vec<CmdlineOption<'a>>,
+}
+
+fn strip_quotes(s: &str) -> &str {
+  if s.len() >= 2 && s.starts_with('"') && s.ends_with('"') {
+    &s[1..(s.len()-1)]
+  } else {
+    s
+  }
+}
+
+impl<'a> Cmdline<'a> {
+  pub fn parse(cmdline: &'a str) -> Cmdline<'a> {
+    let mut options = Vec::new();
+    let mut start = None;
+    let mut in_quotes = false;
+    for (i, c) in cmdline.char_indices().chain(core::iter::once((cmdline.len(), ' '))) {
+      match c {
+        '"' => in_quotes = !in_quotes,
+        ' ' | '\t' | '\n' if !in_quotes || i == cmdline.len() => {
+          if let Some(s) = start.take() {
+            let token = strip_quotes(&cmdline[s..i]);
+            let option = match token.find('=') {
+              Some(idx) => CmdlineOption { key: &token[..idx], value: Some(strip_quotes(&token[(idx+1)..])) },
+              None => CmdlineOption { key: token, value: None },
+            };
+            if in_quotes {
+              option.report("unterminated quote");
+            }
+            options.push(option);
+          }
+          continue;
+        },
+        _ => (),
+      }
+      if start.is_none() {
+        start = Some(i);
+      }
+    }
+    Cmdline { options: options }
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = &CmdlineOption<'a>> {
+    self.options.iter()
+  }
+
+  // the last occurrence wins, as in linux
+  pub fn get(&self, key: &str) -> Option<&CmdlineOption<'a>> {
+    self.options.iter().rev().find(|option| option.key == key)
+  }
+
+  pub fn get_all<'b>(&'b self, key: &'b str) -> impl Iterator<Item = &'b CmdlineOption<'a>> {
+    self.options.iter().filter(move |option| option.key == key)
+  }
+
+  pub fn contains(&self, key: &str) -> bool {
+    self.get(key).is_some()
+  }
+
+  pub fn get_integer(&self, key: &str) -> Option<u64> {
+    self.get(key).and_then(|option| option.parse_value(parse_integer))
+  }
+
+  pub fn get_size(&self, key: &str) -> Option<u64> {
+    self.get(key).and_then(|option| option.parse_value(parse_size))
+  }
+}
+
+/////////
+
+// decimal, or hexadecimal with 0x
+pub fn parse_integer(s: &str) -> Result<u64, &'static str> {
+  let (digits, radix) = if s.starts_with("0x") || s.starts_with("0X") { (&s[2..], 16) } else { (s, 10) };
+  if digits.len() == 0 || !digits.chars().all(|c| c.is_digit(radix)) {
+    return Err("invalid integer");
+  }
+  u64::from_str_radix(digits, radix).map_err(|_| "integer is too large")
+}
+
+// an integer with an optional k, m, g or t suffix of binary multiples
+pub fn parse_size(s: &str) -> Result<u64, &'static str> {
+  let shift = match s.chars().last() {
+    Some('k') | Some('K') => 10,
+    Some('m') | Some('M') => 20,
+    Some('g') | Some('G') => 30,
+    Some('t') | Some('T') => 40,
+    _ => 0,
+  };
+  let digits = if shift == 0 { s } else { &s[..(s.len()-1)] };
+  let value = parse_integer(digits).map_err(|_| "invalid size")?;
+  value.checked_mul(1u64 << shift).ok_or("size is too large")
+}
+
+pub fn parse_macaddress(s: &str) -> Result<MacAddress, &'static str> {
+  MacAddress::parse(s).ok_or("invalid mac address")
+}
+
+pub fn parse_ipv4_address(s: &str) -> Result<Ipv4Address, &'static str> {
+  Ipv4Address::parse(s).ok_or("invalid ipv4 address")
+}
+
+pub fn parse_ipv6_address(s: &str) -> Result<Ipv6Address, &'static str> {
+  Ipv6Address::parse(s).ok_or("invalid ipv6 address")
+}
+
+fn split_prefix(s: &str) -> Result<(&str, u32), &'static str> {
+  match s.find('/') {
+    Some(idx) => match s[(idx+1)..].parse::<u32>() {
+      Ok(prefix_length) => Ok((&s[..idx], prefix_length)),
+      Err(_) => Err("invalid prefix length"),
+    },
+    None => Err("prefix length is missing"),
+  }
+}
+
+// <address>/<length>
+pub fn parse_ipv4_prefix(s: &str) -> Result<(Ipv4Address, u32), &'static str> {
+  let (address, prefix_length) = split_prefix(s)?;
+  if prefix_length > 32 {
+    return Err("invalid prefix length");
+  }
+  Ok((parse_ipv4_address(address)?, prefix_length))
+}
+
+// <address>/<length>
+pub fn parse_ipv6_prefix(s: &str) -> Result<(Ipv6Address, u32), &'static str> {
+  let (address, prefix_length) = split_prefix(s)?;
+  if prefix_length > 128 {
+    return Err("invalid prefix length");
+  }
+  Ok((parse_ipv6_address(address)?, prefix_length))
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum IpPrefix {
+  V4(Ipv4Address, u32),
+  V6(Ipv6Address, u32),
+}
+
+// either family
+pub fn parse_ip_prefix(s: &str) -> Result<IpPrefix, &'static str> {
+  let (address, _) = split_prefix(s)?;
+  if address.contains(':') {
+    parse_ipv6_prefix(s).map(|(address, prefix_length)| IpPrefix::V6(address, prefix_length))
+  } else {
+    parse_ipv4_prefix(s).map(|(address, prefix_length)| IpPrefix::V4(address, prefix_length))
+  }
+}
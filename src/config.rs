@@ -0,0 +1,200 @@
+// startup configuration, read from the initrd.
+// a text file with one statement per line. `#` starts a comment, and indentation is only for readability.
+//
+//   hostname <system name>
+//   interface <index or mac address>
+//     address <address>/<length>
+//   bond <member index or mac address> [<member index or mac address> ...]
+//   vlan <parent index or mac address> <vlan id> [<outer vlan id>]
+//   bridge
+//     port <index or mac address> [access=<vlan id> | trunk=<vlan id>[-<vlan id>] ... [native=<vlan id>]]
+//     aging <seconds>
+//     rstp on|off
+//     priority <bridge priority>
+//   route <prefix>/<length> <next hop> [<distance>]
+//   node <processing node name>
+//
+// addresses belong to the last `interface`, and ports and bridge options to the last `bridge`.
+// bonds are named by the interface index they take, and vlan sub-interfaces <parent>.<vlan id> or <parent>.<outer vlan id>.<vlan id>. routes take the same next hops as urchin.route=.
+// when there are `node` statements, only those processing nodes are enabled. otherwise all of them are.
+
+use alloc::vec::Vec;
+
+use crate::net::address;
+use crate::net::bond;
+use crate::net::bridge;
+use crate::net::vlan;
+use crate::net::lldp;
+use crate::net::static_route;
+
+enum Statement<'a> {
+  Hostname(&'a str),
+  Address(&'a str, &'a str), // interface and prefix
+  Route(&'a str, &'a str, Option<&'a str>),
+  Bond(Vec<&'a str>), // members
+  Vlan(&'a str, &'a str, Option<&'a str>), // parent, vlan id and outer vlan id
+  Bridge(Vec<(&'a str, Vec<&'a str>)>, Vec<(&'a str, &'a str)>), // ports with their modes, and options
+  Node(&'a str),
+}
+
+pub struct StartupConfig<'a> {
+  statements: Vec<(usize, Statement<'a>)>, // with line numbers
+}
+
+fn report(line_number: usize, msg: &str) {
+  println!("Error: startup config line {}: {}", line_number, msg);
+}
+
+impl<'a> StartupConfig<'a> {
+  // the initrd holds the file as is. trailing padding is ignored.
+  pub fn load(ramdisk: &'a [u8]) -> Option<StartupConfig<'a>> {
+    let length = ramdisk.iter().rposition(|c| *c != 0).map(|pos| pos + 1).unwrap_or(0);
+    match core::str::from_utf8(&ramdisk[..length]) {
+      Ok(text) => Some(StartupConfig::parse(text)),
+      Err(_) => {
+        println!("Error: startup config is not a text file");
+        None
+      },
+    }
+  }
+
+  pub fn parse(text: &'a str) -> StartupConfig<'a> {
+    let mut statements = Vec::new();
+    let mut interface = None;
+    let mut bridge = None; // index of the statement
+    for (i, line) in text.lines().enumerate() {
+      let line_number = i + 1;
+      let line = match line.find('#') {
+        Some(idx) => &line[..idx],
+        None => line,
+      };
+      let words: Vec<&str> = line.split_whitespace().collect();
+      match words.as_slice() {
+        [] => (),
+        ["interface", name] => {
+          interface = Some(*name);
+          bridge = None;
+        },
+        ["address", prefix] => match interface {
+          Some(name) => statements.push((line_number, Statement::Address(name, *prefix))),
+          None => report(line_number, "address outside of interface"),
+        },
+        ["bridge"] => {
+          interface = None;
+          bridge = Some(statements.len());
+          statements.push((line_number, Statement::Bridge(Vec::new(), Vec::new())));
+        },
+        ["port", name, mode @ ..] => match bridge.map(|idx| &mut statements[idx].1) {
+          Some(Statement::Bridge(ports, _)) => ports.push((*name, mode.to_vec())),
+          _ => report(line_number, "port outside of bridge"),
+        },
+        [option @ "aging", value] | [option @ "rstp", value] | [option @ "priority", value] => match bridge.map(|idx| &mut statements[idx].1) {
+          Some(Statement::Bridge(_, options)) => options.push((*option, *value)),
+          _ => report(line_number, "bridge option outside of bridge"),
+        },
+        ["hostname", name] => statements.push((line_number, Statement::Hostname(*name))),
+        ["bond", members @ ..] if members.len() > 0 => statements.push((line_number, Statement::Bond(members.to_vec()))),
+        ["vlan", parent, vid] => statements.push((line_number, Statement::Vlan(*parent, *vid, None))),
+        ["vlan", parent, vid, outer_vid] => statements.push((line_number, Statement::Vlan(*parent, *vid, Some(*outer_vid)))),
+        ["route", prefix, nexthop] => statements.push((line_number, Statement::Route(*prefix, *nexthop, None))),
+        ["route", prefix, nexthop, distance] => statements.push((line_number, Statement::Route(*prefix, *nexthop, Some(*distance)))),
+        ["node", name] => statements.push((line_number, Statement::Node(*name))),
+        [keyword, ..] => match *keyword {
+          "hostname" | "interface" | "address" | "bond" | "vlan" | "bridge" | "port" | "aging" | "rstp" | "priority" | "route" | "node" => report(line_number, "wrong number of arguments"),
+          _ => report(line_number, "unknown statement"),
+        },
+      }
+    }
+    StartupConfig { statements: statements }
+  }
+
+  pub fn apply_hostname(&self) {
+    for (line_number, statement) in self.statements.iter() {
+      if let Statement::Hostname(name) = statement {
+        if let Err(msg) = lldp::set_system_name(name) {
+          report(*line_number, msg);
+        }
+      }
+    }
+  }
+
+  // members must be probed already
+  pub fn apply_bonds(&self) {
+    for (line_number, statement) in self.statements.iter() {
+      if let Statement::Bond(members) = statement {
+        match bond::add_bond(members) {
+          Ok(netif) => println!("Bond: {} is interface {}", members.join(","), netif.get_id()),
+          Err(msg) => report(*line_number, msg),
+        }
+      }
+    }
+  }
+
+  // parents must be probed already
+  pub fn apply_vlans(&self) {
+    for (line_number, statement) in self.statements.iter() {
+      if let Statement::Vlan(parent, vid, outer_vid) = statement {
+        match vlan::add_vlan(parent, vid, *outer_vid) {
+          Ok(netif) => println!("VLAN: {} {} is interface {}", parent, vid, netif.get_id()),
+          Err(msg) => report(*line_number, msg),
+        }
+      }
+    }
+  }
+
+  // interfaces must be probed already
+  pub fn apply_bridges(&self) {
+    for (line_number, statement) in self.statements.iter() {
+      if let Statement::Bridge(ports, options) = statement {
+        let names: Vec<&str> = ports.iter().map(|(name, _)| *name).collect();
+        let result = bridge::add_bridge(&names, options).and_then(|id| {
+          for (name, mode) in ports.iter().filter(|(_, mode)| mode.len() > 0) {
+            bridge::configure_port(name, mode)?;
+          }
+          Ok(id)
+        });
+        match result {
+          Ok(id) => println!("Bridge: br{} {}", id, names.join(",")),
+          Err(msg) => report(*line_number, msg),
+        }
+      }
+    }
+  }
+
+  // interfaces must be probed already
+  pub fn apply_addresses(&self) {
+    for (line_number, statement) in self.statements.iter() {
+      if let Statement::Address(name, prefix) = statement {
+        let result = match address::find_netif(name) {
+          Some(netif) => address::add_address(&netif, prefix),
+          None => Err("no such interface"),
+        };
+        match result {
+          Ok(_) => println!("Address: {},{}", name, prefix),
+          Err(msg) => report(*line_number, msg),
+        }
+      }
+    }
+  }
+
+  // after the addresses, so that next hops resolve through connected routes
+  pub fn apply_routes(&self) {
+    for (line_number, statement) in self.statements.iter() {
+      if let Statement::Route(prefix, nexthop, distance) = statement {
+        match static_route::add_static_route(prefix, nexthop, *distance) {
+          Ok(_) => println!("Static route: {} {}", prefix, nexthop),
+          Err(msg) => report(*line_number, msg),
+        }
+      }
+    }
+  }
+
+  // None unless the nodes are chosen
+  pub fn get_nodes(&self) -> Option<Vec<&'a str>> {
+    let nodes: Vec<&'a str> = self.statements.iter().filter_map(|(_, statement)| match statement {
+      Statement::Node(name) => Some(*name),
+      _ => None,
+    }).collect();
+    if nodes.len() > 0 { Some(nodes) } else { None }
+  }
+}
@@ -1,7 +1,10 @@
 use core::fmt;
+use alloc::sync::Arc;
 use crate::devices::serial;
+use crate::interrupt;
+use crate::interrupt::Interruptable;
 
-pub static mut CONSOLE: Option<serial::SerialPort> = None;
+pub static mut CONSOLE: Option<Arc<serial::SerialPort>> = None;
 
 #[macro_export]
 macro_rules! println {
@@ -21,16 +24,42 @@ pub fn init() {
   // todo: support console other than serial
   unsafe {
     CONSOLE = Some(
-      serial::SerialPort::new(serial::COM1PORT)
+      Arc::new(serial::SerialPort::new(serial::COM1PORT, serial::COM1IRQ))
     );
   }
 }
 
+// stop polling the console. output is buffered from now on.
+pub fn enable_interrupt() {
+  unsafe {
+    if let Some(console) = CONSOLE.as_ref() {
+      interrupt::IRQ_HANDLERS.set_handler(Arc::clone(console) as Arc<dyn Interruptable>);
+      console.enable_interrupt();
+    }
+  }
+}
+
 pub fn print(args: fmt::Arguments) {
   use core::fmt::Write;
   unsafe {
-    if let Some(mut console) = CONSOLE {
+    if let Some(console) = CONSOLE.as_ref() {
+      let mut console: &serial::SerialPort = console;
       console.write_fmt(args).unwrap();
     }
   }
 }
+
+// write out what is buffered, e.g. before halting
+pub fn flush() {
+  unsafe {
+    if let Some(console) = CONSOLE.as_ref() {
+      console.flush();
+    }
+  }
+}
+
+pub fn get_console() -> Option<&'static serial::SerialPort> {
+  unsafe {
+    CONSOLE.as_ref().map(|console| &**console)
+  }
+}
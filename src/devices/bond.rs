@@ -0,0 +1,109 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::spinlock::{Spinlock, const_spinlock};
+use crate::devices::netif;
+use crate::devices::netif::Netif;
+use crate::devices::buffer::Buffer;
+use crate::net;
+use crate::net::ethernet::MacAddress;
+
+// 802.3ad link aggregation of virtio-net interfaces.
+// frames of a flow always leave through the same active member, chosen by a hash of their addresses.
+// which members are active is up to net::lacp. receiving is done by net::bond, which hands the frames of
+// active members over as the bond's.
+pub struct BondNetif {
+  id: usize,
+  members: Vec<Arc<dyn Netif>>,
+  active: Spinlock<Vec<Arc<dyn Netif>>>, // collecting and distributing
+  macaddr: MacAddress,
+}
+
+impl BondNetif {
+  // the mac address is derived from the first member's and is locally administered, so that the link-local
+  // address of the bond is its own. vlan sub-interfaces of the member only change the last two octets.
+  // it is the system id of LACP too.
+  // members must share the buffer layout of pre_xmit(), as they do being virtio-net interfaces.
+  pub fn new(id: usize, members: Vec<Arc<dyn Netif>>) -> BondNetif {
+    let mut macaddr = members[0].get_macaddress().get_array();
+    macaddr[0] |= 0x02;
+    macaddr[3] ^= 0x80;
+    BondNetif {
+      id: id,
+      members: members,
+      active: const_spinlock(Vec::new()),
+      macaddr: MacAddress::new(macaddr),
+    }
+  }
+
+  pub fn get_members(&self) -> &Vec<Arc<dyn Netif>> {
+    &self.members
+  }
+
+  pub fn set_active(&self, netif_id: usize, active: bool) {
+    let mut members = self.active.lock();
+    members.retain(|member| member.get_id() != netif_id);
+    if active {
+      if let Some(member) = self.members.iter().find(|member| member.get_id() == netif_id) {
+        members.push(Arc::clone(member));
+        // the same flows keep the same members as long as the active ones don't change
+        members.sort_by_key(|member| member.get_id());
+      }
+    }
+  }
+
+  pub fn is_active(&self, netif_id: usize) -> bool {
+    self.active.lock().iter().any(|member| member.get_id() == netif_id)
+  }
+}
+
+// FNV-1a of the mac addresses, and of the ip addresses for IPv4 and IPv6
+fn flow_hash(slice: &[u8], length: usize) -> u32 {
+  let mut fields: [&[u8]; 3] = [&slice[0..12], &[], &[]];
+  if length >= 14 {
+    match [slice[12], slice[13]] {
+      [0x08, 0x00] if length >= 34 => fields[1] = &slice[26..34],
+      [0x86, 0xdd] if length >= 54 => fields[1] = &slice[22..54],
+      _ => (),
+    }
+  }
+  fields.iter().flat_map(|field| field.iter()).fold(0x811c9dc5, |hash, octet| (hash ^ *octet as u32).wrapping_mul(0x01000193))
+}
+
+impl Netif for BondNetif {
+  fn pre_xmit(&self, size: usize) -> Arc<Buffer> {
+    self.members[0].pre_xmit(size)
+  }
+
+  fn xmit(&self, buffer: Arc<Buffer>) -> Result<(), netif::Error> {
+    let member = {
+      let active = self.active.lock();
+      if active.len() == 0 {
+        return Err(netif::Error::TransmitError());
+      }
+      let hash = flow_hash(buffer.slice(), buffer.get_length());
+      Arc::clone(&active[hash as usize % active.len()])
+    };
+    member.xmit(buffer)
+  }
+
+  fn recv(&self) {
+
+  }
+
+  fn get_id(&self) -> usize {
+    self.id
+  }
+
+  fn get_macaddress(&self) -> &net::ethernet::MacAddress {
+    &self.macaddr
+  }
+
+  fn get_mtu(&self) -> usize {
+    self.members.iter().map(|member| member.get_mtu()).min().unwrap_or(0)
+  }
+
+  fn get_drivername(&self) -> &'static str {
+    "bond"
+  }
+}
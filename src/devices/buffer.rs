@@ -10,6 +10,7 @@ pub enum Error {
 pub struct Buffer {
   buffer_ptr: *mut u8,
   size: usize,
+  length: AtomicUsize, // bytes of data from the start of the buffer
   position: AtomicUsize,
   layout: Layout,
 }
@@ -29,6 +30,7 @@ impl Buffer {
     Ok(Buffer {
       buffer_ptr: ptr,
       size: size,
+      length: AtomicUsize::new(size),
       position: AtomicUsize::new(0),
       layout: layout,
     })
@@ -36,7 +38,23 @@ impl Buffer {
   
   pub fn slide_position(&self, delta: usize) {
     self.position.fetch_add(delta, Ordering::SeqCst);
-  } 
+  }
+
+  // give back room in front of the data, e.g. for tags pushed by a lower layer
+  pub fn rewind_position(&self, delta: usize) {
+    self.position.fetch_sub(delta, Ordering::SeqCst);
+  }
+
+  // the device tells how much it received
+  pub fn set_length(&self, length: usize) {
+    self.length.store(length, Ordering::SeqCst);
+  }
+
+  // bytes of data from the current position
+  pub fn get_length(&self) -> usize {
+    let pos = self.position.load(Ordering::SeqCst);
+    self.length.load(Ordering::SeqCst).saturating_sub(pos)
+  }
 
   pub fn get_address(&self) -> *mut u8 {
     self.buffer_ptr
@@ -68,3 +86,10 @@ impl Drop for Buffer {
     }
   }
 }
+
+// the data behind buffer_ptr is read and written through shared references without a lock. this is sound
+// only because urchin runs on the boot cpu alone, and buffers are touched by the executor, which runs with
+// interrupts disabled, or by interrupt handlers, which disable them. so no two contexts use a buffer at once.
+// this must be revisited before application processors are started or interrupts are enabled around tasks.
+unsafe impl Send for Buffer {}
+unsafe impl Sync for Buffer {}
@@ -1,4 +1,7 @@
 pub mod virtio;
 pub mod netif;
+pub mod null;
+pub mod vlan;
+pub mod bond;
 pub mod serial;
 pub mod buffer;
\ No newline at end of file
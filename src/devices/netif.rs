@@ -15,5 +15,6 @@ pub trait Netif : Sync + Send {
 
   fn get_id(&self) -> usize;
   fn get_macaddress(&self) -> &net::ethernet::MacAddress;
+  fn get_mtu(&self) -> usize;
   fn get_drivername(&self) -> &'static str;
 }
@@ -0,0 +1,58 @@
+use alloc::sync::Arc;
+
+use crate::devices::netif;
+use crate::devices::netif::Netif;
+use crate::devices::buffer::Buffer;
+use crate::net;
+
+const NULL_NETIF_ID: usize = usize::MAX;
+
+// Null0. blackhole and reject routes point to it, and whatever is sent through it disappears.
+pub struct NullNetif {
+  macaddr: net::ethernet::MacAddress,
+}
+
+impl NullNetif {
+  pub fn new() -> NullNetif {
+    NullNetif {
+      macaddr: net::ethernet::MacAddress::new([0; 6]),
+    }
+  }
+}
+
+impl Netif for NullNetif {
+  fn pre_xmit(&self, size: usize) -> Arc<Buffer> {
+    match Buffer::new(size, 64) {
+      Ok(buffer) => Arc::new(buffer),
+      Err(_) => panic!("failed to allocate buffer"),
+    }
+  }
+
+  fn xmit(&self, _buffer: Arc<Buffer>) -> Result<(), netif::Error> {
+    Ok(())
+  }
+
+  fn recv(&self) {
+
+  }
+
+  fn get_id(&self) -> usize {
+    NULL_NETIF_ID
+  }
+
+  fn get_macaddress(&self) -> &net::ethernet::MacAddress {
+    &self.macaddr
+  }
+
+  fn get_mtu(&self) -> usize {
+    65535
+  }
+
+  fn get_drivername(&self) -> &'static str {
+    "null"
+  }
+}
+
+lazy_static! {
+  pub static ref NULL_NETIF: Arc<NullNetif> = Arc::new(NullNetif::new());
+}
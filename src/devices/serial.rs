@@ -1,55 +1,262 @@
 // UART 16550A Compatible Serial port driver
+//
+// the port is polled until enable_interrupt() is called. after that, the interrupt handler moves received bytes
+// into the rx ring and transmits the tx ring as the fifo empties. writers never wait for the line:
+// bytes which don't fit in the tx ring are dropped and counted. tasks read and write with the futures
+// of read() and write(), and write() waits for room in the ring instead of dropping.
 
 pub const COM1PORT: u16 = 0x03f8;
+pub const COM1IRQ: u8 = 4;
 
 use crate::io::outb;
 use crate::io::inb;
+use crate::interrupt::Interruptable;
+use crate::spinlock::Spinlock;
 
 use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crossbeam_queue::ArrayQueue;
+
+const RX_RING_SIZE: usize = 1024;
+const TX_RING_SIZE: usize = 64 * 1024;
+const TX_FIFO_SIZE: usize = 16;
+
+// registers
+const REG_DATA: u16 = 0;
+const REG_IER: u16 = 1;
+const REG_IIR: u16 = 2;
+const REG_LSR: u16 = 5;
+
+const IER_RX_AVAILABLE: u8 = 0x01;
+const IER_TX_EMPTY: u8 = 0x02;
+const IIR_NO_INTERRUPT: u8 = 0x01;
 
-#[derive(Debug, Copy, Clone)]
 pub struct SerialPort {
-  port: u16
+  port: u16,
+  irq: u8,
+  interrupt_enabled: AtomicBool,
+  rx_ring: ArrayQueue<u8>,
+  tx_ring: ArrayQueue<u8>,
+  transmitting: AtomicBool, // someone is moving the tx ring to the fifo
+  rx_waker: Spinlock<Option<Waker>>,
+  tx_waker: Spinlock<Option<Waker>>,
+  rx_dropped: AtomicU64,
+  tx_dropped: AtomicU64,
 }
 
 impl SerialPort {
-  pub fn new(port: u16) -> SerialPort {
+  pub fn new(port: u16, irq: u8) -> SerialPort {
     init_serial(port);
-    SerialPort { port: port }
+    SerialPort {
+      port: port,
+      irq: irq,
+      interrupt_enabled: AtomicBool::new(false),
+      rx_ring: ArrayQueue::new(RX_RING_SIZE),
+      tx_ring: ArrayQueue::new(TX_RING_SIZE),
+      transmitting: AtomicBool::new(false),
+      rx_waker: Spinlock::new(None),
+      tx_waker: Spinlock::new(None),
+      rx_dropped: AtomicU64::new(0),
+      tx_dropped: AtomicU64::new(0),
+    }
+  }
+
+  // call after registering the port with IRQ_HANDLERS
+  pub fn enable_interrupt(&self) {
+    self.interrupt_enabled.store(true, Ordering::SeqCst);
+    unsafe {
+      outb(self.port + REG_IER, IER_RX_AVAILABLE | IER_TX_EMPTY);
+    }
+    self.receive();
+    self.transmit();
+  }
+
+  pub fn get_rx_dropped(&self) -> u64 {
+    self.rx_dropped.load(Ordering::Relaxed)
+  }
+
+  pub fn get_tx_dropped(&self) -> u64 {
+    self.tx_dropped.load(Ordering::Relaxed)
+  }
+
+  // wait for the line. only for polling mode and flush().
+  fn write_byte_polling(&self, val: u8) {
+    while !is_transmit_empty(self.port) {}
+    unsafe {
+      outb(self.port + REG_DATA, val);
+    }
+  }
+
+  // queue as many bytes as fit in the tx ring and return the number of them
+  fn queue_bytes(&self, bytes: &[u8]) -> usize {
+    let mut queued = 0;
+    for &val in bytes.iter() {
+      if self.tx_ring.push(val).is_err() {
+        break;
+      }
+      queued += 1;
+    }
+    self.transmit();
+    queued
+  }
+
+  // write without waiting. what doesn't fit in the tx ring is dropped.
+  pub fn write_bytes(&self, bytes: &[u8]) {
+    if !self.interrupt_enabled.load(Ordering::SeqCst) {
+      for &val in bytes.iter() {
+        self.write_byte_polling(val);
+      }
+      return;
+    }
+    let queued = self.queue_bytes(bytes);
+    if queued < bytes.len() {
+      self.tx_dropped.fetch_add((bytes.len() - queued) as u64, Ordering::Relaxed);
+    }
+  }
+
+  // write the whole tx ring by polling. for the panic handler.
+  pub fn flush(&self) {
+    while let Ok(val) = self.tx_ring.pop() {
+      self.write_byte_polling(val);
+    }
+  }
+
+  pub fn read(&self) -> ReadFuture<'_> {
+    ReadFuture { serial: self }
+  }
+
+  pub fn write<'a>(&'a self, bytes: &'a [u8]) -> WriteFuture<'a> {
+    WriteFuture { serial: self, bytes: bytes }
   }
 
-  pub fn read_byte(&self) -> u8 {
+  // fill the fifo from the tx ring when it is empty.
+  // the interrupt handler may come in between, so only one of them moves bytes at a time.
+  fn transmit(&self) {
     loop {
-      if is_received(self.port) == true {
-        unsafe {
-          return inb(self.port);
+      if self.transmitting.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+        return;
+      }
+      let mut sent = false;
+      if is_transmit_empty(self.port) {
+        for _ in 0..TX_FIFO_SIZE {
+          match self.tx_ring.pop() {
+            Ok(val) => unsafe {
+              outb(self.port + REG_DATA, val);
+              sent = true;
+            },
+            Err(_) => break,
+          }
+        }
+      }
+      self.transmitting.store(false, Ordering::Release);
+
+      if sent {
+        if let Some(waker) = self.tx_waker.lock().take() {
+          waker.wake();
         }
       }
+      // the interrupt for the empty fifo may have been missed while transmitting
+      if self.tx_ring.is_empty() || !is_transmit_empty(self.port) {
+        return;
+      }
+    }
+  }
+
+  fn receive(&self) {
+    let mut received = false;
+    while is_received(self.port) {
+      let val = unsafe { inb(self.port + REG_DATA) };
+      if self.rx_ring.push(val).is_err() {
+        self.rx_dropped.fetch_add(1, Ordering::Relaxed);
+      }
+      received = true;
+    }
+    if received {
+      if let Some(waker) = self.rx_waker.lock().take() {
+        waker.wake();
+      }
     }
   }
+}
+
+impl Interruptable for SerialPort {
+  fn get_irq(&self) -> u8 {
+    self.irq
+  }
 
-  pub fn write_byte(&self, val: u8) {
+  // the irq is edge triggered, so handle every cause until none is left
+  fn interrupt_handler(&self) {
     loop {
-      if is_transmit_empty(self.port) == true {
-        unsafe {
-          outb(self.port, val);
-        }
+      let iir = unsafe { inb(self.port + REG_IIR) };
+      if iir & IIR_NO_INTERRUPT != 0 {
         break;
       }
+      self.receive();
+      self.transmit();
     }
-  } 
+  }
 }
 
-impl fmt::Write for SerialPort {
+impl fmt::Write for &SerialPort {
   fn write_str(&mut self, s: &str) -> fmt::Result {
-    let bytes = s.as_bytes();
-    for (_i, &item) in bytes.iter().enumerate() {
-      self.write_byte(item);
-    }
+    self.write_bytes(s.as_bytes());
     Ok(())
   }
 }
 
+pub struct ReadFuture<'a> {
+  serial: &'a SerialPort,
+}
+
+impl<'a> Future for ReadFuture<'a> {
+  type Output = u8;
+  fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+    if let Ok(val) = self.serial.rx_ring.pop() {
+      return Poll::Ready(val);
+    }
+    *self.serial.rx_waker.lock() = Some(cx.waker().clone());
+    // a byte may have come before the waker was set
+    match self.serial.rx_ring.pop() {
+      Ok(val) => Poll::Ready(val),
+      Err(_) => Poll::Pending,
+    }
+  }
+}
+
+pub struct WriteFuture<'a> {
+  serial: &'a SerialPort,
+  bytes: &'a [u8],
+}
+
+impl<'a> Future for WriteFuture<'a> {
+  type Output = ();
+  fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+    let serial = self.serial;
+    if !serial.interrupt_enabled.load(Ordering::SeqCst) {
+      serial.write_bytes(self.bytes);
+      return Poll::Ready(());
+    }
+    let queued = serial.queue_bytes(self.bytes);
+    self.bytes = &self.bytes[queued..];
+    if self.bytes.len() == 0 {
+      return Poll::Ready(());
+    }
+    *serial.tx_waker.lock() = Some(cx.waker().clone());
+    // the ring may have been drained before the waker was set
+    let queued = serial.queue_bytes(self.bytes);
+    self.bytes = &self.bytes[queued..];
+    if self.bytes.len() == 0 {
+      Poll::Ready(())
+    } else {
+      Poll::Pending
+    }
+  }
+}
+
 fn init_serial(port: u16) {
   unsafe {
     outb(port + 1, 0x00);    // Disable all interrupts
@@ -64,12 +271,12 @@ fn init_serial(port: u16) {
 
 fn is_received(port: u16) -> bool {
   unsafe {
-    (inb(port + 5) & 0x01) == 1
+    (inb(port + REG_LSR) & 0x01) == 1
   }
 }
 
 fn is_transmit_empty(port: u16) -> bool {
   unsafe {
-    (inb(port + 5) & 0x20) == 0x20
+    (inb(port + REG_LSR) & 0x20) == 0x20
   }
 }
@@ -214,13 +214,14 @@ impl<'a> Virtqueue<'a> {
  
     while self.last_used_idx != self.used.idx {
       let desc_idx = self.used_ring[(self.last_used_idx & (self.get_size() - 1)) as usize].id as usize;
+      let len = self.used_ring[(self.last_used_idx & (self.get_size() - 1)) as usize].len as usize;
       {
         let desc = &self.descriptor[desc_idx];
         let addr = (desc.addr) as *const u8;
-        let len = self.used_ring[(self.last_used_idx & (self.get_size() - 1)) as usize].len as usize;
         let buf = unsafe { slice::from_raw_parts(addr, len) };
       }
       if let Some(buff) = self.buffers[desc_idx].as_ref() {
+        buff.set_length(len);
         ret.push(Arc::clone(buff));
       }
       self.buffers[desc_idx] = None;
@@ -2,6 +2,7 @@
 use core::ptr;
 use super::VirtioDevice;
 use super::Virtqueue;
+use crate::cmdline;
 
 pub struct VirtioMMIO {
   addr: u64,
@@ -13,107 +14,23 @@ pub struct VirtioMMIO {
 }
 
 impl VirtioMMIO {
-  pub fn new(cmdline: &str, idx: usize) -> Result<VirtioMMIO, &str> {
-    //assume that cmdline contains ".. virtio_mmio.device=4k@0xd0000000:5 "
-    let keyphrase = "virtio_mmio.device=";
-
-    let size_idx = {
-      let mut partstr = &cmdline[0..];
-      let mut keyidx = 0;
-      for i in 0..(idx+1) {
-        let key_idx = partstr.find(keyphrase);
-        match key_idx {
-          Some(j) => {
-            keyidx += j+keyphrase.len();
-            partstr = &cmdline[keyidx..]; //change range
-          },
-          None => {
-            // keyphrase is not found anymore.
-            return Err("virtio-mmio is not found");
-          },
-        }
-        //println!("Virtio-MMIO: i={} idx={} partstr = \"{}\"", i, idx, partstr);
-      }
-      keyidx
+  // `device` is the value of virtio_mmio.device=<size>@<base address>:<irq>[:<id>], e.g. "4k@0xd0000000:5"
+  pub fn new(device: &str) -> Result<VirtioMMIO, &'static str> {
+    let addr_idx = device.find('@').ok_or("base address is missing")?;
+    let irq_idx = addr_idx + device[addr_idx..].find(':').ok_or("irq is missing")?;
+    let tail_idx = match device[(irq_idx+1)..].find(':') {
+      Some(idx) => irq_idx + 1 + idx,
+      None => device.len(),
     };
 
-    //println!("Virtio-MMIO: partial string \"{}\"", &cmdline[size_idx..]);
-    
-    let addr_idx = match cmdline[size_idx..].find("@0x") {
-      Some(idx) => size_idx + idx + 3,
-      None => return Err("virtio-mmio is not found"),
-    };
-
-    let irq_idx = match cmdline[addr_idx..].find(':') {
-      Some(idx) => addr_idx + idx + 1,
-      None => return Err("virtio-mmio is not found"),
-    };
-
-    let tail_idx = match cmdline[irq_idx..].find(' ') {
-      Some(idx) => irq_idx + idx,
-      None => cmdline.len(),
-    };
-    
-    let mut addr_val: u64 = 0;
-    let mut size_val: usize = 0;
-    let mut irq_val: u8 = 0;
-
-    for c in cmdline[size_idx..addr_idx-3].chars() {
-      size_val = match c {
-        '0' => size_val*10 + 0,
-        '1' => size_val*10 + 1,
-        '2' => size_val*10 + 2,
-        '3' => size_val*10 + 3,
-        '4' => size_val*10 + 4,
-        '5' => size_val*10 + 5,
-        '6' => size_val*10 + 6,
-        '7' => size_val*10 + 7,
-        '8' => size_val*10 + 8,
-        '9' => size_val*10 + 9,
-        'k' | 'K' => size_val * 1024,
-        'm' | 'M' => size_val * 1024 * 1024,
-        _ => size_val,
-      };
-    }
-
-    for c in cmdline[addr_idx..irq_idx-1].chars() {
-      addr_val = match c {
-        '0' => addr_val*16 + 0,
-        '1' => addr_val*16 + 1,
-        '2' => addr_val*16 + 2,
-        '3' => addr_val*16 + 3,
-        '4' => addr_val*16 + 4,
-        '5' => addr_val*16 + 5,
-        '6' => addr_val*16 + 6,
-        '7' => addr_val*16 + 7,
-        '8' => addr_val*16 + 8,
-        '9' => addr_val*16 + 9,
-        'a' | 'A' => addr_val*16 + 10,
-        'b' | 'B' => addr_val*16 + 11,
-        'c' | 'C' => addr_val*16 + 12,
-        'd' | 'D' => addr_val*16 + 13,
-        'e' | 'E' => addr_val*16 + 14,
-        'f' | 'F' => addr_val*16 + 15,
-        _ => addr_val,
-      }
+    let size_val = cmdline::parse_size(&device[..addr_idx])? as usize;
+    let addr_val = cmdline::parse_integer(&device[(addr_idx+1)..irq_idx])?;
+    let irq_val = cmdline::parse_integer(&device[(irq_idx+1)..tail_idx])?;
+    if irq_val > 0xff {
+      return Err("invalid irq");
     }
+    let irq_val = irq_val as u8;
 
-    for c in cmdline[irq_idx..tail_idx].chars() {
-      irq_val = match c {
-        '0' => irq_val*10 + 0,
-        '1' => irq_val*10 + 1,
-        '2' => irq_val*10 + 2,
-        '3' => irq_val*10 + 3,
-        '4' => irq_val*10 + 4,
-        '5' => irq_val*10 + 5,
-        '6' => irq_val*10 + 6,
-        '7' => irq_val*10 + 7,
-        '8' => irq_val*10 + 8,
-        '9' => irq_val*10 + 9,
-        _ => irq_val,
-      }
-    }
-    
     if unsafe { ptr::read_volatile(addr_val as *const u32) } != 0x74726976 {
       return Err("Invalid virtio-mmio device")
     }
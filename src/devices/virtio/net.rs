@@ -15,6 +15,7 @@ use crate::PROC_NODES;
 use crate::NET_IFACES;
 
 const ALIGN: usize = 4096;
+const DEFAULT_MTU: usize = 1500;
 
 pub struct VirtioNet<'a, T: VirtioDevice> {
   id: usize,
@@ -22,6 +23,7 @@ pub struct VirtioNet<'a, T: VirtioDevice> {
   virtio: Spinlock<T>,
   queues: [Spinlock<Virtqueue<'a>>; 3],
   macaddr: net::ethernet::MacAddress,
+  mtu: usize,
 
   flag_csum: bool,
   flag_guest_csum: bool,
@@ -156,7 +158,7 @@ impl <'a, T: VirtioDevice> VirtioNet<'a, T> {
 
       let inst = VirtioNet {
         id: id, 
-        virtio: Spinlock::new(dev), queues: [rxqueue, txqueue, cxqueue], macaddr: macaddr, 
+        virtio: Spinlock::new(dev), queues: [rxqueue, txqueue, cxqueue], macaddr: macaddr, mtu: DEFAULT_MTU,
         flag_csum: flag_csum,
         flag_guest_csum: flag_guest_csum,
         flag_mac: flag_mac,
@@ -312,6 +314,10 @@ impl <'a, T: VirtioDevice> netif::Netif for VirtioNet<'a, T> {
     &self.macaddr
   }
 
+  fn get_mtu(&self) -> usize {
+    self.mtu
+  }
+
   fn get_drivername(&self) -> &'static str {
     "virtio-net"
   }
@@ -0,0 +1,106 @@
+use alloc::sync::Arc;
+
+use crate::devices::netif;
+use crate::devices::netif::Netif;
+use crate::devices::buffer::Buffer;
+use crate::net;
+use crate::net::ethernet::MacAddress;
+
+pub const ETHERTYPE_VLAN: [u8; 2] = [0x81, 0x00]; // 802.1Q C-tag
+pub const ETHERTYPE_QINQ: [u8; 2] = [0x88, 0xa8]; // 802.1ad S-tag
+
+// 802.1Q sub-interface on a parent interface.
+// frames are tagged with the vlan id on transmit, and with the outer S-tag too for 802.1ad QinQ.
+// receiving is done by net::vlan, which strips the tags and hands the frames over as the sub-interface's.
+pub struct VlanNetif {
+  id: usize,
+  parent: Arc<dyn Netif>,
+  vid: u16,
+  outer_vid: Option<u16>,
+  macaddr: MacAddress,
+}
+
+impl VlanNetif {
+  // the mac address is derived from the parent's and is locally administered,
+  // so that the link-local address and the adjacencies of the sub-interface are its own.
+  pub fn new(id: usize, parent: Arc<dyn Netif>, vid: u16, outer_vid: Option<u16>) -> VlanNetif {
+    let mut macaddr = parent.get_macaddress().get_array();
+    let tag = (outer_vid.unwrap_or(0) << 12) ^ vid;
+    macaddr[0] |= 0x02;
+    macaddr[4] ^= (tag >> 8) as u8;
+    macaddr[5] ^= tag as u8;
+    VlanNetif {
+      id: id,
+      parent: parent,
+      vid: vid,
+      outer_vid: outer_vid,
+      macaddr: MacAddress::new(macaddr),
+    }
+  }
+
+  pub fn get_parent(&self) -> &Arc<dyn Netif> {
+    &self.parent
+  }
+
+  pub fn get_vid(&self) -> u16 {
+    self.vid
+  }
+
+  pub fn get_outer_vid(&self) -> Option<u16> {
+    self.outer_vid
+  }
+
+  pub fn get_tag_length(&self) -> usize {
+    match self.outer_vid {
+      Some(_) => 8,
+      None => 4,
+    }
+  }
+}
+
+impl Netif for VlanNetif {
+  // room for the tags is kept in front of the frame. xmit() moves the mac addresses into it.
+  fn pre_xmit(&self, size: usize) -> Arc<Buffer> {
+    let tag_length = self.get_tag_length();
+    let buffer = self.parent.pre_xmit(size + tag_length);
+    buffer.slide_position(tag_length);
+    buffer
+  }
+
+  fn xmit(&self, buffer: Arc<Buffer>) -> Result<(), netif::Error> {
+    let tag_length = self.get_tag_length();
+    buffer.rewind_position(tag_length);
+    let slice = buffer.slice_mut();
+    slice.copy_within(tag_length..(tag_length+12), 0);
+    let mut pos = 12;
+    if let Some(outer_vid) = self.outer_vid {
+      slice[pos..(pos+2)].copy_from_slice(&ETHERTYPE_QINQ);
+      slice[(pos+2)..(pos+4)].copy_from_slice(&outer_vid.to_be_bytes());
+      pos += 4;
+    }
+    slice[pos..(pos+2)].copy_from_slice(&ETHERTYPE_VLAN);
+    slice[(pos+2)..(pos+4)].copy_from_slice(&self.vid.to_be_bytes());
+    self.parent.xmit(buffer)
+  }
+
+  fn recv(&self) {
+
+  }
+
+  fn get_id(&self) -> usize {
+    self.id
+  }
+
+  fn get_macaddress(&self) -> &net::ethernet::MacAddress {
+    &self.macaddr
+  }
+
+  // the tags don't count
+  fn get_mtu(&self) -> usize {
+    self.parent.get_mtu()
+  }
+
+  fn get_drivername(&self) -> &'static str {
+    "vlan"
+  }
+}
@@ -11,6 +11,9 @@
 mod console;
 mod arch;
 mod bootparams;
+mod cli;
+mod cmdline;
+mod config;
 mod devices;
 mod net;
 mod interrupt;
@@ -29,14 +32,10 @@ use crate::devices::serial;
 use crate::devices::virtio;
 use crate::devices::netif::Netif;
 use crate::net::ProcessingNode;
-use crate::net::ethernet::MacAddress;
-use crate::net::ipv4::Ipv4Address;
-use crate::net::ipv6::Ipv6Address;
 use crate::interrupt::Interruptable;
 use crate::spinlock::Spinlock;
 use crate::asynchronous::executor::Executor;
 use crate::asynchronous::timer::TimerFuture;
-use crate::net::fib::{FIBType, MAC_ADDR_TABLE, IPV4_ADJACENT, AdjacentInformation, register_macaddress, register_ipv4_adjacent, register_ipv4_fib, register_ipv6_adjacent, register_ipv6_fib};
 
 use crate::arch::x86_64::io;
 use crate::arch::x86_64::apic;
@@ -77,10 +76,25 @@ pub extern "C" fn start_rust(boot_params: *const c_void) {
   };
 
   let e820 = unsafe { bootparams::get_e820(boot_params) };
+  let ramdisk = unsafe { bootparams::get_ramdisk(boot_params) };
   {
     //todo: get actual range
-    let heap_start = 0x0000000000500000;
-    let heap_end   = 0x000000003ff00000;
+    let mut heap_start = 0x0000000000500000;
+    let mut heap_end   = 0x000000003ff00000;
+    //keep the initrd out of the heap. the heap is the larger of the ranges below and above an overlapping initrd.
+    if let Some(ramdisk) = ramdisk {
+      let ramdisk_start = ramdisk.as_ptr() as usize;
+      let ramdisk_end = ramdisk_start + ramdisk.len();
+      if ramdisk_start < heap_end && ramdisk_end > heap_start {
+        let below = ramdisk_start.saturating_sub(heap_start);
+        let above = heap_end.saturating_sub(ramdisk_end);
+        if below >= above {
+          heap_end = heap_start + below;
+        } else {
+          heap_start = heap_end - above;
+        }
+      }
+    }
     let heap_size = heap_end - heap_start;
     unsafe {
       ALLOCATOR.lock().init(heap_start, heap_size);
@@ -91,6 +105,14 @@ pub extern "C" fn start_rust(boot_params: *const c_void) {
 
   println!("Booting Urchin ...");
   println!("Kernel cmdline: {}", cmd);
+  let cmdline = cmdline::Cmdline::parse(cmd);
+  let startup_config = match ramdisk {
+    Some(ramdisk) => {
+      println!("Startup config: {} bytes from initrd", ramdisk.len());
+      config::StartupConfig::load(ramdisk)
+    },
+    None => None,
+  };
   println!("E820:");
   for ent in e820 {
     println!("  ADDR: {:016x}, SIZE: {:016x}, TYPE: {}", ent.get_addr(), ent.get_size(), ent.get_entry_type_str());
@@ -122,12 +144,15 @@ pub extern "C" fn start_rust(boot_params: *const c_void) {
   //set timer interrupt
   unsafe { interrupt::IRQ_HANDLERS.set_handler(Arc::new(interrupt::Timer::new())); }
 
-  let setup_virtio_net = |index, ipv4addr, prefix| {
-    let virtio_mmio = match virtio::mmio::VirtioMMIO::new(cmd, index) {
+  //console output is buffered and sent on interrupts from here
+  console::enable_interrupt();
+
+  let setup_virtio_net = |index, device: &cmdline::CmdlineOption| {
+    let virtio_mmio = match device.get_value().ok_or("value is missing").and_then(virtio::mmio::VirtioMMIO::new) {
       Ok(inst) => inst,
       Err(msg) => {
-        println!("Error: {}", msg);
-        panic!(msg);
+        device.report(msg);
+        return;
       }    
     };
     println!("Virtio-MMIO: addr:{:016x} size:{:016x} irq:{}", virtio_mmio.get_addr(), virtio_mmio.get_size(), virtio_mmio.get_irq());
@@ -139,98 +164,102 @@ pub extern "C" fn start_rust(boot_params: *const c_void) {
           interrupt::IRQ_HANDLERS.set_handler(Arc::clone(&nif_arc) as Arc<dyn Interruptable>);
           NET_IFACES.push(Arc::clone(&nif_arc) as Arc<dyn Netif>);
         }
-        {
-          //register self mac address
-          let macaddr = nif_arc.get_macaddress();
-          register_macaddress(*macaddr, Arc::clone(&nif_arc) as Arc<dyn Netif + Send + Sync>, true, None);
-
-          //
-          register_ipv4_adjacent(ipv4addr, *macaddr, Arc::clone(&nif_arc) as Arc<dyn Netif + Send + Sync>, true, None);
-          register_ipv4_fib(
-            ipv4addr, 0xffffffff, *macaddr, ipv4addr, 
-            Arc::clone(&nif_arc) as Arc<dyn Netif + Send + Sync>, FIBType::Local
-          );
-          register_ipv4_fib(
-            ipv4addr.masked(24), 0xffffffffu32 << (32u32 - prefix), *macaddr, ipv4addr, 
-            Arc::clone(&nif_arc) as Arc<dyn Netif + Send + Sync>, FIBType::Adjacent
-          );
-
-          // generate ipv6 ll addr
-          let macaddr_array = macaddr.get_array();
-          let lla_eui64 = Ipv6Address::from_array([
-            0xfe, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            macaddr_array[0], macaddr_array[1], macaddr_array[2], 0xff, 
-            0xfe, macaddr_array[3], macaddr_array[4], macaddr_array[5],
-          ]);
-          register_ipv6_adjacent(lla_eui64, *macaddr, Arc::clone(&nif_arc) as Arc<dyn Netif + Send + Sync>, true, None);
-          register_ipv6_fib(
-            lla_eui64, 128, *macaddr, lla_eui64, 
-            Arc::clone(&nif_arc) as Arc<dyn Netif + Send + Sync>, FIBType::Local
-          );
-
-          // register ipv6 solicited node multicast
-          let snmcast = Ipv6Address::from_array([
-            0xff, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x01, 0xff, macaddr_array[3], macaddr_array[4], macaddr_array[5],
-          ]);
-          let snmcast_macaddr = MacAddress::new([0x33, 0x33, 0xff, macaddr_array[3], macaddr_array[4], macaddr_array[5],]);
-          register_macaddress(snmcast_macaddr, Arc::clone(&nif_arc) as Arc<dyn Netif + Send + Sync>, true, None);
-          register_ipv6_adjacent(snmcast, snmcast_macaddr, Arc::clone(&nif_arc) as Arc<dyn Netif + Send + Sync>, true, None);
-          register_ipv6_fib(
-            snmcast, 128, snmcast_macaddr, snmcast, 
-            Arc::clone(&nif_arc) as Arc<dyn Netif + Send + Sync>, FIBType::Local
-          );
-
-          // register ipv6 all node multicast
-          let allnodemcast = Ipv6Address::from_array([
-            0xff, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
-          ]);
-          let allnodemcast_macaddr = MacAddress::new([0x33, 0x33, 0x00, 0x00, 0x00, 0x01,]);
-          register_macaddress(allnodemcast_macaddr, Arc::clone(&nif_arc) as Arc<dyn Netif + Send + Sync>, true, None);
-          register_ipv6_adjacent(allnodemcast, allnodemcast_macaddr, Arc::clone(&nif_arc) as Arc<dyn Netif + Send + Sync>, true, None);
-          register_ipv6_fib(
-            allnodemcast, 128, allnodemcast_macaddr, allnodemcast, 
-            Arc::clone(&nif_arc) as Arc<dyn Netif + Send + Sync>, FIBType::Local
-          );
-        }
+        net::address::attach_netif(&(Arc::clone(&nif_arc) as Arc<dyn Netif>));
       },
       None => (),
     };
   };
 
-  setup_virtio_net(0, Ipv4Address::from_array([192, 168, 0, 10]), 24);
-  setup_virtio_net(1, Ipv4Address::from_array([192, 168, 10, 10]), 24);
+  //probe every virtio_mmio.device= of the cmdline
+  for (index, device) in cmdline.get_all("virtio_mmio.device").enumerate() {
+    setup_virtio_net(index, device);
+  }
+
+  //system name announced by LLDP. urchin.hostname=<system name>
+  net::lldp::configure_system_name(&cmdline);
+  if let Some(startup_config) = startup_config.as_ref() {
+    startup_config.apply_hostname();
+  }
+
+  //bonds aggregated by LACP. urchin.bond=<interface>,<interface>[,...]
+  net::bond::configure_bonds(&cmdline);
+  if let Some(startup_config) = startup_config.as_ref() {
+    startup_config.apply_bonds();
+  }
+
+  //vlan sub-interfaces. urchin.vlan=<parent interface>,<vlan id>[,<outer vlan id>]
+  net::vlan::configure_vlans(&cmdline);
+  if let Some(startup_config) = startup_config.as_ref() {
+    startup_config.apply_vlans();
+  }
+
+  //bridge domains. urchin.bridge=<interface>,<interface>[,...][,aging=<seconds>][,rstp=on|off][,priority=<bridge priority>]
+  net::bridge::configure_bridges(&cmdline);
+  net::bridge::configure_bridge_ports(&cmdline);
+  if let Some(startup_config) = startup_config.as_ref() {
+    startup_config.apply_bridges();
+  }
+
+  //interface addresses. urchin.addr=<index or mac address>,<address>/<length>
+  net::address::configure_addresses(&cmdline);
+  if let Some(startup_config) = startup_config.as_ref() {
+    startup_config.apply_addresses();
+  }
 
-  //todo: choose nodes as user feels like.
+  //static routes. urchin.route=<prefix>/<length>,<next hop>[,<distance>]
+  net::static_route::configure_static_routes(&cmdline);
+  if let Some(startup_config) = startup_config.as_ref() {
+    startup_config.apply_routes();
+  }
+
+  //seed of ecmp flow hashing. differs between routers unless given explicitly.
   {
-    let ether_in = Arc::new(net::ethernet::EthernetIn::new());
-    unsafe {
-      PROC_NODES.insert("ethernet-in", ether_in as Arc<dyn ProcessingNode>);
-    }
-    let arp_in = Arc::new(net::arp::ArpIn::new());
-    unsafe {
-      PROC_NODES.insert("arp-in", arp_in as Arc<dyn ProcessingNode>);
-    }
-    //ipv4
-    let ipv4_in = Arc::new(net::ipv4::Ipv4In::new());
-    unsafe {
-      PROC_NODES.insert("ipv4-in", ipv4_in as Arc<dyn ProcessingNode>);
-    }
-    let icmpv4_in = Arc::new(net::ipv4::Icmpv4InLocal::new());
-    unsafe {
-      PROC_NODES.insert("icmpv4-in-local", icmpv4_in as Arc<dyn ProcessingNode>);
-    }
-    //ipv6
-    let ipv6_in = Arc::new(net::ipv6::Ipv6In::new());
-    unsafe {
-      PROC_NODES.insert("ipv6-in", ipv6_in as Arc<dyn ProcessingNode>);
-    }
-    let icmpv6_in = Arc::new(net::ipv6::Icmpv6InLocal::new());
-    unsafe {
-      PROC_NODES.insert("icmpv6-in-local", icmpv6_in as Arc<dyn ProcessingNode>);
-    }
+    let parse_seed = |s: &str| cmdline::parse_integer(s).and_then(|seed| if seed > 0xffffffff { Err("seed is too large") } else { Ok(seed as u32) });
+    let seed = match cmdline.get("urchin.ecmp_seed").and_then(|option| option.parse_value(parse_seed)) {
+      Some(seed) => seed,
+      None => match unsafe { NET_IFACES.first() } {
+        Some(netif) => {
+          let macaddr_array = netif.get_macaddress().get_array();
+          (macaddr_array[2] as u32) << 24 | (macaddr_array[3] as u32) << 16 | (macaddr_array[4] as u32) << 8 | macaddr_array[5] as u32
+        },
+        None => 0,
+      },
+    };
+    net::fib::set_ecmp_hash_seed(seed);
+  }
 
+  //lifetimes of learned neighbors and mac addresses. urchin.arp_timeout= urchin.nd_timeout= urchin.mac_timeout=
+  net::fib::configure_timeouts(&cmdline);
+
+  //measure the route tables when asked. urchin.fib_bench or urchin.fib_bench=<ipv4 routes>,<ipv6 routes>
+  if let Some(option) = cmdline.get("urchin.fib_bench") {
+    let mut counts = option.get_value().unwrap_or("").split(',').filter(|c| c.len() > 0);
+    let mut count = |default| match counts.next() {
+      Some(c) => cmdline::parse_integer(c).map(|c| c as usize).unwrap_or_else(|msg| {
+        option.report(msg);
+        default
+      }),
+      None => default,
+    };
+    let ipv4_routes = count(1_000_000);
+    let ipv6_routes = count(200_000);
+    net::fib::bench::run(ipv4_routes, ipv6_routes);
+  }
+
+  //processing nodes. all of them unless the startup config chooses.
+  {
+    let node_names = match startup_config.as_ref().and_then(|c| c.get_nodes()) {
+      Some(names) => names,
+      None => net::PROCESSING_NODE_NAMES.to_vec(),
+    };
+    for name in node_names.iter() {
+      match net::create_processing_node(name) {
+        Some((name, node)) => unsafe {
+          PROC_NODES.insert(name, node);
+        },
+        None => println!("Error: unknown processing node {}", name),
+      }
+    }
   }
 
   ////// codes below here are dummy
@@ -275,6 +304,20 @@ pub extern "C" fn start_rust(boot_params: *const c_void) {
   }
   println!("---- Summary of Configuration --*/");
 
+  //start protocol timers
+  if let Some(exec) = unsafe { EXECUTOR.as_ref() } {
+    exec.spawn(net::nd::neighbor_unreachability_detection());
+    exec.spawn(net::fib::expire_adjacent_entries());
+    exec.spawn(net::arp::retry_resolutions());
+    exec.spawn(net::ipv4::expire_reassembly());
+    exec.spawn(net::ipv6::expire_reassembly());
+    exec.spawn(net::bridge::expire_bridge_entries());
+    exec.spawn(net::rstp::spanning_tree());
+    exec.spawn(net::lacp::periodic_transmission());
+    exec.spawn(net::lldp::lldp());
+    exec.spawn(cli::run());
+  }
+
   //add test task
   if let Some(exec) = unsafe { EXECUTOR.as_ref() } {
     exec.spawn(async {
@@ -389,6 +432,7 @@ fn panic(panic_info: &PanicInfo) -> ! {
   } else {
     println!("Panic!");
   }
+  console::flush();
 
   loop {
     unsafe {
@@ -0,0 +1,361 @@
+// addresses of the interfaces.
+// every address brings its adjacency record, its local route and the connected route of its subnet,
+// plus the solicited node multicast group for ipv6. they are added and removed together under one lock.
+//
+// addresses can be given on the kernel cmdline or in the startup configuration as well.
+//
+//   urchin.addr=<interface>,<address>/<length>
+//
+// the interface is its index or its mac address, so that one kernel image fits every vm.
+// vlan sub-interfaces can be given as <parent>.<vlan id> as well.
+// e.g. urchin.addr=0,192.168.0.10/24 urchin.addr=52:54:00:12:34:56,2001:db8::10/64
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::spinlock::{Spinlock, const_spinlock};
+use crate::devices::netif::Netif;
+use crate::net::ethernet::MacAddress;
+use crate::net::ipv4::Ipv4Address;
+use crate::net::ipv6::Ipv6Address;
+use crate::net::fib::{
+  FIBType, MAC_ADDR_TABLE, register_macaddress, unregister_macaddress,
+  register_ipv4_adjacent, unregister_ipv4_adjacent, register_ipv6_adjacent, unregister_ipv6_adjacent,
+};
+use crate::net::vlan;
+use crate::net::rib::{RouteSource, RibRoute, RibNexthop, add_ipv4_route, withdraw_ipv4_route, add_ipv6_route, withdraw_ipv6_route};
+use crate::cmdline;
+use crate::cmdline::{Cmdline, IpPrefix};
+use crate::NET_IFACES;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct InterfaceAddress<A> {
+  address: A,
+  prefix_length: u32,
+}
+
+impl<A: Copy> InterfaceAddress<A> {
+  pub fn get_address(&self) -> A {
+    self.address
+  }
+
+  pub fn get_prefix_length(&self) -> u32 {
+    self.prefix_length
+  }
+}
+
+struct InterfaceAddresses {
+  netif: Arc<dyn Netif>,
+  ipv4: Vec<InterfaceAddress<Ipv4Address>>,
+  ipv6: Vec<InterfaceAddress<Ipv6Address>>,
+}
+
+// keyed by interface id
+static ADDRESSES: Spinlock<BTreeMap<usize, InterfaceAddresses>> = const_spinlock(BTreeMap::new());
+
+fn ipv4_prefix_mask(prefix_length: u32) -> u32 {
+  0xffffffffu32.checked_shl(32 - prefix_length).unwrap_or(0)
+}
+
+fn solicited_node_multicast(address: &Ipv6Address) -> (Ipv6Address, MacAddress) {
+  let address_array = address.get_array();
+  let snmcast = Ipv6Address::from_array([
+    0xff, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x01, 0xff, address_array[13], address_array[14], address_array[15],
+  ]);
+  let snmcast_macaddr = MacAddress::new([0x33, 0x33, 0xff, address_array[13], address_array[14], address_array[15],]);
+  (snmcast, snmcast_macaddr)
+}
+
+// the connected route of a subnet goes out of every interface having an address in it
+fn sync_ipv4_connected(table: &BTreeMap<usize, InterfaceAddresses>, prefix: Ipv4Address, prefix_length: u32) {
+  if prefix_length == 32 {
+    return;
+  }
+  let mut nexthops = Vec::new();
+  for addresses in table.values() {
+    let found = addresses.ipv4.iter()
+      .find(|a| a.prefix_length == prefix_length && a.address.masked(prefix_length) == prefix);
+    if let Some(a) = found {
+      nexthops.push(RibNexthop::new(a.address, *addresses.netif.get_macaddress(), Arc::clone(&addresses.netif), 1));
+    }
+  }
+  if nexthops.len() > 0 {
+    add_ipv4_route(prefix, ipv4_prefix_mask(prefix_length), RibRoute::new(RouteSource::Connected, 0, FIBType::Adjacent, nexthops));
+  } else {
+    withdraw_ipv4_route(prefix, ipv4_prefix_mask(prefix_length), RouteSource::Connected);
+  }
+}
+
+// link-local subnets exist once per link, so they never make a route
+fn sync_ipv6_connected(table: &BTreeMap<usize, InterfaceAddresses>, prefix: Ipv6Address, prefix_length: u32) {
+  if prefix_length == 128 || prefix.is_link_local() {
+    return;
+  }
+  let mut nexthops = Vec::new();
+  for addresses in table.values() {
+    let found = addresses.ipv6.iter()
+      .find(|a| a.prefix_length == prefix_length && a.address.masked(prefix_length) == prefix);
+    if let Some(a) = found {
+      nexthops.push(RibNexthop::new(a.address, *addresses.netif.get_macaddress(), Arc::clone(&addresses.netif), 1));
+    }
+  }
+  if nexthops.len() > 0 {
+    add_ipv6_route(prefix, prefix_length, RibRoute::new(RouteSource::Connected, 0, FIBType::Adjacent, nexthops));
+  } else {
+    withdraw_ipv6_route(prefix, prefix_length, RouteSource::Connected);
+  }
+}
+
+// join the solicited node multicast group while any address of the interface maps to it
+fn sync_ipv6_snmcast(table: &BTreeMap<usize, InterfaceAddresses>, snmcast: Ipv6Address, snmcast_macaddr: MacAddress) {
+  let mut nexthops = Vec::new();
+  let mut first_netif = None;
+  for addresses in table.values() {
+    if addresses.ipv6.iter().any(|a| solicited_node_multicast(&a.address).0 == snmcast) {
+      nexthops.push(RibNexthop::new(snmcast, snmcast_macaddr, Arc::clone(&addresses.netif), 1));
+      first_netif.get_or_insert_with(|| Arc::clone(&addresses.netif));
+    }
+  }
+  match first_netif {
+    Some(netif) => {
+      register_macaddress(snmcast_macaddr, Arc::clone(&netif), true, None);
+      register_ipv6_adjacent(snmcast, snmcast_macaddr, netif, true, None);
+      add_ipv6_route(snmcast, 128, RibRoute::new(RouteSource::Local, 0, FIBType::Local, nexthops));
+    },
+    None => {
+      let is_local = MAC_ADDR_TABLE.lock().get(&snmcast_macaddr).map(|adj| adj.is_local()).unwrap_or(false);
+      if is_local {
+        unregister_macaddress(snmcast_macaddr);
+      }
+      unregister_ipv6_adjacent(snmcast);
+      withdraw_ipv6_route(snmcast, 128, RouteSource::Local);
+    },
+  }
+}
+
+// bring up the addresses every interface has: the EUI-64 link-local address and all-nodes multicast
+pub fn attach_netif(netif: &Arc<dyn Netif>) {
+  let macaddr = *netif.get_macaddress();
+  register_macaddress(macaddr, Arc::clone(netif), true, None);
+
+  let allnodemcast = Ipv6Address::from_array([
+    0xff, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+  ]);
+  let allnodemcast_macaddr = MacAddress::new([0x33, 0x33, 0x00, 0x00, 0x00, 0x01,]);
+  register_macaddress(allnodemcast_macaddr, Arc::clone(netif), true, None);
+  register_ipv6_adjacent(allnodemcast, allnodemcast_macaddr, Arc::clone(netif), true, None);
+  add_ipv6_route(allnodemcast, 128, RibRoute::new(
+    RouteSource::Local, 0, FIBType::Local,
+    vec![RibNexthop::new(allnodemcast, allnodemcast_macaddr, Arc::clone(netif), 1)],
+  ));
+
+  let macaddr_array = macaddr.get_array();
+  let lla_eui64 = Ipv6Address::from_array([
+    0xfe, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    macaddr_array[0], macaddr_array[1], macaddr_array[2], 0xff,
+    0xfe, macaddr_array[3], macaddr_array[4], macaddr_array[5],
+  ]);
+  if let Err(msg) = add_ipv6_address(netif, lla_eui64, 64) {
+    println!("Error: link-local address of interface {}: {}", netif.get_id(), msg);
+  }
+}
+
+pub fn add_ipv4_address(netif: &Arc<dyn Netif>, address: Ipv4Address, prefix_length: u32) -> Result<(), &'static str> {
+  if prefix_length > 32 {
+    return Err("invalid prefix length");
+  }
+  if address.is_unspecified() || address.is_multicast() || address.is_broadcast() {
+    return Err("invalid address");
+  }
+
+  let mut table = ADDRESSES.lock();
+  if table.values().any(|addresses| addresses.ipv4.iter().any(|a| a.address == address)) {
+    return Err("address is already assigned");
+  }
+  table.entry(netif.get_id()).or_insert_with(|| InterfaceAddresses {
+    netif: Arc::clone(netif),
+    ipv4: Vec::new(),
+    ipv6: Vec::new(),
+  }).ipv4.push(InterfaceAddress { address: address, prefix_length: prefix_length });
+
+  let macaddr = *netif.get_macaddress();
+  register_ipv4_adjacent(address, macaddr, Arc::clone(netif), true, None);
+  add_ipv4_route(address, 0xffffffff, RibRoute::new(
+    RouteSource::Local, 0, FIBType::Local,
+    vec![RibNexthop::new(address, macaddr, Arc::clone(netif), 1)],
+  ));
+  sync_ipv4_connected(&table, address.masked(prefix_length), prefix_length);
+  Ok(())
+}
+
+pub fn remove_ipv4_address(netif_id: usize, address: Ipv4Address) -> Result<(), &'static str> {
+  let mut table = ADDRESSES.lock();
+  let removed = match table.get_mut(&netif_id) {
+    Some(addresses) => match addresses.ipv4.iter().position(|a| a.address == address) {
+      Some(idx) => addresses.ipv4.remove(idx),
+      None => return Err("no such address"),
+    },
+    None => return Err("no such address"),
+  };
+
+  unregister_ipv4_adjacent(address);
+  withdraw_ipv4_route(address, 0xffffffff, RouteSource::Local);
+  sync_ipv4_connected(&table, address.masked(removed.prefix_length), removed.prefix_length);
+  Ok(())
+}
+
+pub fn add_ipv6_address(netif: &Arc<dyn Netif>, address: Ipv6Address, prefix_length: u32) -> Result<(), &'static str> {
+  if prefix_length > 128 {
+    return Err("invalid prefix length");
+  }
+  if address.is_unspecified() || address.is_multicast() {
+    return Err("invalid address");
+  }
+
+  let mut table = ADDRESSES.lock();
+  if table.values().any(|addresses| addresses.ipv6.iter().any(|a| a.address == address)) {
+    return Err("address is already assigned");
+  }
+  table.entry(netif.get_id()).or_insert_with(|| InterfaceAddresses {
+    netif: Arc::clone(netif),
+    ipv4: Vec::new(),
+    ipv6: Vec::new(),
+  }).ipv6.push(InterfaceAddress { address: address, prefix_length: prefix_length });
+
+  let macaddr = *netif.get_macaddress();
+  register_ipv6_adjacent(address, macaddr, Arc::clone(netif), true, None);
+  add_ipv6_route(address, 128, RibRoute::new(
+    RouteSource::Local, 0, FIBType::Local,
+    vec![RibNexthop::new(address, macaddr, Arc::clone(netif), 1)],
+  ));
+  let (snmcast, snmcast_macaddr) = solicited_node_multicast(&address);
+  sync_ipv6_snmcast(&table, snmcast, snmcast_macaddr);
+  sync_ipv6_connected(&table, address.masked(prefix_length), prefix_length);
+  Ok(())
+}
+
+pub fn remove_ipv6_address(netif_id: usize, address: Ipv6Address) -> Result<(), &'static str> {
+  let mut table = ADDRESSES.lock();
+  let removed = match table.get_mut(&netif_id) {
+    Some(addresses) => match addresses.ipv6.iter().position(|a| a.address == address) {
+      Some(idx) => addresses.ipv6.remove(idx),
+      None => return Err("no such address"),
+    },
+    None => return Err("no such address"),
+  };
+
+  unregister_ipv6_adjacent(address);
+  withdraw_ipv6_route(address, 128, RouteSource::Local);
+  let (snmcast, snmcast_macaddr) = solicited_node_multicast(&address);
+  sync_ipv6_snmcast(&table, snmcast, snmcast_macaddr);
+  sync_ipv6_connected(&table, address.masked(removed.prefix_length), removed.prefix_length);
+  Ok(())
+}
+
+pub fn get_ipv4_addresses(netif_id: usize) -> Vec<InterfaceAddress<Ipv4Address>> {
+  ADDRESSES.lock().get(&netif_id).map(|addresses| addresses.ipv4.clone()).unwrap_or_else(Vec::new)
+}
+
+pub fn get_ipv6_addresses(netif_id: usize) -> Vec<InterfaceAddress<Ipv6Address>> {
+  ADDRESSES.lock().get(&netif_id).map(|addresses| addresses.ipv6.clone()).unwrap_or_else(Vec::new)
+}
+
+// the address of the interface on the subnet of `dest`, or its first address
+pub fn find_ipv4_source_address(netif_id: usize, dest: &Ipv4Address) -> Option<Ipv4Address> {
+  let table = ADDRESSES.lock();
+  let addresses = &table.get(&netif_id)?.ipv4;
+  addresses.iter()
+    .find(|a| a.address.masked(a.prefix_length) == dest.masked(a.prefix_length))
+    .or(addresses.first())
+    .map(|a| a.address)
+}
+
+// whether a neighbor may be learned at `address` on the interface: inside a subnet of the interface,
+// and none of our own addresses
+pub fn is_ipv4_on_link(netif_id: usize, address: &Ipv4Address) -> bool {
+  let table = ADDRESSES.lock();
+  if table.values().any(|addresses| addresses.ipv4.iter().any(|a| a.address == *address)) {
+    return false;
+  }
+  match table.get(&netif_id) {
+    Some(addresses) => addresses.ipv4.iter().any(|a| a.prefix_length < 32 && a.address.masked(a.prefix_length) == address.masked(a.prefix_length)),
+    None => false,
+  }
+}
+
+// the same for ipv6. link-local neighbors are on the link of any interface with a link-local address.
+pub fn is_ipv6_on_link(netif_id: usize, address: &Ipv6Address) -> bool {
+  let table = ADDRESSES.lock();
+  if table.values().any(|addresses| addresses.ipv6.iter().any(|a| a.address == *address)) {
+    return false;
+  }
+  match table.get(&netif_id) {
+    Some(addresses) => addresses.ipv6.iter().any(|a| {
+      a.prefix_length < 128 && a.address.is_link_local() == address.is_link_local()
+        && a.address.masked(a.prefix_length) == address.masked(a.prefix_length)
+    }),
+    None => false,
+  }
+}
+
+/////////
+
+// the index of a new interface, above every index in use. virtio-net interfaces take the indexes of their
+// devices, which leave gaps when a device is not probed, so the count of interfaces may be in use already.
+pub fn next_netif_id() -> usize {
+  unsafe { NET_IFACES.iter() }.map(|netif| netif.get_id() + 1).max().unwrap_or(0)
+}
+
+// an interface by its index or its mac address
+pub fn find_netif(name: &str) -> Option<Arc<dyn Netif>> {
+  // vlan sub-interfaces as <parent>.<vlan id> or <parent>.<outer vlan id>.<vlan id>
+  if let Some(idx) = name.find('.') {
+    let parent_id = find_netif(&name[..idx])?.get_id();
+    let vids: Vec<&str> = name[(idx+1)..].split('.').collect();
+    let vlan = match vids.as_slice() {
+      [vid] => vlan::find_vlan(parent_id, None, vlan::parse_vlan_id(vid).ok()?),
+      [outer_vid, vid] => vlan::find_vlan(parent_id, Some(vlan::parse_vlan_id(outer_vid).ok()?), vlan::parse_vlan_id(vid).ok()?),
+      _ => None,
+    };
+    return vlan.map(|vlan| vlan as Arc<dyn Netif>);
+  }
+
+  let netifs = unsafe { NET_IFACES.iter() };
+  match cmdline::parse_macaddress(name).ok() {
+    Some(macaddr) => netifs.filter(|netif| *netif.get_macaddress() == macaddr).next().map(|netif| Arc::clone(netif)),
+    None => {
+      let index = name.parse::<usize>().ok()?;
+      netifs.filter(|netif| netif.get_id() == index).next().map(|netif| Arc::clone(netif))
+    },
+  }
+}
+
+fn configure_address(arg: &str) -> Result<(), &'static str> {
+  let (name, prefix) = match arg.find(',') {
+    Some(idx) => (&arg[..idx], &arg[(idx+1)..]),
+    None => return Err("expected <interface>,<address>/<length>"),
+  };
+  let netif = find_netif(name).ok_or("no such interface")?;
+  add_address(&netif, prefix)
+}
+
+// <address>/<length> of either family
+pub fn add_address(netif: &Arc<dyn Netif>, prefix: &str) -> Result<(), &'static str> {
+  match cmdline::parse_ip_prefix(prefix)? {
+    IpPrefix::V4(address, prefix_length) => add_ipv4_address(netif, address, prefix_length),
+    IpPrefix::V6(address, prefix_length) => add_ipv6_address(netif, address, prefix_length),
+  }
+}
+
+// configure every urchin.addr= of the cmdline. interfaces must be probed already.
+pub fn configure_addresses(cmdline: &Cmdline) {
+  for option in cmdline.get_all("urchin.addr") {
+    if let Some(_) = option.parse_value(configure_address) {
+      println!("Address: {}", option.get_value().unwrap_or(""));
+    }
+  }
+}
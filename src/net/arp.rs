@@ -1,11 +1,30 @@
+use core::time::Duration;
+
+use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
+use alloc::vec;
 use alloc::vec::Vec;
 
 use crate::devices::buffer::Buffer;
+use crate::devices::netif::Netif;
 use crate::net::{DataFromNetif, ProcessingNode};
 use crate::net::ethernet::{MacAddress, generate_ether_header};
-use crate::net::ipv4::{Ipv4Address};
-use crate::net::fib::{FIBType, MAC_ADDR_TABLE, AdjacentInformation, register_macaddress, register_ipv4_fib, register_ipv4_adjacent, IPV4_ADJACENT};
+use crate::net::ipv4::{Ipv4Address, send_icmpv4_error, ICMPV4_DEST_UNREACHABLE, ICMPV4_CODE_HOST_UNREACHABLE};
+use crate::net::fib::{FIBType, MAC_ADDR_TABLE, AdjacentInformation, ForwardInformationBaseIpv4, register_macaddress, register_ipv4_adjacent, ipv4_adjacent_expire_time, IPV4_ADJACENT};
+use crate::net::rib;
+use crate::net::rib::{RouteSource, RibRoute, RibNexthop, add_ipv4_route};
+use crate::net::address;
+use crate::spinlock::{Spinlock, const_spinlock};
+use crate::asynchronous::timer::TimerFuture;
+use crate::arch::x86_64::kvmclock::get_monotonic_time;
+use crate::PROC_NODES;
+
+const ARP_RETRY_INTERVAL: u64 = 1_000_000_000;
+const ARP_RETRY_TICK: Duration = Duration::from_millis(250);
+const ARP_MAX_RETRIES: usize = 3;
+const ARP_MAX_PENDING_PACKETS: usize = 32;
+// bound of the resolutions in progress, against scans of a connected subnet exhausting the memory
+const ARP_MAX_PENDING_DESTINATIONS: usize = 64;
 
 pub struct ArpIn;
 
@@ -41,6 +60,111 @@ pub fn generate_arp_packet(buffer: &mut [u8], oper: u16, src_mac: MacAddress, sr
   arp_packet.tpa = dest_ip.get_array();
 }
 
+// packets waiting for the mac address of their next hop
+struct PendingResolution {
+  netif: Arc<dyn Netif>,
+  src_ip: Ipv4Address,
+  packets: Vec<DataFromNetif>,
+  retries: usize,
+  requested_time: u64,
+}
+
+static ARP_PENDING: Spinlock<BTreeMap<Ipv4Address, PendingResolution>> = const_spinlock(BTreeMap::new());
+
+fn send_arp_request(netif: &Arc<dyn Netif>, src_ip: Ipv4Address, dest_ip: Ipv4Address) {
+  let reqbuff = netif.pre_xmit(14+28);
+  let reqslice = reqbuff.slice_mut();
+
+  let broadcast = MacAddress::new([0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+  generate_ether_header(&mut reqslice[0..], *netif.get_macaddress(), broadcast, [0x08, 0x06]);
+  generate_arp_packet(&mut reqslice[14..], 0x0100, *netif.get_macaddress(), src_ip, MacAddress::new([0; 6]), dest_ip);
+  let _ = netif.xmit(reqbuff);
+}
+
+// queue a packet towards an on-link address whose mac address isn't known yet.
+// the packet is re-injected to ipv4-in once ArpIn learns the address.
+// it is dropped when too many addresses are being resolved already.
+pub fn enqueue_unresolved(frame: DataFromNetif, dest_ip: Ipv4Address, fib: &ForwardInformationBaseIpv4) {
+  let mut pending = ARP_PENDING.lock();
+  if let Some(resolution) = pending.get_mut(&dest_ip) {
+    if resolution.packets.len() < ARP_MAX_PENDING_PACKETS {
+      resolution.packets.push(frame);
+    }
+    return;
+  }
+  if pending.len() >= ARP_MAX_PENDING_DESTINATIONS {
+    return;
+  }
+
+  let netif = Arc::clone(fib.get_netif());
+  // adjacent entries carry our own address of the subnet. otherwise the next hop is a gateway.
+  let src_ip = match fib.get_fib_type() {
+    FIBType::Adjacent => fib.get_nexthop_address(),
+    _ => match address::find_ipv4_source_address(netif.get_id(), &dest_ip) {
+      Some(addr) => addr,
+      None => return,
+    },
+  };
+  let mut packets = Vec::with_capacity(ARP_MAX_PENDING_PACKETS);
+  packets.push(frame);
+  pending.insert(dest_ip, PendingResolution {
+    netif: Arc::clone(&netif),
+    src_ip: src_ip,
+    packets: packets,
+    retries: 0,
+    requested_time: get_monotonic_time(),
+  });
+  drop(pending);
+
+  send_arp_request(&netif, src_ip, dest_ip);
+}
+
+// resend the requests of pending resolutions, and give up on those which got no reply
+pub async fn retry_resolutions() {
+  loop {
+    TimerFuture::new(ARP_RETRY_TICK).await;
+
+    let now = get_monotonic_time();
+    let mut requests = Vec::new();
+    let mut expired = Vec::new();
+    let failed: Vec<PendingResolution> = {
+      let mut pending = ARP_PENDING.lock();
+      for (dest_ip, resolution) in pending.iter_mut() {
+        if now.saturating_sub(resolution.requested_time) < ARP_RETRY_INTERVAL {
+          continue;
+        }
+        if resolution.retries < ARP_MAX_RETRIES {
+          resolution.retries += 1;
+          resolution.requested_time = now;
+          requests.push((Arc::clone(&resolution.netif), resolution.src_ip, *dest_ip));
+        } else {
+          expired.push(*dest_ip);
+        }
+      }
+      expired.iter().filter_map(|dest_ip| pending.remove(dest_ip)).collect()
+    };
+
+    for (netif, src_ip, dest_ip) in requests.iter() {
+      send_arp_request(netif, *src_ip, *dest_ip);
+    }
+    // give up. tell the senders that the host is unreachable.
+    for resolution in failed.iter() {
+      for frame in resolution.packets.iter() {
+        send_icmpv4_error(frame, ICMPV4_DEST_UNREACHABLE, ICMPV4_CODE_HOST_UNREACHABLE, [0; 4]);
+      }
+    }
+  }
+}
+
+fn flush_resolved(ip_address: Ipv4Address) {
+  let resolution = ARP_PENDING.lock().remove(&ip_address);
+  if let Some(resolution) = resolution {
+    if let Some(node_ref) = unsafe { PROC_NODES.get("ipv4-in") } {
+      node_ref.process(&resolution.packets);
+    }
+  }
+}
+
 impl ProcessingNode for ArpIn {
   fn process(&self, buff: &[DataFromNetif]) {
     for frame in buff.iter() {
@@ -50,14 +174,22 @@ impl ProcessingNode for ArpIn {
       //println!("DEBUG: oper={} sha={:?} spa={:?} tha={:?} tpa={:?}", arp_packet.oper, arp_packet.sha, arp_packet.spa, arp_packet.tha, arp_packet.tpa);
 
       {
-        //register source to fib and adj
+        //register source to rib and adj. only senders on a subnet of the interface are neighbors,
+        //and our own addresses are never taken over.
         let src_mac = MacAddress::new(arp_packet.sha);
         let src_ip = Ipv4Address::from_array(arp_packet.spa);
 
-        register_ipv4_fib(src_ip, 0xffffffff, 
-          src_mac, src_ip, Arc::clone(frame.get_netif()), FIBType::AdjacentResolved
-        );
-        register_ipv4_adjacent(src_ip, src_mac, Arc::clone(frame.get_netif()), false, None);
+        if address::is_ipv4_on_link(frame.get_netif().get_id(), &src_ip) {
+          register_ipv4_adjacent(src_ip, src_mac, Arc::clone(frame.get_netif()), false, ipv4_adjacent_expire_time());
+          add_ipv4_route(src_ip, 0xffffffff, RibRoute::new(
+            RouteSource::Neighbor, 0, FIBType::AdjacentResolved,
+            vec![RibNexthop::new(src_ip, src_mac, Arc::clone(frame.get_netif()), 1)],
+          ));
+
+          // routes via this gateway can be forwarded now
+          rib::notify_ipv4_neighbor(src_ip);
+          flush_resolved(src_ip);
+        }
       }
 
 
@@ -0,0 +1,115 @@
+// bonds of virtio-net interfaces, aggregated by LACP in net::lacp.
+// a bond is an interface of its own with its own addresses and routes, like a vlan sub-interface.
+// frames received on a member are the bond's while the member is active, and LACPDUs go to LACP.
+// members take no frames of their own.
+//
+//   urchin.bond=<interface>,<interface>[,...]
+//
+// bonds take the next interface index. e.g. urchin.bond=1,2 urchin.addr=3,10.0.0.1/24
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::spinlock::{Spinlock, const_spinlock};
+use crate::devices::netif::Netif;
+use crate::devices::bond::BondNetif;
+use crate::net::DataFromNetif;
+use crate::net::address;
+use crate::net::lacp;
+use crate::cmdline::Cmdline;
+use crate::NET_IFACES;
+
+static BONDS: Spinlock<Vec<Arc<BondNetif>>> = const_spinlock(Vec::new());
+
+// what the bond makes of a received frame
+pub enum BondIngress {
+  NotMember, // processed as on any interface
+  Consumed, // LACPDUs, and frames of members which aren't active
+  Bond(DataFromNetif), // the frame as the bond's
+}
+
+// the bond of the interface id, if it is one
+pub fn get_bond(netif_id: usize) -> Option<Arc<BondNetif>> {
+  BONDS.lock().iter().find(|bond| bond.get_id() == netif_id).map(|bond| Arc::clone(bond))
+}
+
+// the bond the interface id is a member of
+pub fn find_member_bond(netif_id: usize) -> Option<Arc<BondNetif>> {
+  BONDS.lock().iter()
+    .find(|bond| bond.get_members().iter().any(|member| member.get_id() == netif_id))
+    .map(|bond| Arc::clone(bond))
+}
+
+pub fn create_bond(members: Vec<Arc<dyn Netif>>) -> Result<Arc<dyn Netif>, &'static str> {
+  if members.len() == 0 {
+    return Err("a bond needs a member or more");
+  }
+  for (i, member) in members.iter().enumerate() {
+    if member.get_drivername() != "virtio-net" {
+      return Err("bond members must be virtio-net interfaces");
+    }
+    if members[..i].iter().any(|other| other.get_id() == member.get_id()) {
+      return Err("duplicate member");
+    }
+  }
+
+  let bond = {
+    let mut bonds = BONDS.lock();
+    if members.iter().any(|member| bonds.iter().any(|bond| bond.get_members().iter().any(|other| other.get_id() == member.get_id()))) {
+      return Err("interface is a bond member already");
+    }
+    let bond = Arc::new(BondNetif::new(address::next_netif_id(), members));
+    unsafe {
+      NET_IFACES.push(Arc::clone(&bond) as Arc<dyn Netif>);
+    }
+    bonds.push(Arc::clone(&bond));
+    bond
+  };
+
+  lacp::enable(Arc::clone(&bond));
+  let netif = bond as Arc<dyn Netif>;
+  address::attach_netif(&netif);
+  Ok(netif)
+}
+
+// hand a frame of a member over to its bond
+pub fn demux(frame: &DataFromNetif) -> BondIngress {
+  let bond = match find_member_bond(frame.get_netif().get_id()) {
+    Some(bond) => bond,
+    None => return BondIngress::NotMember,
+  };
+
+  let slice = frame.get_buffer().slice();
+  if frame.get_buffer().get_length() >= 15 && slice[0..6] == lacp::LACP_MACADDRESS
+    && slice[12..14] == lacp::ETHERTYPE_SLOW_PROTOCOLS && slice[14] == lacp::SUBTYPE_LACP {
+    lacp::receive(bond.get_id(), frame);
+    return BondIngress::Consumed;
+  }
+  match bond.is_active(frame.get_netif().get_id()) {
+    true => BondIngress::Bond(DataFromNetif::new(bond as Arc<dyn Netif>, Arc::clone(frame.get_buffer()))),
+    false => BondIngress::Consumed,
+  }
+}
+
+// members by index or mac address
+pub fn add_bond(members: &[&str]) -> Result<Arc<dyn Netif>, &'static str> {
+  let mut netifs = Vec::with_capacity(members.len());
+  for name in members.iter() {
+    netifs.push(address::find_netif(name).ok_or("no such interface")?);
+  }
+  create_bond(netifs)
+}
+
+fn configure_bond(arg: &str) -> Result<Arc<dyn Netif>, &'static str> {
+  let members: Vec<&str> = arg.split(',').collect();
+  add_bond(&members)
+}
+
+// create every urchin.bond= of the cmdline. members must be probed already.
+pub fn configure_bonds(cmdline: &Cmdline) {
+  for option in cmdline.get_all("urchin.bond") {
+    if let Some(netif) = option.parse_value(configure_bond) {
+      println!("Bond: {} is interface {}", option.get_value().unwrap_or(""), netif.get_id());
+    }
+  }
+}
@@ -0,0 +1,483 @@
+// layer 2 bridging between interfaces.
+// a bridge domain groups ports. frames to a learned mac address leave through its port, and the others,
+// i.e. unknown unicast, broadcast and multicast, are flooded to every port but the one they came in.
+// frames to the router's own mac addresses are processed locally as on interfaces which aren't bridged.
+// learned entries age out after the aging time of their bridge.
+//
+// bridges are vlan aware. a port is an access port of one vlan, untagged, or a trunk port of some vlans,
+// tagged but for the native vlan. ports are access ports of vlan 1 unless told otherwise.
+// tagged frames for a vlan sub-interface of the port go to the sub-interface instead.
+//
+// bridges run the rapid spanning tree of net::rstp unless told otherwise. ports learn and forward
+// only in the states the tree gives them. bridges without it forward on every port at once.
+//
+//   urchin.bridge=<interface>,<interface>[,...][,aging=<seconds>][,rstp=on|off][,priority=<bridge priority>]
+//   urchin.bridge_port=<interface>,access=<vlan id>
+//   urchin.bridge_port=<interface>,trunk=<vlan id>[-<vlan id>][,trunk=...][,native=<vlan id>]
+//
+// e.g. urchin.bridge=0,1,2,priority=4096 urchin.bridge=52:54:00:12:34:56,3,aging=60,rstp=off urchin.bridge_port=1,trunk=10-20,trunk=30,native=1
+
+use core::fmt;
+use core::time::Duration;
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::spinlock::{Spinlock, const_spinlock};
+use crate::arch::x86_64::kvmclock::get_monotonic_time;
+use crate::asynchronous::timer::TimerFuture;
+use crate::devices::netif::Netif;
+use crate::devices::vlan::ETHERTYPE_VLAN;
+use crate::net::DataFromNetif;
+use crate::net::ethernet::MacAddress;
+use crate::net::address;
+use crate::net::bond;
+use crate::net::vlan;
+use crate::net::rstp;
+use crate::cmdline;
+use crate::cmdline::Cmdline;
+
+const DEFAULT_AGING_TIME: Duration = Duration::from_secs(300);
+const DEFAULT_VLAN: u16 = 1;
+const AGING_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Copy, Clone)]
+pub struct FdbEntry {
+  port: usize, // netif id
+  expire_time: u64,
+}
+
+impl FdbEntry {
+  pub fn get_port(&self) -> usize {
+    self.port
+  }
+
+  pub fn get_expire_time(&self) -> u64 {
+    self.expire_time
+  }
+}
+
+// which vlans a port carries, and whether they are tagged on it
+#[derive(Clone)]
+pub enum PortMode {
+  Access(u16),
+  Trunk(Vec<(u16, u16)>, Option<u16>), // ranges of allowed vlans and the native vlan
+}
+
+impl PortMode {
+  // the vlan of a frame received with the tag, if the port takes it. priority tagged frames are untagged ones.
+  fn get_ingress_vlan(&self, tag: Option<u16>) -> Option<u16> {
+    match (self, tag) {
+      (PortMode::Access(vid), None) | (PortMode::Access(vid), Some(0)) => Some(*vid),
+      (PortMode::Access(_), Some(_)) => None,
+      (PortMode::Trunk(_, native), None) | (PortMode::Trunk(_, native), Some(0)) => *native,
+      (PortMode::Trunk(_, _), Some(vid)) => if self.carries(vid) { Some(vid) } else { None },
+    }
+  }
+
+  // whether frames of the vlan leave tagged, if the port carries the vlan
+  fn get_egress_tagged(&self, vid: u16) -> Option<bool> {
+    match self {
+      PortMode::Access(access_vid) => if *access_vid == vid { Some(false) } else { None },
+      PortMode::Trunk(_, native) if *native == Some(vid) => Some(false),
+      PortMode::Trunk(_, _) => if self.carries(vid) { Some(true) } else { None },
+    }
+  }
+
+  fn carries(&self, vid: u16) -> bool {
+    match self {
+      PortMode::Access(access_vid) => *access_vid == vid,
+      PortMode::Trunk(allowed, native) => *native == Some(vid) || allowed.iter().any(|(first, last)| *first <= vid && vid <= *last),
+    }
+  }
+
+  // access=<vlan id>, or trunk=<vlan id>[-<vlan id>] as many as needed with an optional native=<vlan id>
+  pub fn parse(fields: &[&str]) -> Result<PortMode, &'static str> {
+    let mut access = None;
+    let mut allowed = Vec::new();
+    let mut native = None;
+    for field in fields.iter() {
+      let (key, value) = match field.find('=') {
+        Some(idx) => (&field[..idx], &field[(idx+1)..]),
+        None => return Err("expected access=, trunk= or native="),
+      };
+      match key {
+        "access" => access = Some(vlan::parse_vlan_id(value)?),
+        "trunk" => allowed.push(match value.find('-') {
+          Some(idx) => (vlan::parse_vlan_id(&value[..idx])?, vlan::parse_vlan_id(&value[(idx+1)..])?),
+          None => (vlan::parse_vlan_id(value)?, vlan::parse_vlan_id(value)?),
+        }),
+        "native" => native = Some(vlan::parse_vlan_id(value)?),
+        _ => return Err("expected access=, trunk= or native="),
+      }
+    }
+    match access {
+      Some(_) if allowed.len() > 0 || native.is_some() => Err("a port is either access or trunk"),
+      Some(vid) => Ok(PortMode::Access(vid)),
+      None if allowed.len() == 0 && native.is_none() => Err("no vlan is given"),
+      None => Ok(PortMode::Trunk(allowed, native)),
+    }
+  }
+}
+
+impl fmt::Display for PortMode {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      PortMode::Access(vid) => write!(f, "access {}", vid),
+      PortMode::Trunk(allowed, native) => {
+        write!(f, "trunk")?;
+        for (first, last) in allowed.iter() {
+          match first == last {
+            true => write!(f, " {}", first)?,
+            false => write!(f, " {}-{}", first, last)?,
+          }
+        }
+        match native {
+          Some(vid) => write!(f, " native {}", vid),
+          None => Ok(()),
+        }
+      },
+    }
+  }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PortState {
+  Discarding,
+  Learning,
+  Forwarding,
+}
+
+impl fmt::Display for PortState {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      PortState::Discarding => write!(f, "discarding"),
+      PortState::Learning => write!(f, "learning"),
+      PortState::Forwarding => write!(f, "forwarding"),
+    }
+  }
+}
+
+#[derive(Clone)]
+pub struct BridgePort {
+  netif: Arc<dyn Netif>,
+  mode: PortMode,
+  state: PortState,
+}
+
+impl BridgePort {
+  pub fn get_netif(&self) -> &Arc<dyn Netif> {
+    &self.netif
+  }
+
+  pub fn get_mode(&self) -> &PortMode {
+    &self.mode
+  }
+
+  pub fn get_state(&self) -> PortState {
+    self.state
+  }
+}
+
+// what the bridge makes of a received frame
+pub enum Ingress {
+  NotBridged, // processed as on any interface
+  Blocked, // the port doesn't take the frame, or the spanning tree keeps it discarding
+  Forward(u16), // bridged in the vlan
+}
+
+#[derive(Clone)]
+pub struct Bridge {
+  id: usize,
+  ports: Vec<BridgePort>,
+  aging_time: u64, // nanoseconds
+  fdb: BTreeMap<(u16, MacAddress), FdbEntry>, // by vlan and address
+}
+
+impl Bridge {
+  pub fn get_id(&self) -> usize {
+    self.id
+  }
+
+  pub fn get_ports(&self) -> &Vec<BridgePort> {
+    &self.ports
+  }
+
+  pub fn get_aging_time(&self) -> Duration {
+    Duration::from_nanos(self.aging_time)
+  }
+
+  pub fn get_fdb(&self) -> &BTreeMap<(u16, MacAddress), FdbEntry> {
+    &self.fdb
+  }
+
+  fn get_port(&self, netif_id: usize) -> Option<&BridgePort> {
+    self.ports.iter().find(|port| port.netif.get_id() == netif_id)
+  }
+
+  fn has_port(&self, netif_id: usize) -> bool {
+    self.get_port(netif_id).is_some()
+  }
+}
+
+static BRIDGES: Spinlock<Vec<Bridge>> = const_spinlock(Vec::new());
+
+fn is_group_address(macaddr: &MacAddress) -> bool {
+  (macaddr.get_array()[0] & 0x01) != 0
+}
+
+// 01:80:c2:00:00:00 to 01:80:c2:00:00:0f are for the link only, e.g. BPDUs
+fn is_reserved_address(macaddr: &MacAddress) -> bool {
+  let octets = macaddr.get_array();
+  octets[0..5] == rstp::BPDU_MACADDRESS[0..5] && (octets[5] & 0xf0) == 0
+}
+
+// the vlan id of the 802.1Q tag, if the frame has one
+fn get_tag(frame: &DataFromNetif) -> Option<u16> {
+  let slice = frame.get_buffer().slice();
+  match frame.get_buffer().get_length() >= 18 && [slice[12], slice[13]] == ETHERTYPE_VLAN {
+    true => Some(vlan::get_tci_vid(&slice[14..16])),
+    false => None,
+  }
+}
+
+// returns the id of the new bridge. ports are in access mode of vlan 1 until told otherwise.
+pub fn create_bridge(ports: Vec<Arc<dyn Netif>>, aging_time: Duration) -> Result<usize, &'static str> {
+  if ports.len() < 2 {
+    return Err("a bridge needs two ports or more");
+  }
+  for (i, port) in ports.iter().enumerate() {
+    if ports[..i].iter().any(|other| other.get_id() == port.get_id()) {
+      return Err("duplicate port");
+    }
+    if bond::find_member_bond(port.get_id()).is_some() {
+      return Err("interface is a bond member");
+    }
+  }
+
+  let mut bridges = BRIDGES.lock();
+  if ports.iter().any(|port| bridges.iter().any(|bridge| bridge.has_port(port.get_id()))) {
+    return Err("interface is bridged already");
+  }
+  let id = bridges.len();
+  bridges.push(Bridge {
+    id: id,
+    ports: ports.into_iter().map(|netif| BridgePort { netif: netif, mode: PortMode::Access(DEFAULT_VLAN), state: PortState::Forwarding }).collect(),
+    aging_time: aging_time.as_nanos() as u64,
+    fdb: BTreeMap::new(),
+  });
+  Ok(id)
+}
+
+pub fn set_port_mode(netif_id: usize, mode: PortMode) -> Result<(), &'static str> {
+  let mut bridges = BRIDGES.lock();
+  for bridge in bridges.iter_mut() {
+    if let Some(port) = bridge.ports.iter_mut().find(|port| port.netif.get_id() == netif_id) {
+      port.mode = mode;
+      // what was learned in the vlans the port leaves must not point to it
+      bridge.fdb.retain(|_, entry| entry.port != netif_id);
+      return Ok(());
+    }
+  }
+  Err("interface isn't bridged")
+}
+
+// the spanning tree moves ports between states
+pub fn set_port_state(netif_id: usize, state: PortState) -> Result<(), &'static str> {
+  let mut bridges = BRIDGES.lock();
+  for bridge in bridges.iter_mut() {
+    if let Some(port) = bridge.ports.iter_mut().find(|port| port.netif.get_id() == netif_id) {
+      port.state = state;
+      if state == PortState::Discarding {
+        bridge.fdb.retain(|_, entry| entry.port != netif_id);
+      }
+      return Ok(());
+    }
+  }
+  Err("interface isn't bridged")
+}
+
+// forget what was learned on the ports of the bridge but one, after a topology change
+pub fn flush_fdb(bridge_id: usize, except_port: usize) {
+  if let Some(bridge) = BRIDGES.lock().get_mut(bridge_id) {
+    bridge.fdb.retain(|_, entry| entry.port == except_port);
+  }
+}
+
+pub fn get_bridges() -> Vec<Bridge> {
+  BRIDGES.lock().clone()
+}
+
+// learn the source address on the bridge of the ingress port, if the port is learning or forwarding.
+// frames are bridged only from forwarding ports.
+pub fn learn(frame: &DataFromNetif, src: MacAddress) -> Ingress {
+  let ingress = frame.get_netif().get_id();
+  let mut bridges = BRIDGES.lock();
+  let bridge = match bridges.iter_mut().find(|bridge| bridge.has_port(ingress)) {
+    Some(bridge) => bridge,
+    None => return Ingress::NotBridged,
+  };
+  let port = match bridge.get_port(ingress) {
+    Some(port) => port,
+    None => return Ingress::NotBridged,
+  };
+  let state = port.state;
+  let vid = match port.mode.get_ingress_vlan(get_tag(frame)) {
+    Some(vid) if state != PortState::Discarding => vid,
+    _ => return Ingress::Blocked,
+  };
+  if !is_group_address(&src) {
+    let expire_time = get_monotonic_time() + bridge.aging_time;
+    bridge.fdb.insert((vid, src), FdbEntry { port: ingress, expire_time: expire_time });
+  }
+  match state {
+    PortState::Forwarding => Ingress::Forward(vid),
+    _ => Ingress::Blocked,
+  }
+}
+
+// send the frame of the vlan toward `dest` on the bridge of its ingress port, tagged as each egress port wants.
+// nothing goes back out of the ingress port, nor out of ports which aren't forwarding.
+pub fn forward(frame: &DataFromNetif, vid: u16, dest: MacAddress) {
+  if is_reserved_address(&dest) {
+    return;
+  }
+  let ingress = frame.get_netif().get_id();
+  let egress: Vec<(Arc<dyn Netif>, bool)> = {
+    let bridges = BRIDGES.lock();
+    let bridge = match bridges.iter().find(|bridge| bridge.has_port(ingress)) {
+      Some(bridge) => bridge,
+      None => return,
+    };
+    let known_port = match bridge.fdb.get(&(vid, dest)) {
+      Some(entry) if !is_group_address(&dest) && entry.expire_time >= get_monotonic_time() => Some(entry.port),
+      _ => None,
+    };
+    bridge.ports.iter()
+      .filter(|port| port.state == PortState::Forwarding)
+      .filter(|port| port.netif.get_id() != ingress && known_port.map(|known_port| port.netif.get_id() == known_port).unwrap_or(true))
+      .filter_map(|port| port.mode.get_egress_tagged(vid).map(|tagged| (Arc::clone(&port.netif), tagged)))
+      .collect()
+  };
+
+  let ingress_tagged = get_tag(frame).is_some();
+  for (netif, tagged) in egress.iter() {
+    transmit(netif, frame, ingress_tagged, if *tagged { Some(vid) } else { None });
+  }
+}
+
+// copy the frame for the egress port, taking off or putting on the 802.1Q tag
+fn transmit(netif: &Arc<dyn Netif>, frame: &DataFromNetif, ingress_tagged: bool, tag: Option<u16>) {
+  let slice = frame.get_buffer().slice();
+  let length = frame.get_buffer().get_length();
+  let payload_offset = if ingress_tagged { 16 } else { 12 }; // from the ethertype of the payload
+  if length < payload_offset + 2 || length - payload_offset > 2 + netif.get_mtu() {
+    return;
+  }
+  let tag_length = if tag.is_some() { 4 } else { 0 };
+  let out_length = 12 + tag_length + (length - payload_offset);
+
+  let buffer = netif.pre_xmit(out_length);
+  let out_slice = buffer.slice_mut();
+  out_slice[0..12].copy_from_slice(&slice[0..12]);
+  if let Some(vid) = tag {
+    // keep the priority of the received tag
+    let pcp = if ingress_tagged { slice[14] & 0xf0 } else { 0 };
+    out_slice[12..14].copy_from_slice(&ETHERTYPE_VLAN);
+    out_slice[14] = pcp | (vid >> 8) as u8;
+    out_slice[15] = vid as u8;
+  }
+  out_slice[(12+tag_length)..out_length].copy_from_slice(&slice[payload_offset..length]);
+  let _ = netif.xmit(buffer);
+}
+
+// evict learned entries which are older than the aging time of their bridge
+pub async fn expire_bridge_entries() {
+  loop {
+    TimerFuture::new(AGING_INTERVAL).await;
+
+    let now = get_monotonic_time();
+    for bridge in BRIDGES.lock().iter_mut() {
+      bridge.fdb.retain(|_, entry| entry.expire_time >= now);
+    }
+  }
+}
+
+fn parse_bridge_priority(s: &str) -> Result<u16, &'static str> {
+  match cmdline::parse_integer(s)? {
+    priority if priority <= 61440 && priority % 4096 == 0 => Ok(priority as u16),
+    _ => Err("bridge priority must be a multiple of 4096 up to 61440"),
+  }
+}
+
+// ports by index or mac address, and options as key and value:
+// aging in seconds, rstp on or off, and the bridge priority of the spanning tree
+pub fn add_bridge(ports: &[&str], options: &[(&str, &str)]) -> Result<usize, &'static str> {
+  let mut aging_time = DEFAULT_AGING_TIME;
+  let mut stp = true;
+  let mut priority = rstp::DEFAULT_BRIDGE_PRIORITY;
+  for (key, value) in options.iter() {
+    match (*key, *value) {
+      ("aging", seconds) => aging_time = Duration::from_secs(cmdline::parse_integer(seconds)?),
+      ("rstp", "on") => stp = true,
+      ("rstp", "off") => stp = false,
+      ("rstp", _) => return Err("expected rstp on or off"),
+      ("priority", value) => priority = parse_bridge_priority(value)?,
+      _ => return Err("unknown bridge option"),
+    }
+  }
+  let mut netifs = Vec::with_capacity(ports.len());
+  for name in ports.iter() {
+    netifs.push(address::find_netif(name).ok_or("no such interface")?);
+  }
+  let id = create_bridge(netifs.clone(), aging_time)?;
+  if stp {
+    rstp::enable(id, netifs, priority);
+  }
+  Ok(id)
+}
+
+fn configure_bridge(arg: &str) -> Result<usize, &'static str> {
+  let mut ports = Vec::new();
+  let mut options = Vec::new();
+  for field in arg.split(',') {
+    match field.find('=') {
+      Some(idx) => options.push((&field[..idx], &field[(idx+1)..])),
+      None => ports.push(field),
+    }
+  }
+  add_bridge(&ports, &options)
+}
+
+// create every urchin.bridge= of the cmdline. interfaces must be probed already.
+pub fn configure_bridges(cmdline: &Cmdline) {
+  for option in cmdline.get_all("urchin.bridge") {
+    if let Some(id) = option.parse_value(configure_bridge) {
+      println!("Bridge: br{} {}", id, option.get_value().unwrap_or(""));
+    }
+  }
+}
+
+// the port by index or mac address, and the fields of PortMode::parse
+pub fn configure_port(name: &str, fields: &[&str]) -> Result<(), &'static str> {
+  let netif = address::find_netif(name).ok_or("no such interface")?;
+  set_port_mode(netif.get_id(), PortMode::parse(fields)?)
+}
+
+fn configure_bridge_port(arg: &str) -> Result<(), &'static str> {
+  let fields: Vec<&str> = arg.split(',').collect();
+  if fields.len() < 2 {
+    return Err("expected <interface>,access=<vlan id> or <interface>,trunk=<vlan ids>[,native=<vlan id>]");
+  }
+  configure_port(fields[0], &fields[1..])
+}
+
+// set up every urchin.bridge_port= of the cmdline. bridges must be created already.
+pub fn configure_bridge_ports(cmdline: &Cmdline) {
+  for option in cmdline.get_all("urchin.bridge_port") {
+    if let Some(_) = option.parse_value(configure_bridge_port) {
+      println!("Bridge port: {}", option.get_value().unwrap_or(""));
+    }
+  }
+}
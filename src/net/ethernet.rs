@@ -1,11 +1,19 @@
 use core::cmp::Ordering;
+use core::fmt;
 
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 
 use crate::devices::buffer::Buffer;
 use crate::net::{DataFromNetif, ProcessingNode};
-use crate::net::fib::{MAC_ADDR_TABLE, AdjacentInformation,register_macaddress};
+use crate::net::fib::{MAC_ADDR_TABLE, AdjacentInformation,register_macaddress, macaddress_expire_time};
+use crate::net::bond;
+use crate::net::bond::BondIngress;
+use crate::net::bridge;
+use crate::net::bridge::Ingress;
+use crate::net::rstp;
+use crate::net::lldp;
+use crate::net::vlan;
 use crate::net::arp::ArpIn;
 use crate::PROC_NODES;
 
@@ -35,6 +43,20 @@ impl MacAddress {
   pub fn get_prim(&self) -> u64 {
     self.addr_prim
   }
+
+  // colon or hyphen separated, e.g. "52:54:00:12:34:56"
+  pub fn parse(s: &str) -> Option<MacAddress> {
+    let mut addr = [0u8; 6];
+    let mut count = 0;
+    for octet in s.split(|c| c == ':' || c == '-') {
+      if octet.len() != 2 || !octet.bytes().all(|c| c.is_ascii_hexdigit()) || count == 6 {
+        return None;
+      }
+      addr[count] = u8::from_str_radix(octet, 16).ok()?;
+      count += 1;
+    }
+    if count == 6 { Some(MacAddress::new(addr)) } else { None }
+  }
 }
 
 impl Ord for MacAddress {
@@ -57,6 +79,13 @@ impl PartialEq for MacAddress {
   }
 }
 
+impl fmt::Display for MacAddress {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let arr = self.addr;
+    write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", arr[0], arr[1], arr[2], arr[3], arr[4], arr[5])
+  }
+}
+
 ////////
 
 pub struct EthernetIn;
@@ -87,7 +116,31 @@ impl ProcessingNode for EthernetIn {
     let mut ipv4_pkts = Vec::with_capacity(buff.len());
     let mut ipv6_pkts = Vec::with_capacity(buff.len());
 
+    let mut upper_frames = Vec::new(); // of bonds and vlan sub-interfaces
+
     for frame in buff.iter() {
+      // LLDPDUs are about the link they came in on, before bonds and vlans take the frame
+      if lldp::is_lldpdu(frame) {
+        lldp::receive(frame);
+        continue;
+      }
+
+      // frames of bond members are the bond's
+      match bond::demux(frame) {
+        BondIngress::NotMember => (),
+        BondIngress::Consumed => continue,
+        BondIngress::Bond(bond_frame) => {
+          upper_frames.push(bond_frame);
+          continue;
+        },
+      }
+
+      // tagged frames for a vlan sub-interface are its own
+      if let Some(vlan_frame) = vlan::demux(frame) {
+        upper_frames.push(vlan_frame);
+        continue;
+      }
+
       let slice = frame.get_buffer().slice();
       let header = unsafe { &*((&slice[0] as *const _) as *const EthernetFrame) };
       let mut proc_frame = || {
@@ -101,7 +154,7 @@ impl ProcessingNode for EthernetIn {
             ipv4_pkts.push(frame.clone());
           },
           [0x81, 0x00] => {
-            //VLAN
+            //VLAN of no sub-interface. only a bridge takes it.
           },
           [0x86, 0xDD] => {
             //IPv6
@@ -111,36 +164,52 @@ impl ProcessingNode for EthernetIn {
         }
       };
 
-      // learn MAC address
-      register_macaddress(MacAddress::new(header.src_addr), Arc::clone(frame.get_netif()), false, None);      
+      let src_addr = MacAddress::new(header.src_addr);
+      let dest_addr = MacAddress::new(header.dest_addr);
 
-      let mactable = MAC_ADDR_TABLE.lock();
+      // BPDUs are for the spanning tree of the bridge, and go no further
+      if header.dest_addr == rstp::BPDU_MACADDRESS {
+        rstp::receive(frame);
+        continue;
+      }
+
+      // learn MAC address. ports of a bridge learn into its own table, per vlan.
+      let bridged_vlan = match bridge::learn(frame, src_addr) {
+        Ingress::NotBridged => {
+          register_macaddress(src_addr, Arc::clone(frame.get_netif()), false, macaddress_expire_time());
+          None
+        },
+        // the spanning tree keeps the port from taking frames
+        Ingress::Blocked => continue,
+        Ingress::Forward(vid) => Some(vid),
+      };
+
+      let is_local = MAC_ADDR_TABLE.lock().get(&dest_addr).map(|adj| adj.is_local()).unwrap_or(false);
       if header.dest_addr == [0xff, 0xff, 0xff, 0xff, 0xff, 0xff] {
         //broadcast address
         proc_frame();
-        //todo: l2sw with flood
-        //todo: multicast mac
-      } else if let Some(adj) = mactable.get(&MacAddress::new(header.dest_addr)) {
-        if adj.is_local() {
-          // this mac address is myself. so I'll process it.
-          proc_frame();
-
-          // even if it's own, i must do switching l2 if it's multicast.
-          match header.dest_addr[0..3] {
-            [0x33, 0x33, _] => (), //IPv6 Multicast
-            [0x01, 0x00, 0x5e] => (), //IPv4 Multicast
-            _ => (),
-          }
-        } else {
-          // i know this mac address but it's not own.
-          // todo: implement l2 switching
+        if let Some(vid) = bridged_vlan {
+          bridge::forward(frame, vid, dest_addr);
         }
+      } else if is_local {
+        // this mac address is myself. so I'll process it.
+        proc_frame();
+
+        // even if it's own, i must do switching l2 if it's multicast.
+        if let (Some(vid), true) = (bridged_vlan, (header.dest_addr[0] & 0x01) != 0) {
+          bridge::forward(frame, vid, dest_addr);
+        }
+      } else if let Some(vid) = bridged_vlan {
+        // known unicast goes to its port. unknown unicast and multicast are flooded.
+        bridge::forward(frame, vid, dest_addr);
       } else {
-        // i don't know this mac address.
-        // todo: implement l2 switching with flooding
+        // not for me, and the interface isn't bridged.
       }
     }
 
+    if upper_frames.len() > 0 {
+      self.process(&upper_frames);
+    }
     if arp_pkts.len() > 0 {
       if let Some(node_ref) = unsafe { PROC_NODES.get("arp-in") } {
         node_ref.process(&arp_pkts);
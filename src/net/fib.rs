@@ -1,18 +1,130 @@
+pub mod lpm;
+pub mod bench;
+
+use core::mem::size_of;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use core::time::Duration;
+
 use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 
 use crate::spinlock::{ Spinlock, const_spinlock };
+use crate::asynchronous::timer::TimerFuture;
+use crate::arch::x86_64::kvmclock::get_monotonic_time;
 use crate::devices::netif::Netif;
 use crate::net::ethernet::MacAddress;
 use crate::net::ipv4::Ipv4Address;
 use crate::net::ipv6::Ipv6Address;
+use crate::net::fib::lpm::{MultibitTrie, MAX_VALUE};
+use crate::net::rib;
+use crate::net::rib::RouteSource;
+use crate::cmdline;
+use crate::cmdline::Cmdline;
 
 #[derive(Copy, Clone)]
 pub enum FIBType {
   Remote,
+  RemoteUnresolved, // the next hop is on-link, but its mac address isn't known yet
   Adjacent,
   AdjacentResolved,
   Local,
+  Blackhole, // drop silently
+  Reject, // drop and tell the sender the destination is unreachable
+}
+
+// equal or weighted cost next hops of a prefix
+#[derive(Clone)]
+pub struct NexthopGroup<F> {
+  nexthops: Vec<(F, u32)>, // next hop and its weight
+}
+
+impl<F> NexthopGroup<F> {
+  pub fn new(nexthop: F, weight: u32) -> NexthopGroup<F> {
+    let mut nexthops = Vec::with_capacity(1);
+    nexthops.push((nexthop, if weight == 0 { 1 } else { weight }));
+    NexthopGroup {
+      nexthops: nexthops,
+    }
+  }
+
+  pub fn get_nexthops(&self) -> &Vec<(F, u32)> {
+    &self.nexthops
+  }
+
+  pub fn first(&self) -> Option<&F> {
+    self.nexthops.first().map(|(nexthop, _)| nexthop)
+  }
+
+  pub fn len(&self) -> usize {
+    self.nexthops.len()
+  }
+
+  pub fn push(&mut self, nexthop: F, weight: u32) {
+    self.nexthops.push((nexthop, if weight == 0 { 1 } else { weight }));
+  }
+
+  // add a next hop, or update the weight of the one `is_same` matches
+  fn insert<P: Fn(&F) -> bool>(&mut self, nexthop: F, weight: u32, is_same: P) {
+    let weight = if weight == 0 { 1 } else { weight };
+    match self.nexthops.iter_mut().find(|(n, _)| is_same(n)) {
+      Some(entry) => *entry = (nexthop, weight),
+      None => self.nexthops.push((nexthop, weight)),
+    }
+  }
+
+  fn remove<P: Fn(&F) -> bool>(&mut self, is_same: P) {
+    self.nexthops.retain(|(n, _)| !is_same(n));
+  }
+
+  // hash-threshold selection (RFC 2992). the hash space is split in proportion to the weights,
+  // so adding or removing a next hop only moves the flows next to the boundaries.
+  pub fn select(&self, flow_hash: u32) -> Option<&F> {
+    if self.nexthops.len() == 1 {
+      return self.first();
+    }
+    let total_weight: u64 = self.nexthops.iter().map(|(_, w)| *w as u64).sum();
+    let point = (flow_hash as u64 * total_weight) >> 32;
+    let mut threshold = 0u64;
+    for (nexthop, weight) in self.nexthops.iter() {
+      threshold += *weight as u64;
+      if point < threshold {
+        return Some(nexthop);
+      }
+    }
+    self.first()
+  }
+}
+
+static ECMP_HASH_SEED: AtomicU32 = AtomicU32::new(0);
+
+// routers sharing a path should use different seeds, or they all split the traffic the same way (polarization)
+pub fn set_ecmp_hash_seed(seed: u32) {
+  ECMP_HASH_SEED.store(seed, Ordering::Relaxed);
+}
+
+pub fn get_ecmp_hash_seed() -> u32 {
+  ECMP_HASH_SEED.load(Ordering::Relaxed)
+}
+
+// seeded murmur3 over the words identifying a flow
+pub fn calc_flow_hash(words: &[u32]) -> u32 {
+  let mut h = get_ecmp_hash_seed();
+  for word in words.iter() {
+    let mut k = word.wrapping_mul(0xcc9e2d51);
+    k = k.rotate_left(15);
+    k = k.wrapping_mul(0x1b873593);
+    h ^= k;
+    h = h.rotate_left(13);
+    h = h.wrapping_mul(5).wrapping_add(0xe6546b64);
+  }
+  h ^= (words.len() * 4) as u32;
+  h ^= h >> 16;
+  h = h.wrapping_mul(0x85ebca6b);
+  h ^= h >> 13;
+  h = h.wrapping_mul(0xc2b2ae35);
+  h ^= h >> 16;
+  h
 }
 
 #[derive(Clone)]
@@ -50,80 +162,192 @@ impl ForwardInformationBaseIpv4 {
   }
 }
 
-pub static mut IPV4_FIB_INDEX: [BTreeMap<Ipv4Address, ForwardInformationBaseIpv4>; 33] = [
-  BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(),
-  BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(),
-  BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(),
-  BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(),
-  BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(),
-  BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(),
-  BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(),
-  BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(),
-  BTreeMap::new(), 
-];
-
-fn ipv4_mask_to_prefixlen(mask: u32) -> usize {
-  match mask {
-    0xffffffff => 32,
-    0xfffffffe => 31,
-    0xfffffffc => 30,
-    0xfffffff8 => 29,
-    0xfffffff0 => 28,
-    0xffffffe0 => 27,
-    0xffffffc0 => 26,
-    0xffffff80 => 25,
-    0xffffff00 => 24,
-    0xfffffe00 => 23,
-    0xfffffc00 => 22,
-    0xfffff800 => 21,
-    0xfffff000 => 20,
-    0xffffe000 => 19,
-    0xffffc000 => 18,
-    0xffff8000 => 17,
-    0xffff0000 => 16,
-    0xfffe0000 => 15,
-    0xfffc0000 => 14,
-    0xfff80000 => 13,
-    0xfff00000 => 12,
-    0xffe00000 => 11,
-    0xffc00000 => 10,
-    0xff800000 => 9,
-    0xff000000 => 8,
-    0xfe000000 => 7,
-    0xfc000000 => 6,
-    0xf8000000 => 5,
-    0xf0000000 => 4,
-    0xe0000000 => 3,
-    0xc0000000 => 2,
-    0x80000000 => 1,
-    0x00000000 => 0,
-    _ => 0,
+// routes of one address family. prefixes are kept exactly in `routes` for updates,
+// and the trie maps every address to the next hop group of its longest matching prefix.
+pub struct RouteTable<F> {
+  key_bits: u32,
+  routes: BTreeMap<(u128, u32), u32>, // (prefix, length) -> index of the next hop group
+  groups: Vec<Option<NexthopGroup<F>>>,
+  free_groups: Vec<u32>,
+  trie: MultibitTrie,
+}
+
+impl<F> RouteTable<F> {
+  pub const fn new(key_bits: u32, root_bits: u32) -> RouteTable<F> {
+    RouteTable {
+      key_bits: key_bits,
+      routes: BTreeMap::new(),
+      groups: Vec::new(),
+      free_groups: Vec::new(),
+      trie: MultibitTrie::new(key_bits, root_bits),
+    }
+  }
+
+  fn mask(&self, key: u128, length: u32) -> u128 {
+    if length == 0 {
+      0
+    } else {
+      key & !((1u128 << (self.key_bits - length)).wrapping_sub(1))
+    }
   }
+
+  pub fn len(&self) -> usize {
+    self.routes.len()
+  }
+
+  pub fn get(&self, key: u128, length: u32) -> Option<&NexthopGroup<F>> {
+    let index = *self.routes.get(&(self.mask(key, length), length))?;
+    self.groups[index as usize].as_ref()
+  }
+
+  pub fn get_mut(&mut self, key: u128, length: u32) -> Option<&mut NexthopGroup<F>> {
+    let index = *self.routes.get(&(self.mask(key, length), length))?;
+    self.groups[index as usize].as_mut()
+  }
+
+  // add the prefix or replace its next hops. false if the table is full.
+  pub fn insert(&mut self, key: u128, length: u32, group: NexthopGroup<F>) -> bool {
+    let key = self.mask(key, length);
+    if let Some(index) = self.routes.get(&(key, length)) {
+      self.groups[*index as usize] = Some(group);
+      return true;
+    }
+
+    let index = match self.free_groups.pop() {
+      Some(index) => {
+        self.groups[index as usize] = Some(group);
+        index
+      },
+      None => {
+        if self.groups.len() as u32 > MAX_VALUE {
+          return false;
+        }
+        self.groups.push(Some(group));
+        (self.groups.len() - 1) as u32
+      },
+    };
+    self.routes.insert((key, length), index);
+    self.trie.insert(key, length, index);
+    true
+  }
+
+  pub fn remove(&mut self, key: u128, length: u32) -> Option<NexthopGroup<F>> {
+    let key = self.mask(key, length);
+    let index = self.routes.remove(&(key, length))?;
+
+    // the addresses fall back to the longest prefix left above this one
+    let mut covering = None;
+    for l in (0..length).rev() {
+      if let Some(i) = self.routes.get(&(self.mask(key, l), l)) {
+        covering = Some((*i, l));
+        break;
+      }
+    }
+    self.trie.remove(key, length, covering);
+
+    self.free_groups.push(index);
+    self.groups[index as usize].take()
+  }
+
+  pub fn lookup(&self, key: u128) -> Option<&NexthopGroup<F>> {
+    let index = self.trie.lookup(key)?;
+    self.groups[index as usize].as_ref()
+  }
+
+  // longest match among the prefixes not longer than `max_length`. slow, for the control plane.
+  pub fn lookup_within(&self, key: u128, max_length: u32) -> Option<&NexthopGroup<F>> {
+    if max_length >= self.key_bits {
+      return self.lookup(key);
+    }
+    for l in (0..(max_length+1)).rev() {
+      if let Some(group) = self.get(key, l) {
+        return Some(group);
+      }
+    }
+    None
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = (u128, u32, &NexthopGroup<F>)> {
+    self.routes.iter().filter_map(move |((key, length), index)| {
+      self.groups[*index as usize].as_ref().map(|group| (*key, *length, group))
+    })
+  }
+
+  // approximate bytes held by the table
+  pub fn get_memory_usage(&self) -> usize {
+    let route_size = size_of::<(u128, u32)>() + size_of::<u32>();
+    let nexthops: usize = self.groups.iter().filter_map(|g| g.as_ref()).map(|g| g.nexthops.capacity() * size_of::<(F, u32)>()).sum();
+    self.trie.get_memory_usage()
+      + self.routes.len() * route_size
+      + self.groups.capacity() * size_of::<Option<NexthopGroup<F>>>()
+      + self.free_groups.capacity() * size_of::<u32>()
+      + nexthops
+  }
+}
+
+// DIR-24-8
+pub static mut IPV4_FIB: RouteTable<ForwardInformationBaseIpv4> = RouteTable::new(32, 24);
+
+pub fn ipv4_mask_to_prefixlen(mask: u32) -> u32 {
+  // non-contiguous masks are taken by their leading ones
+  (!mask).leading_zeros()
 }
 
 pub fn register_ipv4_fib(ip_address: Ipv4Address, mask: u32, nexthop_macaddress: MacAddress, nexthop_address: Ipv4Address, netif: Arc<dyn Netif>, fib_type: FIBType) {
-  let fib_index = ipv4_mask_to_prefixlen(mask);
+  let table = unsafe { &mut IPV4_FIB };
+  table.insert(ip_address.get_prim() as u128, ipv4_mask_to_prefixlen(mask), NexthopGroup::new(ForwardInformationBaseIpv4::new(nexthop_macaddress, nexthop_address, netif, fib_type), 1));
+}
+
+// add a next hop to the prefix, next to the existing ones. a next hop already there gets the new weight.
+pub fn add_ipv4_nexthop(ip_address: Ipv4Address, mask: u32, nexthop_macaddress: MacAddress, nexthop_address: Ipv4Address, netif: Arc<dyn Netif>, fib_type: FIBType, weight: u32) {
+  let table = unsafe { &mut IPV4_FIB };
+  let prefix_length = ipv4_mask_to_prefixlen(mask);
+  let netif_id = netif.get_id();
+  let fib = ForwardInformationBaseIpv4::new(nexthop_macaddress, nexthop_address, netif, fib_type);
+  match table.get_mut(ip_address.get_prim() as u128, prefix_length) {
+    Some(group) => group.insert(fib, weight, |n| n.get_nexthop_address() == nexthop_address && n.get_netif().get_id() == netif_id),
+    None => {
+      table.insert(ip_address.get_prim() as u128, prefix_length, NexthopGroup::new(fib, weight));
+    },
+  }
+}
 
-  let table = unsafe { &mut IPV4_FIB_INDEX[fib_index] };
-  table.insert(ip_address, ForwardInformationBaseIpv4::new(nexthop_macaddress, nexthop_address, netif, fib_type));
+// remove a next hop from the prefix. the prefix itself goes away with its last next hop.
+pub fn remove_ipv4_nexthop(ip_address: Ipv4Address, mask: u32, nexthop_address: Ipv4Address) {
+  let table = unsafe { &mut IPV4_FIB };
+  let prefix_length = ipv4_mask_to_prefixlen(mask);
+  let is_empty = match table.get_mut(ip_address.get_prim() as u128, prefix_length) {
+    Some(group) => {
+      group.remove(|n| n.get_nexthop_address() == nexthop_address);
+      group.len() == 0
+    },
+    None => false,
+  };
+  if is_empty {
+    table.remove(ip_address.get_prim() as u128, prefix_length);
+  }
 }
 
-pub fn find_ipv4_fib(ip_address: &Ipv4Address, mask: u32) -> Option<&'static ForwardInformationBaseIpv4> {
-  let mut fib_index = ipv4_mask_to_prefixlen(mask);
+// replace all next hops of the prefix at once
+pub fn install_ipv4_fib(ip_address: Ipv4Address, mask: u32, group: NexthopGroup<ForwardInformationBaseIpv4>) {
+  let table = unsafe { &mut IPV4_FIB };
+  table.insert(ip_address.get_prim() as u128, ipv4_mask_to_prefixlen(mask), group);
+}
 
-  // longest match
-  loop {
-    if let Some(fib) = unsafe { IPV4_FIB_INDEX[fib_index].get(&ip_address.masked(fib_index as u32)) } {
-      return Some(fib)
-    }
+pub fn unregister_ipv4_fib(ip_address: Ipv4Address, mask: u32) {
+  let table = unsafe { &mut IPV4_FIB };
+  table.remove(ip_address.get_prim() as u128, ipv4_mask_to_prefixlen(mask));
+}
 
-    if fib_index == 0 {
-      break;
-    } 
+// the next hops of exactly this prefix
+pub fn find_ipv4_fib_exact(ip_address: &Ipv4Address, mask: u32) -> Option<&'static NexthopGroup<ForwardInformationBaseIpv4>> {
+  unsafe { IPV4_FIB.get(ip_address.get_prim() as u128, ipv4_mask_to_prefixlen(mask)) }
+}
 
-    fib_index = fib_index - 1;
-  }
-  None
+// longest match. `flow_hash` picks one of the next hops when the matched prefix has several.
+pub fn find_ipv4_fib(ip_address: &Ipv4Address, mask: u32, flow_hash: u32) -> Option<&'static ForwardInformationBaseIpv4> {
+  let table = unsafe { &IPV4_FIB };
+  table.lookup_within(ip_address.get_prim() as u128, ipv4_mask_to_prefixlen(mask))?.select(flow_hash)
 }
 
 #[derive(Clone)]
@@ -161,50 +385,74 @@ impl ForwardInformationBaseIpv6 {
   }
 }
 
-
-pub static mut IPV6_FIB_INDEX: [BTreeMap<Ipv6Address, ForwardInformationBaseIpv6>; 129] = [
-  BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(),
-  BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(),
-  BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(),
-  BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(),
-  BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(),
-  BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(),
-  BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(),
-  BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(),
-  BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(),
-  BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(),
-  BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(),
-  BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(),
-  BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(),
-  BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(),
-  BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(),
-  BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(), BTreeMap::new(),
-  BTreeMap::new(), 
-];
+// 16 bits at the root, then 8 bits per level
+pub static mut IPV6_FIB: RouteTable<ForwardInformationBaseIpv6> = RouteTable::new(128, 16);
 
 pub fn register_ipv6_fib(ip_address: Ipv6Address, prefix: u32, nexthop_macaddress: MacAddress, nexthop_address: Ipv6Address, netif: Arc<dyn Netif>, fib_type: FIBType) {
-  let table = unsafe { &mut IPV6_FIB_INDEX[prefix as usize] };
-  table.insert(ip_address, ForwardInformationBaseIpv6::new(nexthop_macaddress, nexthop_address, netif, fib_type));
+  let table = unsafe { &mut IPV6_FIB };
+  table.insert(ip_address.get_prim(), prefix, NexthopGroup::new(ForwardInformationBaseIpv6::new(nexthop_macaddress, nexthop_address, netif, fib_type), 1));
 }
 
-pub fn find_ipv6_fib(ip_address: &Ipv6Address, prefix: u32) -> Option<&'static ForwardInformationBaseIpv6> {
-  let mut fib_index = prefix as usize;
+// add a next hop to the prefix, next to the existing ones. a next hop already there gets the new weight.
+pub fn add_ipv6_nexthop(ip_address: Ipv6Address, prefix: u32, nexthop_macaddress: MacAddress, nexthop_address: Ipv6Address, netif: Arc<dyn Netif>, fib_type: FIBType, weight: u32) {
+  let table = unsafe { &mut IPV6_FIB };
+  let netif_id = netif.get_id();
+  let fib = ForwardInformationBaseIpv6::new(nexthop_macaddress, nexthop_address, netif, fib_type);
+  match table.get_mut(ip_address.get_prim(), prefix) {
+    Some(group) => group.insert(fib, weight, |n| n.get_nexthop_address() == nexthop_address && n.get_netif().get_id() == netif_id),
+    None => {
+      table.insert(ip_address.get_prim(), prefix, NexthopGroup::new(fib, weight));
+    },
+  }
+}
 
-  // longest match
-  loop {
-    if let Some(fib) = unsafe { IPV6_FIB_INDEX[fib_index].get(&ip_address.masked(prefix)) } {
-      return Some(fib)
-    }
+// remove a next hop from the prefix. the prefix itself goes away with its last next hop.
+pub fn remove_ipv6_nexthop(ip_address: Ipv6Address, prefix: u32, nexthop_address: Ipv6Address) {
+  let table = unsafe { &mut IPV6_FIB };
+  let is_empty = match table.get_mut(ip_address.get_prim(), prefix) {
+    Some(group) => {
+      group.remove(|n| n.get_nexthop_address() == nexthop_address);
+      group.len() == 0
+    },
+    None => false,
+  };
+  if is_empty {
+    table.remove(ip_address.get_prim(), prefix);
+  }
+}
 
-    if fib_index == 0 {
-      break;
-    } 
+// replace all next hops of the prefix at once
+pub fn install_ipv6_fib(ip_address: Ipv6Address, prefix: u32, group: NexthopGroup<ForwardInformationBaseIpv6>) {
+  let table = unsafe { &mut IPV6_FIB };
+  table.insert(ip_address.get_prim(), prefix, group);
+}
 
-    fib_index = fib_index - 1;
-  }
-  None
+pub fn unregister_ipv6_fib(ip_address: Ipv6Address, prefix: u32) {
+  let table = unsafe { &mut IPV6_FIB };
+  table.remove(ip_address.get_prim(), prefix);
 }
 
+// the next hops of exactly this prefix
+pub fn find_ipv6_fib_exact(ip_address: &Ipv6Address, prefix: u32) -> Option<&'static NexthopGroup<ForwardInformationBaseIpv6>> {
+  unsafe { IPV6_FIB.get(ip_address.get_prim(), prefix) }
+}
+
+// longest match. `flow_hash` picks one of the next hops when the matched prefix has several.
+pub fn find_ipv6_fib(ip_address: &Ipv6Address, prefix: u32, flow_hash: u32) -> Option<&'static ForwardInformationBaseIpv6> {
+  let table = unsafe { &IPV6_FIB };
+  table.lookup_within(ip_address.get_prim(), prefix)?.select(flow_hash)
+}
+
+
+// neighbor unreachability detection states (RFC 4861 7.3.2)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NeighborState {
+  Incomplete(usize), // number of solicitations sent
+  Reachable,
+  Stale,
+  Delay,
+  Probe(usize), // number of solicitations sent
+}
 
 #[derive(Clone)]
 pub struct AdjacentInformation {
@@ -212,6 +460,8 @@ pub struct AdjacentInformation {
   netif: Arc<dyn Netif>,
   is_local: bool,
   expire_time: Option<u64>, //permanent entry if expire_time is None
+  state: NeighborState,
+  state_time: u64, // monotonic time of the last state transition
 }
 
 impl AdjacentInformation {
@@ -221,9 +471,17 @@ impl AdjacentInformation {
       netif: netif,
       is_local: is_local,
       expire_time: expire_time,
+      state: NeighborState::Reachable,
+      state_time: 0,
     }
   }
 
+  pub fn get_mac_address(&self) -> MacAddress {
+    self.mac_address
+  }
+  pub fn set_mac_address(&mut self, mac_address: MacAddress) {
+    self.mac_address = mac_address;
+  }
   pub fn get_netif(&self) -> &Arc<dyn Netif> {
     &self.netif
   }
@@ -233,6 +491,19 @@ impl AdjacentInformation {
   pub fn get_expire_time(&self) -> Option<u64> {
     self.expire_time
   }
+  pub fn set_expire_time(&mut self, expire_time: Option<u64>) {
+    self.expire_time = expire_time;
+  }
+  pub fn get_state(&self) -> NeighborState {
+    self.state
+  }
+  pub fn get_state_time(&self) -> u64 {
+    self.state_time
+  }
+  pub fn set_state(&mut self, state: NeighborState, state_time: u64) {
+    self.state = state;
+    self.state_time = state_time;
+  }
 }
 
 pub static IPV4_ADJACENT: Spinlock<BTreeMap<Ipv4Address, AdjacentInformation>> = const_spinlock(BTreeMap::new());
@@ -241,8 +512,8 @@ pub fn register_ipv4_adjacent(ip_address: Ipv4Address, mac_address: MacAddress,
   //record to adj-table
   let mut adj_table = IPV4_ADJACENT.lock();
   if let Some(adj) = adj_table.get(&ip_address) {
-    if let Some(expire_time_of_existing) = adj.get_expire_time() {
-      //todo: check lifetime and register this mac if expired.
+    if let Some(_) = adj.get_expire_time() {
+      // dynamic entry. refresh it with the new information and lifetime.
       adj_table.insert(ip_address, AdjacentInformation::new(mac_address, netif, is_local, expire_time));
     } else {
       // do nothing if the existing entry is permanent
@@ -253,14 +524,28 @@ pub fn register_ipv4_adjacent(ip_address: Ipv4Address, mac_address: MacAddress,
   }
 }
 
+pub fn unregister_ipv4_adjacent(ip_address: Ipv4Address) {
+  IPV4_ADJACENT.lock().remove(&ip_address);
+}
+
+pub fn find_ipv4_local_address(netif_id: usize) -> Option<Ipv4Address> {
+  let adj_table = IPV4_ADJACENT.lock();
+  for (ip_address, adj) in adj_table.iter() {
+    if adj.is_local() && adj.get_netif().get_id() == netif_id {
+      return Some(*ip_address);
+    }
+  }
+  None
+}
+
 pub static IPV6_ADJACENT: Spinlock<BTreeMap<Ipv6Address, AdjacentInformation>> = const_spinlock(BTreeMap::new());
 
 pub fn register_ipv6_adjacent(ip_address: Ipv6Address, mac_address: MacAddress, netif: Arc<dyn Netif>, is_local: bool, expire_time: Option<u64>) {
   //record to adj-table
   let mut adj_table = IPV6_ADJACENT.lock();
   if let Some(adj) = adj_table.get(&ip_address) {
-    if let Some(expire_time_of_existing) = adj.get_expire_time() {
-      //todo: check lifetime and register this mac if expired.
+    if let Some(_) = adj.get_expire_time() {
+      // dynamic entry. refresh it with the new information and lifetime.
       adj_table.insert(ip_address, AdjacentInformation::new(mac_address, netif, is_local, expire_time));
     } else {
       // do nothing if the existing entry is permanent
@@ -271,6 +556,37 @@ pub fn register_ipv6_adjacent(ip_address: Ipv6Address, mac_address: MacAddress,
   }
 }
 
+pub fn unregister_ipv6_adjacent(ip_address: Ipv6Address) {
+  IPV6_ADJACENT.lock().remove(&ip_address);
+}
+
+pub fn find_ipv6_link_local_address(netif_id: usize) -> Option<Ipv6Address> {
+  let adj_table = IPV6_ADJACENT.lock();
+  for (ip_address, adj) in adj_table.iter() {
+    if adj.is_local() && adj.get_netif().get_id() == netif_id && ip_address.is_link_local() {
+      return Some(*ip_address);
+    }
+  }
+  None
+}
+
+// pick an address of the interface to originate packets towards `dest`. prefers an address of the same scope.
+pub fn find_ipv6_source_address(netif_id: usize, dest: &Ipv6Address) -> Option<Ipv6Address> {
+  let adj_table = IPV6_ADJACENT.lock();
+  let mut fallback = None;
+  for (ip_address, adj) in adj_table.iter() {
+    if !adj.is_local() || adj.get_netif().get_id() != netif_id || ip_address.is_multicast() {
+      continue;
+    }
+    if ip_address.is_link_local() == dest.is_link_local() {
+      return Some(*ip_address);
+    }
+    if fallback.is_none() {
+      fallback = Some(*ip_address);
+    }
+  }
+  fallback
+}
 
 pub static MAC_ADDR_TABLE: Spinlock<BTreeMap<MacAddress, AdjacentInformation>> = const_spinlock(BTreeMap::new());
 
@@ -278,8 +594,8 @@ pub fn register_macaddress(mac_address: MacAddress, netif: Arc<dyn Netif>, is_lo
   //record to adj-table
   let mut mactable = MAC_ADDR_TABLE.lock();
   if let Some(adj) = mactable.get(&mac_address) {
-    if let Some(expire_time_of_existing) = adj.get_expire_time() {
-      //todo: check lifetime and register this mac if expired.
+    if let Some(_) = adj.get_expire_time() {
+      // dynamic entry. refresh it with the new information and lifetime.
       mactable.insert(mac_address, AdjacentInformation::new(mac_address, netif, is_local, expire_time));
     } else {
       // do nothing if the existing entry is permanent
@@ -288,4 +604,120 @@ pub fn register_macaddress(mac_address: MacAddress, netif: Arc<dyn Netif>, is_lo
     // register this mac
     mactable.insert(mac_address, AdjacentInformation::new(mac_address, netif, is_local, expire_time));
   }
-}
\ No newline at end of file
+}
+
+pub fn unregister_macaddress(mac_address: MacAddress) {
+  MAC_ADDR_TABLE.lock().remove(&mac_address);
+}
+
+/////////
+
+// lifetime of dynamic entries in nanoseconds. zero means that entries never expire.
+static IPV4_ADJACENT_TIMEOUT: AtomicU64 = AtomicU64::new(300_000_000_000);
+static IPV6_ADJACENT_TIMEOUT: AtomicU64 = AtomicU64::new(300_000_000_000);
+static MAC_ADDR_TIMEOUT: AtomicU64 = AtomicU64::new(300_000_000_000);
+
+const AGING_INTERVAL: Duration = Duration::from_secs(1);
+
+pub fn set_ipv4_adjacent_timeout(timeout: Duration) {
+  IPV4_ADJACENT_TIMEOUT.store(timeout.as_nanos() as u64, Ordering::Relaxed);
+}
+
+pub fn set_ipv6_adjacent_timeout(timeout: Duration) {
+  IPV6_ADJACENT_TIMEOUT.store(timeout.as_nanos() as u64, Ordering::Relaxed);
+}
+
+pub fn set_macaddress_timeout(timeout: Duration) {
+  MAC_ADDR_TIMEOUT.store(timeout.as_nanos() as u64, Ordering::Relaxed);
+}
+
+fn parse_timeout(s: &str) -> Result<Duration, &'static str> {
+  let seconds = cmdline::parse_integer(s)?;
+  match seconds.checked_mul(1_000_000_000) {
+    Some(_) => Ok(Duration::from_secs(seconds)),
+    None => Err("timeout is too long"),
+  }
+}
+
+// lifetimes of learned entries in seconds, 0 for never.
+// urchin.arp_timeout=<seconds> urchin.nd_timeout=<seconds> urchin.mac_timeout=<seconds>
+pub fn configure_timeouts(cmdline: &Cmdline) {
+  if let Some(timeout) = cmdline.get("urchin.arp_timeout").and_then(|option| option.parse_value(parse_timeout)) {
+    set_ipv4_adjacent_timeout(timeout);
+  }
+  if let Some(timeout) = cmdline.get("urchin.nd_timeout").and_then(|option| option.parse_value(parse_timeout)) {
+    set_ipv6_adjacent_timeout(timeout);
+  }
+  if let Some(timeout) = cmdline.get("urchin.mac_timeout").and_then(|option| option.parse_value(parse_timeout)) {
+    set_macaddress_timeout(timeout);
+  }
+}
+
+fn calc_expire_time(timeout: &AtomicU64) -> Option<u64> {
+  match timeout.load(Ordering::Relaxed) {
+    0 => None,
+    t => Some(get_monotonic_time() + t),
+  }
+}
+
+pub fn ipv4_adjacent_expire_time() -> Option<u64> {
+  calc_expire_time(&IPV4_ADJACENT_TIMEOUT)
+}
+
+pub fn ipv6_adjacent_expire_time() -> Option<u64> {
+  calc_expire_time(&IPV6_ADJACENT_TIMEOUT)
+}
+
+pub fn macaddress_expire_time() -> Option<u64> {
+  calc_expire_time(&MAC_ADDR_TIMEOUT)
+}
+
+fn is_expired(adj: &AdjacentInformation, now: u64) -> bool {
+  match adj.get_expire_time() {
+    Some(expire_time) => now > expire_time,
+    None => false,
+  }
+}
+
+// evict expired dynamic entries from the adjacency tables, and withdraw the host routes of expired neighbors.
+pub async fn expire_adjacent_entries() {
+  loop {
+    TimerFuture::new(AGING_INTERVAL).await;
+
+    let now = get_monotonic_time();
+
+    let expired_ipv4: Vec<Ipv4Address> = {
+      let mut adj_table = IPV4_ADJACENT.lock();
+      let expired: Vec<Ipv4Address> = adj_table.iter().filter(|(_, adj)| is_expired(adj, now)).map(|(ip, _)| *ip).collect();
+      for ip_address in expired.iter() {
+        adj_table.remove(ip_address);
+      }
+      expired
+    };
+    for ip_address in expired_ipv4.iter() {
+      rib::withdraw_ipv4_route(*ip_address, 0xffffffff, RouteSource::Neighbor);
+      rib::notify_ipv4_neighbor(*ip_address);
+    }
+
+    let expired_ipv6: Vec<Ipv6Address> = {
+      let mut adj_table = IPV6_ADJACENT.lock();
+      let expired: Vec<Ipv6Address> = adj_table.iter().filter(|(_, adj)| is_expired(adj, now)).map(|(ip, _)| *ip).collect();
+      for ip_address in expired.iter() {
+        adj_table.remove(ip_address);
+      }
+      expired
+    };
+    for ip_address in expired_ipv6.iter() {
+      rib::withdraw_ipv6_route(*ip_address, 128, RouteSource::Neighbor);
+      rib::notify_ipv6_neighbor(*ip_address);
+    }
+
+    {
+      let mut mactable = MAC_ADDR_TABLE.lock();
+      let expired: Vec<MacAddress> = mactable.iter().filter(|(_, adj)| is_expired(adj, now)).map(|(mac, _)| *mac).collect();
+      for mac_address in expired.iter() {
+        mactable.remove(mac_address);
+      }
+    }
+  }
+}
@@ -0,0 +1,148 @@
+// lookup rate and memory use of the route tables with a full-size table loaded.
+// runs at boot when the kernel cmdline has `urchin.fib_bench`. the live fib is left untouched.
+
+use alloc::vec::Vec;
+
+use crate::arch::x86_64::kvmclock::get_monotonic_time;
+use crate::net::fib::{NexthopGroup, RouteTable};
+use crate::ALLOCATOR;
+
+const LOOKUP_COUNT: usize = 10_000_000;
+const NEXTHOP_COUNT: u32 = 16;
+
+// xorshift64*. good enough to scatter prefixes and addresses.
+struct Random {
+  state: u64,
+}
+
+impl Random {
+  fn new(seed: u64) -> Random {
+    Random { state: if seed == 0 { 0x9e3779b97f4a7c15 } else { seed } }
+  }
+
+  fn next(&mut self) -> u64 {
+    self.state ^= self.state >> 12;
+    self.state ^= self.state << 25;
+    self.state ^= self.state >> 27;
+    self.state.wrapping_mul(0x2545f4914f6cdd1d)
+  }
+}
+
+// roughly the shape of the internet table: mostly /24, then /22 and /23, a few shorter and longer ones
+fn ipv4_prefix_length(rand: &mut Random) -> u32 {
+  match rand.next() % 100 {
+    0..=59 => 24,
+    60..=69 => 23,
+    70..=79 => 22,
+    80..=84 => 21,
+    85..=89 => 20,
+    90..=94 => 16 + (rand.next() % 4) as u32,
+    95..=97 => 8 + (rand.next() % 8) as u32,
+    _ => 25 + (rand.next() % 8) as u32,
+  }
+}
+
+// allocations are /32 and shorter, sites are /48, and they cluster under a limited number of /32s
+fn ipv6_prefix(rand: &mut Random, allocations: &[u128]) -> (u128, u32) {
+  let allocation = allocations[(rand.next() % allocations.len() as u64) as usize];
+  let length = match rand.next() % 100 {
+    0..=9 => 32,
+    10..=19 => 29 + (rand.next() % 3) as u32,
+    20..=34 => 33 + (rand.next() % 15) as u32,
+    35..=89 => 48,
+    _ => 49 + (rand.next() % 16) as u32,
+  };
+  // vary the bits below the allocation inside a /40 chosen among a few
+  let site = ((rand.next() % 16) as u128) << 88 | ((rand.next() & 0xff_ffff) as u128) << 64;
+  (allocation | site, length)
+}
+
+fn report(name: &str, routes: usize, load_time: u64, lookup_time: u64, hits: usize, table_memory: usize, heap_memory: usize) {
+  println!("{}: {} routes loaded in {} ms", name, routes, load_time / 1_000_000);
+  println!(
+    "{}: {} lookups in {} ms ({} Mlookups/s, {} hits)",
+    name, LOOKUP_COUNT, lookup_time / 1_000_000,
+    if lookup_time == 0 { 0 } else { LOOKUP_COUNT as u64 * 1_000 / lookup_time }, hits,
+  );
+  println!("{}: table {} KiB, heap grew by {} KiB", name, table_memory / 1024, heap_memory / 1024);
+}
+
+pub fn run_ipv4(route_count: usize) {
+  let mut rand = Random::new(get_monotonic_time());
+  let heap_before = ALLOCATOR.lock().used();
+  {
+    let mut table: RouteTable<u32> = RouteTable::new(32, 24);
+
+    let start = get_monotonic_time();
+    while table.len() < route_count {
+      let length = ipv4_prefix_length(&mut rand);
+      let key = rand.next() as u32 as u128;
+      let nexthop = (rand.next() % NEXTHOP_COUNT as u64) as u32;
+      if !table.insert(key, length, NexthopGroup::new(nexthop, 1)) {
+        break;
+      }
+    }
+    let load_time = get_monotonic_time() - start;
+
+    let addresses: Vec<u128> = (0..1024).map(|_| rand.next() as u32 as u128).collect();
+    let mut hits = 0;
+    let start = get_monotonic_time();
+    for i in 0..LOOKUP_COUNT {
+      if let Some(group) = table.lookup(addresses[i & 1023] ^ (i as u128 * 0x9e37_79b9 & 0xffff_ffff)) {
+        if group.select(i as u32).is_some() {
+          hits += 1;
+        }
+      }
+    }
+    let lookup_time = get_monotonic_time() - start;
+
+    let heap_after = ALLOCATOR.lock().used();
+    report("IPv4", table.len(), load_time, lookup_time, hits, table.get_memory_usage(), heap_after.saturating_sub(heap_before));
+  }
+}
+
+pub fn run_ipv6(route_count: usize) {
+  let mut rand = Random::new(get_monotonic_time());
+  let heap_before = ALLOCATOR.lock().used();
+  {
+    let mut table: RouteTable<u32> = RouteTable::new(128, 16);
+
+    // 2000::/4 split into allocations
+    let allocations: Vec<u128> = (0..2048).map(|_| (0x2000_0000u128 | (rand.next() as u128 & 0x0fff_ffff)) << 96).collect();
+
+    let start = get_monotonic_time();
+    while table.len() < route_count {
+      let (key, length) = ipv6_prefix(&mut rand, &allocations);
+      let nexthop = (rand.next() % NEXTHOP_COUNT as u64) as u32;
+      if !table.insert(key, length, NexthopGroup::new(nexthop, 1)) {
+        break;
+      }
+    }
+    let load_time = get_monotonic_time() - start;
+
+    // addresses inside the allocations, so that lookups go down the trie
+    let addresses: Vec<u128> = (0..1024).map(|_| {
+      let (key, _) = ipv6_prefix(&mut rand, &allocations);
+      key | (rand.next() as u128)
+    }).collect();
+    let mut hits = 0;
+    let start = get_monotonic_time();
+    for i in 0..LOOKUP_COUNT {
+      if let Some(group) = table.lookup(addresses[i & 1023] ^ (i as u128) << 64) {
+        if group.select(i as u32).is_some() {
+          hits += 1;
+        }
+      }
+    }
+    let lookup_time = get_monotonic_time() - start;
+
+    let heap_after = ALLOCATOR.lock().used();
+    report("IPv6", table.len(), load_time, lookup_time, hits, table.get_memory_usage(), heap_after.saturating_sub(heap_before));
+  }
+}
+
+pub fn run(ipv4_route_count: usize, ipv6_route_count: usize) {
+  println!("FIB benchmark: {} IPv4 routes, {} IPv6 routes", ipv4_route_count, ipv6_route_count);
+  run_ipv4(ipv4_route_count);
+  run_ipv6(ipv6_route_count);
+}
@@ -0,0 +1,216 @@
+// longest prefix match by a multibit trie with controlled prefix expansion.
+// the root consumes `root_bits` of the key and every node below consumes 8 bits,
+// so 32 bit keys with 24 root bits make DIR-24-8, and 128 bit keys with 16 root bits take at most 15 memory accesses.
+// every slot holds the value of the longest prefix covering it (leaf pushing), so a lookup never backtracks.
+
+use alloc::vec::Vec;
+
+use core::mem::size_of;
+
+// layout of a slot: valid(1) child(1) depth(8) index(22)
+const SLOT_VALID: u32 = 1 << 31;
+const SLOT_CHILD: u32 = 1 << 30;
+const SLOT_DEPTH_SHIFT: u32 = 22;
+const SLOT_INDEX_MASK: u32 = (1 << SLOT_DEPTH_SHIFT) - 1;
+
+const NODE_BITS: u32 = 8;
+const NODE_SIZE: usize = 1 << NODE_BITS;
+
+// the largest value (and the largest number of nodes) a trie can hold
+pub const MAX_VALUE: u32 = SLOT_INDEX_MASK;
+
+pub struct MultibitTrie {
+  key_bits: u32,
+  root_bits: u32,
+  root: Vec<u32>,
+  nodes: Vec<u32>, // NODE_SIZE slots each
+  free_nodes: Vec<u32>,
+}
+
+fn slot_depth(slot: u32) -> u32 {
+  (slot >> SLOT_DEPTH_SHIFT) & 0xff
+}
+
+impl MultibitTrie {
+  pub const fn new(key_bits: u32, root_bits: u32) -> MultibitTrie {
+    MultibitTrie {
+      key_bits: key_bits,
+      root_bits: root_bits,
+      root: Vec::new(),
+      nodes: Vec::new(),
+      free_nodes: Vec::new(),
+    }
+  }
+
+  fn root_size(&self) -> usize {
+    1 << self.root_bits
+  }
+
+  // slots are addressed through the root first, then the nodes
+  fn node_base(&self, slot: u32) -> usize {
+    self.root_size() + (slot & SLOT_INDEX_MASK) as usize * NODE_SIZE
+  }
+
+  fn get_slot(&self, index: usize) -> u32 {
+    if index < self.root.len() {
+      self.root[index]
+    } else {
+      self.nodes[index - self.root.len()]
+    }
+  }
+
+  fn set_slot(&mut self, index: usize, slot: u32) {
+    if index < self.root.len() {
+      self.root[index] = slot;
+    } else {
+      let root_len = self.root.len();
+      self.nodes[index - root_len] = slot;
+    }
+  }
+
+  // the index into the node (or the root) that consumes key bits up to `consumed`
+  fn slot_index(&self, key: u128, consumed: u32, stride: u32) -> usize {
+    ((key >> (self.key_bits - consumed)) & ((1u128 << stride) - 1)) as usize
+  }
+
+  pub fn get_memory_usage(&self) -> usize {
+    (self.root.capacity() + self.nodes.capacity() + self.free_nodes.capacity()) * size_of::<u32>()
+  }
+
+  pub fn lookup(&self, key: u128) -> Option<u32> {
+    if self.root.len() == 0 {
+      return None;
+    }
+    let mut consumed = self.root_bits;
+    let mut slot = self.root[self.slot_index(key, consumed, self.root_bits)];
+    while slot & SLOT_CHILD != 0 {
+      consumed += NODE_BITS;
+      slot = self.nodes[(slot & SLOT_INDEX_MASK) as usize * NODE_SIZE + self.slot_index(key, consumed, NODE_BITS)];
+    }
+    if slot & SLOT_VALID != 0 {
+      Some(slot & SLOT_INDEX_MASK)
+    } else {
+      None
+    }
+  }
+
+  // a new node inherits the slot it replaces in all of its slots
+  fn alloc_node(&mut self, fill: u32) -> u32 {
+    match self.free_nodes.pop() {
+      Some(node) => {
+        let base = node as usize * NODE_SIZE;
+        for slot in self.nodes[base..(base+NODE_SIZE)].iter_mut() {
+          *slot = fill;
+        }
+        node
+      },
+      None => {
+        let node = (self.nodes.len() / NODE_SIZE) as u32;
+        self.nodes.resize(self.nodes.len() + NODE_SIZE, fill);
+        node
+      },
+    }
+  }
+
+  // the slots `base+first..base+first+count` covered by the prefix, creating nodes on the way
+  fn walk_to_range(&mut self, key: u128, length: u32, create: bool, path: &mut Vec<usize>) -> Option<(usize, usize, usize)> {
+    let mut base = 0;
+    let mut consumed = self.root_bits;
+    let mut stride = self.root_bits;
+    loop {
+      if length <= consumed {
+        let first = self.slot_index(key, consumed, stride) & !((1usize << (consumed - length)) - 1);
+        return Some((base, first, 1 << (consumed - length)));
+      }
+      let index = base + self.slot_index(key, consumed, stride);
+      let slot = self.get_slot(index);
+      if slot & SLOT_CHILD == 0 {
+        if !create {
+          return None;
+        }
+        let node = self.alloc_node(slot);
+        self.set_slot(index, SLOT_CHILD | node);
+      }
+      path.push(index);
+      base = self.node_base(self.get_slot(index));
+      consumed += NODE_BITS;
+      stride = NODE_BITS;
+    }
+  }
+
+  // put `entry` into a slot unless a longer prefix already owns it
+  fn fill_slot(&mut self, index: usize, length: u32, entry: u32) {
+    let slot = self.get_slot(index);
+    if slot & SLOT_CHILD != 0 {
+      let base = self.node_base(slot);
+      for i in 0..NODE_SIZE {
+        self.fill_slot(base + i, length, entry);
+      }
+    } else if slot & SLOT_VALID == 0 || slot_depth(slot) <= length {
+      self.set_slot(index, entry);
+    }
+  }
+
+  // give the slots owned by the prefix to the covering one, and fold the nodes left uniform
+  fn clear_slot(&mut self, index: usize, length: u32, replacement: u32) {
+    let slot = self.get_slot(index);
+    if slot & SLOT_CHILD != 0 {
+      let base = self.node_base(slot);
+      for i in 0..NODE_SIZE {
+        self.clear_slot(base + i, length, replacement);
+      }
+      self.fold(index);
+    } else if slot & SLOT_VALID != 0 && slot_depth(slot) == length {
+      self.set_slot(index, replacement);
+    }
+  }
+
+  // replace a child by its only value if all of its slots agree
+  fn fold(&mut self, index: usize) {
+    let slot = self.get_slot(index);
+    if slot & SLOT_CHILD == 0 {
+      return;
+    }
+    let base = (slot & SLOT_INDEX_MASK) as usize * NODE_SIZE;
+    let first = self.nodes[base];
+    if first & SLOT_CHILD != 0 || self.nodes[base..(base+NODE_SIZE)].iter().any(|s| *s != first) {
+      return;
+    }
+    self.set_slot(index, first);
+    self.free_nodes.push(slot & SLOT_INDEX_MASK);
+  }
+
+  // `key` must already be masked to `length` bits
+  pub fn insert(&mut self, key: u128, length: u32, value: u32) {
+    if self.root.len() == 0 {
+      self.root.resize(self.root_size(), 0);
+    }
+    let entry = SLOT_VALID | (length << SLOT_DEPTH_SHIFT) | (value & SLOT_INDEX_MASK);
+    let mut path = Vec::new();
+    if let Some((base, first, count)) = self.walk_to_range(key, length, true, &mut path) {
+      for i in first..(first+count) {
+        self.fill_slot(base + i, length, entry);
+      }
+    }
+  }
+
+  // remove the prefix. `covering` is the value and length of the longest prefix left that covers it.
+  pub fn remove(&mut self, key: u128, length: u32, covering: Option<(u32, u32)>) {
+    if self.root.len() == 0 {
+      return;
+    }
+    let replacement = match covering {
+      Some((value, depth)) => SLOT_VALID | (depth << SLOT_DEPTH_SHIFT) | (value & SLOT_INDEX_MASK),
+      None => 0,
+    };
+    let mut path = Vec::new();
+    if let Some((base, first, count)) = self.walk_to_range(key, length, false, &mut path) {
+      for i in first..(first+count) {
+        self.clear_slot(base + i, length, replacement);
+      }
+      for index in path.iter().rev() {
+        self.fold(*index);
+      }
+    }
+  }
+}
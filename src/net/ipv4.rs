@@ -1,13 +1,23 @@
 use core::convert::TryInto;
 use core::cmp::Ordering;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use core::time::Duration;
 
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 
 use crate::devices::buffer::Buffer;
+use crate::devices::netif::Netif;
 use crate::net::{DataFromNetif, ProcessingNode};
+use crate::net::reassembly::{Reassembler, ReassemblyResult, build_datagram_frame};
 use crate::net::ethernet::{MacAddress, generate_ether_header};
-use crate::net::fib::{FIBType, MAC_ADDR_TABLE, AdjacentInformation, find_ipv4_fib, register_ipv4_fib, register_ipv4_adjacent, IPV4_ADJACENT};
+use crate::net::arp;
+use crate::net::ping;
+use crate::net::fib::{FIBType, MAC_ADDR_TABLE, AdjacentInformation, ForwardInformationBaseIpv4, find_ipv4_fib, find_ipv4_local_address, calc_flow_hash, register_ipv4_fib, register_ipv4_adjacent, IPV4_ADJACENT};
+use crate::spinlock::{Spinlock, const_spinlock};
+use crate::asynchronous::timer::TimerFuture;
+use crate::arch::x86_64::kvmclock::get_monotonic_time;
 use crate::PROC_NODES;
 
 #[derive(Debug, Copy, Clone)]
@@ -22,7 +32,7 @@ impl Ipv4Address {
   }
 
   pub fn masked(&self, prefix_length: u32) -> Ipv4Address {
-    let mask = 0xffffffffu32 << (32 - prefix_length);
+    let mask = 0xffffffffu32.checked_shl(32 - prefix_length).unwrap_or(0);
     Ipv4Address { addr_prim: self.addr_prim & mask }
   }
         
@@ -30,9 +40,49 @@ impl Ipv4Address {
     [(self.addr_prim >> 24) as u8, (self.addr_prim >> 16) as u8, (self.addr_prim >> 8) as u8, self.addr_prim as u8]
   }
 
+  pub const fn from_prim(prim: u32) -> Ipv4Address {
+    Ipv4Address { addr_prim: prim }
+  }
+
   pub fn get_prim(&self) -> u32 {
     self.addr_prim
   }
+
+  pub fn is_unspecified(&self) -> bool {
+    self.addr_prim == 0
+  }
+
+  pub fn is_loopback(&self) -> bool {
+    // 127.0.0.0/8
+    (self.addr_prim >> 24) == 127
+  }
+
+  pub fn is_multicast(&self) -> bool {
+    // 224.0.0.0/4
+    (self.addr_prim >> 28) == 0xe
+  }
+
+  pub fn is_broadcast(&self) -> bool {
+    self.addr_prim == 0xffffffff
+  }
+
+  // dotted decimal, e.g. "192.168.0.1"
+  pub fn parse(s: &str) -> Option<Ipv4Address> {
+    let mut prim = 0u32;
+    let mut count = 0;
+    for octet in s.split('.') {
+      if octet.len() == 0 || octet.len() > 3 || !octet.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+      }
+      let value = octet.parse::<u32>().ok()?;
+      if value > 255 || count == 4 {
+        return None;
+      }
+      prim = prim << 8 | value;
+      count += 1;
+    }
+    if count == 4 { Some(Ipv4Address::from_prim(prim)) } else { None }
+  }
 }
 
 impl Ord for Ipv4Address {
@@ -55,13 +105,296 @@ impl PartialEq for Ipv4Address {
   }
 }
 
+impl fmt::Display for Ipv4Address {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let arr = self.get_array();
+    write!(f, "{}.{}.{}.{}", arr[0], arr[1], arr[2], arr[3])
+  }
+}
+
 ////
 
-pub struct Ipv4In;
+pub struct Ipv4In {
+  forwarded: AtomicU64,
+  dropped_ttl_expired: AtomicU64,
+  dropped_too_big: AtomicU64,
+  dropped_no_route: AtomicU64,
+  dropped_xmit_failed: AtomicU64,
+  dropped_reassembly_failed: AtomicU64,
+  fragmented: AtomicU64,
+  reassembled: AtomicU64,
+}
 
 impl Ipv4In {
   pub const fn new() -> Ipv4In {
-    Ipv4In {}
+    Ipv4In {
+      forwarded: AtomicU64::new(0),
+      dropped_ttl_expired: AtomicU64::new(0),
+      dropped_too_big: AtomicU64::new(0),
+      dropped_no_route: AtomicU64::new(0),
+      dropped_xmit_failed: AtomicU64::new(0),
+      dropped_reassembly_failed: AtomicU64::new(0),
+      fragmented: AtomicU64::new(0),
+      reassembled: AtomicU64::new(0),
+    }
+  }
+
+  pub fn get_forwarded_count(&self) -> u64 {
+    self.forwarded.load(AtomicOrdering::Relaxed)
+  }
+
+  pub fn get_dropped_ttl_expired_count(&self) -> u64 {
+    self.dropped_ttl_expired.load(AtomicOrdering::Relaxed)
+  }
+
+  pub fn get_dropped_too_big_count(&self) -> u64 {
+    self.dropped_too_big.load(AtomicOrdering::Relaxed)
+  }
+
+  pub fn get_dropped_no_route_count(&self) -> u64 {
+    self.dropped_no_route.load(AtomicOrdering::Relaxed)
+  }
+
+  pub fn get_dropped_xmit_failed_count(&self) -> u64 {
+    self.dropped_xmit_failed.load(AtomicOrdering::Relaxed)
+  }
+
+  pub fn get_dropped_reassembly_failed_count(&self) -> u64 {
+    self.dropped_reassembly_failed.load(AtomicOrdering::Relaxed)
+  }
+
+  pub fn get_fragmented_count(&self) -> u64 {
+    self.fragmented.load(AtomicOrdering::Relaxed)
+  }
+
+  pub fn get_reassembled_count(&self) -> u64 {
+    self.reassembled.load(AtomicOrdering::Relaxed)
+  }
+
+  fn forward(&self, frame: &DataFromNetif, fib: &ForwardInformationBaseIpv4) {
+    let slice = frame.get_buffer().slice();
+    let ipv4_hdr = unsafe { &*((&slice[14] as *const _) as *const Ipv4Packet) };
+    let length = (ipv4_hdr.length[0] as usize) << 8 | ipv4_hdr.length[1] as usize;
+    let header_length = (ipv4_hdr.version_and_ihl & 0x0f) as usize * 4;
+
+    if header_length < 20 || length < header_length || 14 + length > frame.get_buffer().get_length() {
+      // broken length field. drop it.
+      return;
+    }
+
+    if ipv4_hdr.ttl <= 1 {
+      // ttl expired in transit.
+      self.dropped_ttl_expired.fetch_add(1, AtomicOrdering::Relaxed);
+      send_icmpv4_error(frame, ICMPV4_TIME_EXCEEDED, ICMPV4_CODE_TTL_EXCEEDED, [0; 4]);
+      return;
+    }
+
+    let netif = Arc::clone(fib.get_netif());
+
+    let mtu = netif.get_mtu();
+    if length > mtu {
+      if (slice[14+6] & 0x40) != 0 {
+        // too big for the egress interface and fragmentation is prohibited.
+        self.dropped_too_big.fetch_add(1, AtomicOrdering::Relaxed);
+        send_icmpv4_error(frame, ICMPV4_DEST_UNREACHABLE, ICMPV4_CODE_FRAGMENTATION_NEEDED, [0, 0, (mtu >> 8) as u8, mtu as u8]);
+      } else {
+        self.fragment(frame, fib, &netif, mtu);
+      }
+      return;
+    }
+
+    let fwdbuff = netif.pre_xmit(14+length);
+    let fwdslice = fwdbuff.slice_mut();
+
+    fwdslice[0..(14+length)].copy_from_slice(&slice[0..(14+length)]);
+    generate_ether_header(&mut fwdslice[0..], *netif.get_macaddress(), fib.get_nexthop_macaddress(), [0x08, 0x00]);
+
+    // decrement ttl and update checksum incrementally (RFC 1624)
+    let old_word = (fwdslice[14+8] as u32) << 8 | fwdslice[14+9] as u32;
+    fwdslice[14+8] = fwdslice[14+8] - 1;
+    let new_word = (fwdslice[14+8] as u32) << 8 | fwdslice[14+9] as u32;
+    let old_csum = (fwdslice[14+10] as u32) << 8 | fwdslice[14+11] as u32;
+    let mut csum = (!old_csum & 0xffff) + (!old_word & 0xffff) + new_word;
+    csum = (csum & 0x0000ffff) + (csum >> 16);
+    csum = (csum & 0x0000ffff) + (csum >> 16);
+    csum = !csum;
+    fwdslice[14+10] = (csum >> 8) as u8;
+    fwdslice[14+11] = csum as u8;
+
+    match netif.xmit(fwdbuff) {
+      Ok(_) => {
+        self.forwarded.fetch_add(1, AtomicOrdering::Relaxed);
+      },
+      Err(_) => {
+        self.dropped_xmit_failed.fetch_add(1, AtomicOrdering::Relaxed);
+      },
+    }
+  }
+
+  // split the packet to fit the egress mtu (RFC 791 3.2)
+  fn fragment(&self, frame: &DataFromNetif, fib: &ForwardInformationBaseIpv4, netif: &Arc<dyn Netif>, mtu: usize) {
+    let slice = frame.get_buffer().slice();
+    let ipv4_hdr = unsafe { &*((&slice[14] as *const _) as *const Ipv4Packet) };
+    let length = (ipv4_hdr.length[0] as usize) << 8 | ipv4_hdr.length[1] as usize;
+    let header_length = (ipv4_hdr.version_and_ihl & 0x0f) as usize * 4;
+
+    let flags_and_offset = (slice[14+6] as usize) << 8 | slice[14+7] as usize;
+    let orig_offset = (flags_and_offset & 0x1fff) * 8;
+    let orig_more = (flags_and_offset & 0x2000) != 0;
+
+    // only options with the copied flag go into the later fragments
+    let mut copied_options = Vec::new();
+    let mut i = 20;
+    while i < header_length {
+      match slice[14+i] {
+        0 => break, // end of option list
+        1 => i += 1, // no operation
+        opt_type => {
+          if i + 1 >= header_length {
+            break;
+          }
+          let opt_length = slice[14+i+1] as usize;
+          if opt_length < 2 || i + opt_length > header_length {
+            break;
+          }
+          if opt_type & 0x80 != 0 {
+            copied_options.extend_from_slice(&slice[(14+i)..(14+i+opt_length)]);
+          }
+          i += opt_length;
+        },
+      }
+    }
+    while copied_options.len() % 4 != 0 {
+      copied_options.push(0);
+    }
+
+    let payload = &slice[(14+header_length)..(14+length)];
+    let mut pos = 0;
+    while pos < payload.len() {
+      let frag_header_length = if pos == 0 { header_length } else { 20 + copied_options.len() };
+      if mtu < frag_header_length + 8 {
+        // an mtu which can't carry any payload.
+        self.dropped_too_big.fetch_add(1, AtomicOrdering::Relaxed);
+        return;
+      }
+      let max_data_length = (mtu - frag_header_length) & !7;
+      let data_length = if payload.len() - pos > max_data_length { max_data_length } else { payload.len() - pos };
+      let is_last = pos + data_length == payload.len();
+      let frag_length = frag_header_length + data_length;
+
+      let fragbuff = netif.pre_xmit(14+frag_length);
+      let fragslice = fragbuff.slice_mut();
+
+      generate_ether_header(&mut fragslice[0..], *netif.get_macaddress(), fib.get_nexthop_macaddress(), [0x08, 0x00]);
+      if pos == 0 {
+        fragslice[14..(14+header_length)].copy_from_slice(&slice[14..(14+header_length)]);
+      } else {
+        fragslice[14..(14+20)].copy_from_slice(&slice[14..(14+20)]);
+        fragslice[(14+20)..(14+frag_header_length)].copy_from_slice(&copied_options);
+        fragslice[14] = 0x40 | (frag_header_length / 4) as u8;
+      }
+      fragslice[14+2] = (frag_length >> 8) as u8;
+      fragslice[14+3] = frag_length as u8;
+      let frag_offset = (orig_offset + pos) / 8;
+      let more_flag = if is_last && !orig_more { 0x00 } else { 0x20 };
+      fragslice[14+6] = more_flag | (frag_offset >> 8) as u8;
+      fragslice[14+7] = frag_offset as u8;
+      fragslice[14+8] = fragslice[14+8] - 1;
+      fragslice[14+10] = 0;
+      fragslice[14+11] = 0;
+      let csum = calc_checksum(&fragslice[14..(14+frag_header_length)]);
+      fragslice[14+10] = (csum >> 8) as u8;
+      fragslice[14+11] = csum as u8;
+      fragslice[(14+frag_header_length)..(14+frag_length)].copy_from_slice(&payload[pos..(pos+data_length)]);
+
+      if let Err(_) = netif.xmit(fragbuff) {
+        self.dropped_xmit_failed.fetch_add(1, AtomicOrdering::Relaxed);
+        return;
+      }
+      pos += data_length;
+    }
+
+    self.forwarded.fetch_add(1, AtomicOrdering::Relaxed);
+    self.fragmented.fetch_add(1, AtomicOrdering::Relaxed);
+  }
+
+  // returns the whole datagram once its last missing fragment arrives
+  fn reassemble(&self, frame: &DataFromNetif) -> Option<DataFromNetif> {
+    let slice = frame.get_buffer().slice();
+    let ipv4_hdr = unsafe { &*((&slice[14] as *const _) as *const Ipv4Packet) };
+    let length = (ipv4_hdr.length[0] as usize) << 8 | ipv4_hdr.length[1] as usize;
+    let header_length = (ipv4_hdr.version_and_ihl & 0x0f) as usize * 4;
+
+    if header_length < 20 || length < header_length || 14 + length > frame.get_buffer().get_length() {
+      return None;
+    }
+
+    let flags_and_offset = (slice[14+6] as usize) << 8 | slice[14+7] as usize;
+    let offset = (flags_and_offset & 0x1fff) * 8;
+    let more = (flags_and_offset & 0x2000) != 0;
+    let data = &slice[(14+header_length)..(14+length)];
+    if more && data.len() % 8 != 0 {
+      // every fragment but the last must be a multiple of 8 bytes.
+      return None;
+    }
+
+    let key = (
+      Ipv4Address::from_array(ipv4_hdr.src_ip).get_prim(),
+      Ipv4Address::from_array(ipv4_hdr.dest_ip).get_prim(),
+      (slice[14+4] as u16) << 8 | slice[14+5] as u16,
+      ipv4_hdr.proto,
+    );
+    let header = if offset == 0 { Some(&slice[0..(14+header_length)]) } else { None };
+
+    let result = IPV4_REASSEMBLY.lock().insert(key, frame.get_netif(), header, offset, data, more, get_monotonic_time());
+    match result {
+      ReassemblyResult::Complete(netif, mut header, payload) => {
+        let total_length = header.len() - 14 + payload.len();
+        header[14+2] = (total_length >> 8) as u8;
+        header[14+3] = total_length as u8;
+        header[14+6] = header[14+6] & 0x40;
+        header[14+7] = 0;
+        header[14+10] = 0;
+        header[14+11] = 0;
+        let csum = calc_checksum(&header[14..]);
+        header[14+10] = (csum >> 8) as u8;
+        header[14+11] = csum as u8;
+
+        self.reassembled.fetch_add(1, AtomicOrdering::Relaxed);
+        build_datagram_frame(netif, &header, &payload)
+      },
+      ReassemblyResult::Incomplete => None,
+      ReassemblyResult::Dropped => {
+        self.dropped_reassembly_failed.fetch_add(1, AtomicOrdering::Relaxed);
+        None
+      },
+    }
+  }
+}
+
+const IPV4_REASSEMBLY_TIMEOUT: u64 = 30_000_000_000;
+const IPV4_REASSEMBLY_MAX_MEMORY: usize = 4 * 1024 * 1024;
+const IPV4_REASSEMBLY_MAX_DATAGRAMS: usize = 256;
+
+// keyed by source, destination, identification and protocol
+static IPV4_REASSEMBLY: Spinlock<Reassembler<(u32, u32, u16, u8)>> = const_spinlock(Reassembler::new(
+  IPV4_REASSEMBLY_MAX_MEMORY, IPV4_REASSEMBLY_MAX_DATAGRAMS, IPV4_REASSEMBLY_TIMEOUT
+));
+
+// discard incomplete datagrams and notify their sources
+pub async fn expire_reassembly() {
+  loop {
+    TimerFuture::new(Duration::from_secs(1)).await;
+
+    let expired = IPV4_REASSEMBLY.lock().expire(get_monotonic_time());
+    for datagram in expired.iter() {
+      // only the first fragment carries enough to quote (RFC 792)
+      if let (Some(header), Some(first_fragment)) = (datagram.get_header(), datagram.get_first_fragment()) {
+        if let Some(frame) = build_datagram_frame(Arc::clone(datagram.get_netif()), header, first_fragment) {
+          send_icmpv4_error(&frame, ICMPV4_TIME_EXCEEDED, ICMPV4_CODE_REASSEMBLY_EXCEEDED, [0; 4]);
+        }
+      }
+    }
   }
 }
 
@@ -93,11 +426,151 @@ pub fn generate_ipv4_header(buffer: &mut [u8], len: [u8;2], proto: u8, src_ip: I
   ipv4_hdr.dest_ip = dest_ip.get_array();
 }
 
+pub fn calc_checksum(slice: &[u8]) -> u16 {
+  let mut csum: u32 = 0;
+  for i in 0..(slice.len()/2) {
+    csum = csum + ((slice[i*2] as u32) << 8 | (slice[i*2+1] as u32));
+  }
+  if slice.len() % 2 == 1 {
+    csum = csum + ((slice[slice.len()-1] as u32) << 8);
+  }
+  csum = (csum & 0x0000ffff) + (csum >> 16);
+  csum = (csum & 0x0000ffff) + (csum >> 16);
+  !csum as u16
+}
+
+pub const ICMPV4_DEST_UNREACHABLE: u8 = 3;
+pub const ICMPV4_TIME_EXCEEDED: u8 = 11;
+
+pub const ICMPV4_CODE_NET_UNREACHABLE: u8 = 0;
+pub const ICMPV4_CODE_HOST_UNREACHABLE: u8 = 1;
+pub const ICMPV4_CODE_PROTOCOL_UNREACHABLE: u8 = 2;
+pub const ICMPV4_CODE_PORT_UNREACHABLE: u8 = 3;
+pub const ICMPV4_CODE_FRAGMENTATION_NEEDED: u8 = 4;
+pub const ICMPV4_CODE_TTL_EXCEEDED: u8 = 0;
+pub const ICMPV4_CODE_REASSEMBLY_EXCEEDED: u8 = 1;
+
+// an icmp error message mustn't exceed 576 bytes (RFC 1812 4.3.2.3)
+const ICMPV4_ERROR_MAX_LENGTH: usize = 576;
+const ICMPV4_ERROR_RATE_LIMIT: u64 = 100; // messages per second
+
+static ICMPV4_ERROR_WINDOW: AtomicU64 = AtomicU64::new(0);
+static ICMPV4_ERROR_COUNT: AtomicU64 = AtomicU64::new(0);
+
+fn is_icmpv4_error_allowed() -> bool {
+  let window = get_monotonic_time() / 1_000_000_000;
+  if ICMPV4_ERROR_WINDOW.swap(window, AtomicOrdering::Relaxed) != window {
+    ICMPV4_ERROR_COUNT.store(0, AtomicOrdering::Relaxed);
+  }
+  ICMPV4_ERROR_COUNT.fetch_add(1, AtomicOrdering::Relaxed) < ICMPV4_ERROR_RATE_LIMIT
+}
+
+// originate an icmp error about the received frame, back through its ingress interface.
+// `param` is the 4 bytes following the checksum (unused, or next-hop mtu for fragmentation needed).
+pub fn send_icmpv4_error(frame: &DataFromNetif, icmp_type: u8, code: u8, param: [u8; 4]) {
+  let slice = frame.get_buffer().slice();
+  let frame_length = frame.get_buffer().get_length();
+  if frame_length < 14 + 20 {
+    return; // too short for an ip header
+  }
+  let ipv4_hdr = unsafe { &*((&slice[14] as *const _) as *const Ipv4Packet) };
+  let src_ip = Ipv4Address::from_array(ipv4_hdr.src_ip);
+  let dest_ip = Ipv4Address::from_array(ipv4_hdr.dest_ip);
+  let header_length = (ipv4_hdr.version_and_ihl & 0x0f) as usize * 4;
+  let length = (ipv4_hdr.length[0] as usize) << 8 | ipv4_hdr.length[1] as usize;
+  if header_length < 20 || length < header_length || 14 + length > frame_length {
+    return; // a broken header
+  }
+
+  // never send an error about (RFC 1812 4.3.2.7)
+  if slice[0] & 0x01 != 0 {
+    return; // a link-layer broadcast or multicast
+  }
+  if dest_ip.is_multicast() || dest_ip.is_broadcast() {
+    return; // an ip broadcast or multicast
+  }
+  if src_ip.is_unspecified() || src_ip.is_loopback() || src_ip.is_multicast() || src_ip.is_broadcast() {
+    return; // a packet whose source doesn't define a single host
+  }
+  if ((slice[14+6] as usize & 0x1f) << 8 | slice[14+7] as usize) != 0 {
+    return; // a non-initial fragment
+  }
+  if ipv4_hdr.proto == 0x01 && header_length < length {
+    match slice[14+header_length] {
+      0 | 8 | 13 | 14 | 15 | 16 | 17 | 18 => (),
+      _ => return, // an icmp error
+    }
+  }
+  if !is_icmpv4_error_allowed() {
+    return;
+  }
+
+  let netif = Arc::clone(frame.get_netif());
+  let src_ip_addr = match find_ipv4_local_address(netif.get_id()) {
+    Some(addr) => addr,
+    None => return, // no address on the ingress interface.
+  };
+
+  // quote the offending ip header as received, followed by as much of its payload as fits
+  let quote_length = if 20 + 8 + length > ICMPV4_ERROR_MAX_LENGTH { ICMPV4_ERROR_MAX_LENGTH - 20 - 8 } else { length };
+  let total_length = 20 + 8 + quote_length;
+
+  let respbuff = netif.pre_xmit(14+total_length);
+  let respslice = respbuff.slice_mut();
+
+  let dest_mac = MacAddress::new(slice[6..12].try_into().unwrap());
+  generate_ether_header(&mut respslice[0..], *netif.get_macaddress(), dest_mac, [0x08, 0x00]);
+  generate_ipv4_header(&mut respslice[14..], [(total_length >> 8) as u8, total_length as u8], 0x01, src_ip_addr, src_ip);
+
+  respslice[14+20] = icmp_type;
+  respslice[14+20+1] = code;
+  respslice[14+20+2] = 0;
+  respslice[14+20+3] = 0;
+  respslice[(14+20+4)..(14+20+8)].copy_from_slice(&param);
+  respslice[(14+20+8)..(14+total_length)].copy_from_slice(&slice[14..(14+quote_length)]);
+
+  let csum_icmp = calc_checksum(&respslice[(14+20)..(14+total_length)]);
+  respslice[14+20+2] = (csum_icmp >> 8) as u8;
+  respslice[14+20+3] = csum_icmp as u8;
+
+  let csum_ipv4 = calc_checksum(&respslice[14..(14+20)]);
+  respslice[24] = (csum_ipv4 >> 8) as u8;
+  respslice[25] = csum_ipv4 as u8;
+
+  let _ = netif.xmit(respbuff);
+}
+
+// hash of the 5-tuple to pick one of equal cost next hops.
+// fragments only hash the addresses and protocol so that all of them take the same path.
+fn flow_hash(slice: &[u8], length: usize) -> u32 {
+  let ipv4_hdr = unsafe { &*((&slice[14] as *const _) as *const Ipv4Packet) };
+  let header_length = (ipv4_hdr.version_and_ihl & 0x0f) as usize * 4;
+  let is_fragment = (slice[14+6] & 0x3f) != 0 || slice[14+7] != 0;
+
+  let ports = match ipv4_hdr.proto {
+    6 | 17 | 132 if !is_fragment && 14 + header_length + 4 <= length => {
+      let p = &slice[(14+header_length)..(14+header_length+4)];
+      (p[0] as u32) << 24 | (p[1] as u32) << 16 | (p[2] as u32) << 8 | p[3] as u32
+    },
+    _ => 0,
+  };
+  calc_flow_hash(&[
+    Ipv4Address::from_array(ipv4_hdr.src_ip).get_prim(),
+    Ipv4Address::from_array(ipv4_hdr.dest_ip).get_prim(),
+    ipv4_hdr.proto as u32,
+    ports,
+  ])
+}
+
 impl ProcessingNode for Ipv4In {
   fn process(&self, buff: &[DataFromNetif]) {
     let mut icmp_pkts = Vec::with_capacity(buff.len());
 
     for frame in buff.iter() {
+      if frame.get_buffer().get_length() < 14 + 20 {
+        // too short for an ip header. drop it.
+        continue;
+      }
       let slice = frame.get_buffer().slice();
       let ipv4_hdr = unsafe { &*((&slice[14] as *const _) as *const Ipv4Packet) };
       let dest_ip_addr = Ipv4Address::from_array(ipv4_hdr.dest_ip);
@@ -107,33 +580,55 @@ impl ProcessingNode for Ipv4In {
         continue;
       }
 
-      if let Some(fib) = find_ipv4_fib(&dest_ip_addr, 0xffffffff) {
+      if let Some(fib) = find_ipv4_fib(&dest_ip_addr, 0xffffffff, flow_hash(slice, frame.get_buffer().get_length())) {
         //println!("fib is found. {:?} mac={:?}", fib.get_nexthop_address().get_array(), fib.get_nexthop_macaddress().get_array());
         match fib.get_fib_type() {
           FIBType::Local => {
+            let is_fragment = (slice[14+6] & 0x3f) != 0 || slice[14+7] != 0;
+            let local_frame = if is_fragment {
+              match self.reassemble(frame) {
+                Some(datagram) => datagram,
+                None => continue,
+              }
+            } else {
+              frame.clone()
+            };
+
             match ipv4_hdr.proto {
-              0x01 => icmp_pkts.push(frame.clone()), //ICMP
-              _ => (),
+              0x01 => icmp_pkts.push(local_frame), //ICMP
+              0x11 => send_icmpv4_error(&local_frame, ICMPV4_DEST_UNREACHABLE, ICMPV4_CODE_PORT_UNREACHABLE, [0; 4]), //UDP
+              _ => send_icmpv4_error(&local_frame, ICMPV4_DEST_UNREACHABLE, ICMPV4_CODE_PROTOCOL_UNREACHABLE, [0; 4]),
             }
           },
           FIBType::Adjacent => {
             // must resolve mac address using arp.
+            arp::enqueue_unresolved(frame.clone(), dest_ip_addr, fib);
+          },
+          FIBType::RemoteUnresolved => {
+            // resolve the mac address of the gateway rather than of the destination.
+            arp::enqueue_unresolved(frame.clone(), fib.get_nexthop_address(), fib);
           },
-          FIBType::AdjacentResolved => {
-            //forward
+          FIBType::AdjacentResolved | FIBType::Remote => {
+            self.forward(frame, fib);
           },
-          FIBType::Remote => {
-            //forward
+          FIBType::Blackhole => {
+            self.dropped_no_route.fetch_add(1, AtomicOrdering::Relaxed);
+          },
+          FIBType::Reject => {
+            self.dropped_no_route.fetch_add(1, AtomicOrdering::Relaxed);
+            send_icmpv4_error(frame, ICMPV4_DEST_UNREACHABLE, ICMPV4_CODE_NET_UNREACHABLE, [0; 4]);
           },
         }
       } else {
         // fib not found. cannot handle this packet.
+        self.dropped_no_route.fetch_add(1, AtomicOrdering::Relaxed);
+        send_icmpv4_error(frame, ICMPV4_DEST_UNREACHABLE, ICMPV4_CODE_NET_UNREACHABLE, [0; 4]);
       }
+    }
 
-      if icmp_pkts.len() > 0 {
-        if let Some(node_ref) = unsafe { PROC_NODES.get("icmpv4-in-local") } {
-          node_ref.process(&icmp_pkts);
-        }
+    if icmp_pkts.len() > 0 {
+      if let Some(node_ref) = unsafe { PROC_NODES.get("icmpv4-in-local") } {
+        node_ref.process(&icmp_pkts);
       }
     }
   }
@@ -171,8 +666,18 @@ impl ProcessingNode for Icmpv4InLocal {
           //echo request
           //println!("Got ICMP Echo request");
 
-          let length = ((ipv4_hdr.length[0] as usize) << 8 | ipv4_hdr.length[1] as usize) - ((ipv4_hdr.version_and_ihl & 0x0f) as usize * 4);
+          let total_length = (ipv4_hdr.length[0] as usize) << 8 | ipv4_hdr.length[1] as usize;
+          let header_length = (ipv4_hdr.version_and_ihl & 0x0f) as usize * 4;
+          if header_length < 20 || total_length < header_length + 8 || 14 + total_length > frame.get_buffer().get_length() {
+            // broken length field. drop it.
+            continue;
+          }
+          let length = total_length - header_length;
           let netif = Arc::clone(frame.get_netif());
+          if 20 + length > netif.get_mtu() {
+            // the reply of a reassembled request doesn't fit the link, and isn't fragmented.
+            continue;
+          }
           let respbuff = netif.pre_xmit(14+20+length);
           let respslice = respbuff.slice_mut();
 
@@ -180,7 +685,7 @@ impl ProcessingNode for Icmpv4InLocal {
 
           let dest_mac = MacAddress::new(slice[6..12].try_into().unwrap());
           generate_ether_header(&mut respslice[0..], *netif.get_macaddress(), dest_mac, [0x08, 0x00]);
-          generate_ipv4_header(&mut respslice[14..], ipv4_hdr.length, 0x01, Ipv4Address::from_array(ipv4_hdr.dest_ip), Ipv4Address::from_array(ipv4_hdr.src_ip));
+          generate_ipv4_header(&mut respslice[14..], [((20+length) >> 8) as u8, (20+length) as u8], 0x01, Ipv4Address::from_array(ipv4_hdr.dest_ip), Ipv4Address::from_array(ipv4_hdr.src_ip));
 
           let mut icmpv4_send_hdr = unsafe { &mut *((&mut respslice[14+20] as *mut _) as *mut Icmpv4Packet) };
           icmpv4_send_hdr.icmp_type = 0x00; //icmp echo reply
@@ -188,9 +693,8 @@ impl ProcessingNode for Icmpv4InLocal {
           icmpv4_send_hdr.checksum = 0;
           icmpv4_send_hdr.identifier = icmpv4_hdr.identifier;
           icmpv4_send_hdr.sequence = icmpv4_hdr.sequence;
-          for i in (14+20+8)..(14+20+length) {
-            respslice[i] = slice[i];
-          }
+          // the reply carries no ip options
+          respslice[(14+20+8)..(14+20+length)].copy_from_slice(&slice[(14+header_length+8)..(14+total_length)]);
 
           let mut csum_icmp: u32 = 0;
           for i in ((14+20)/2)..((14+20+length)/2) {
@@ -213,6 +717,10 @@ impl ProcessingNode for Icmpv4InLocal {
           
           netif.xmit(respbuff);
         },
+        0x00 => {
+          //echo reply to our ping
+          ping::notify_echo_reply(u16::from_be(icmpv4_hdr.identifier), u16::from_be(icmpv4_hdr.sequence));
+        },
         _ => (),
       }
     }
@@ -1,13 +1,23 @@
 use core::convert::TryInto;
 use core::cmp::Ordering;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use core::time::Duration;
 
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 
 use crate::devices::buffer::Buffer;
 use crate::net::{DataFromNetif, ProcessingNode};
+use crate::net::reassembly::{Reassembler, ReassemblyResult, build_datagram_frame};
 use crate::net::ethernet::{MacAddress, generate_ether_header};
-use crate::net::fib::{FIBType, MAC_ADDR_TABLE, AdjacentInformation, register_macaddress, register_ipv6_adjacent, register_ipv6_fib, find_ipv6_fib};
+use crate::net::nd;
+use crate::net::ping;
+use crate::net::ipv4::Ipv4Address;
+use crate::net::fib::{FIBType, MAC_ADDR_TABLE, AdjacentInformation, ForwardInformationBaseIpv6, register_macaddress, register_ipv6_adjacent, register_ipv6_fib, find_ipv6_fib, find_ipv6_link_local_address, find_ipv6_source_address, calc_flow_hash};
+use crate::spinlock::{Spinlock, const_spinlock};
+use crate::asynchronous::timer::TimerFuture;
+use crate::arch::x86_64::kvmclock::get_monotonic_time;
 use crate::PROC_NODES;
 
 #[derive(Debug, Copy, Clone)]
@@ -38,9 +48,23 @@ impl Ipv6Address {
   }
 
   pub fn masked(&self, prefix_length: u32) -> Ipv6Address {
-    let mask = 0xffffffff_ffffffff_ffffffff_ffffffffu128 << (128 - prefix_length);
+    let mask = 0xffffffff_ffffffff_ffffffff_ffffffffu128.checked_shl(128 - prefix_length).unwrap_or(0);
     Ipv6Address { addr_prim: self.addr_prim & mask }
   }
+
+  pub fn is_link_local(&self) -> bool {
+    // fe80::/10
+    (self.addr_prim >> 118) == 0x3fa
+  }
+
+  pub fn is_multicast(&self) -> bool {
+    // ff00::/8
+    (self.addr_prim >> 120) == 0xff
+  }
+
+  pub fn is_unspecified(&self) -> bool {
+    self.addr_prim == 0
+  }
         
   pub fn get_array(&self) -> [u8; 16] {
     [
@@ -63,9 +87,64 @@ impl Ipv6Address {
     ]
   }
 
+  pub const fn from_prim(prim: u128) -> Ipv6Address {
+    Ipv6Address { addr_prim: prim }
+  }
+
   pub fn get_prim(&self) -> u128 {
     self.addr_prim
   }
+
+  // RFC 4291 text form, e.g. "2001:db8::1" or "::ffff:192.168.0.1"
+  pub fn parse(s: &str) -> Option<Ipv6Address> {
+    fn parse_groups(s: &str, groups: &mut Vec<u16>) -> Option<()> {
+      if s.len() == 0 {
+        return Some(());
+      }
+      let fields: Vec<&str> = s.split(':').collect();
+      for (i, field) in fields.iter().enumerate() {
+        if i == fields.len() - 1 && field.contains('.') {
+          // embedded ipv4 address at the tail
+          let v4 = Ipv4Address::parse(field)?.get_prim();
+          groups.push((v4 >> 16) as u16);
+          groups.push(v4 as u16);
+        } else {
+          if field.len() == 0 || field.len() > 4 || !field.bytes().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+          }
+          groups.push(u16::from_str_radix(field, 16).ok()?);
+        }
+      }
+      Some(())
+    }
+
+    let mut head = Vec::with_capacity(8);
+    let mut tail = Vec::with_capacity(8);
+    match s.find("::") {
+      Some(idx) => {
+        parse_groups(&s[..idx], &mut head)?;
+        parse_groups(&s[(idx+2)..], &mut tail)?;
+        if head.len() + tail.len() > 7 {
+          return None;
+        }
+      },
+      None => {
+        parse_groups(s, &mut head)?;
+        if head.len() != 8 {
+          return None;
+        }
+      },
+    }
+
+    let mut prim = 0u128;
+    for (i, group) in head.iter().enumerate() {
+      prim |= (*group as u128) << (112 - i * 16);
+    }
+    for (i, group) in tail.iter().rev().enumerate() {
+      prim |= (*group as u128) << (i * 16);
+    }
+    Some(Ipv6Address::from_prim(prim))
+  }
 }
 
 impl Ord for Ipv6Address {
@@ -88,13 +167,246 @@ impl PartialEq for Ipv6Address {
   }
 }
 
+// RFC 5952 text form. the longest run of zero groups is compressed.
+impl fmt::Display for Ipv6Address {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let mut groups = [0u16; 8];
+    for i in 0..8 {
+      groups[i] = (self.addr_prim >> (112 - i * 16)) as u16;
+    }
+
+    let mut zero_run = (0, 0); // start and length
+    let mut i = 0;
+    while i < 8 {
+      if groups[i] == 0 {
+        let start = i;
+        while i < 8 && groups[i] == 0 {
+          i += 1;
+        }
+        if i - start > zero_run.1 {
+          zero_run = (start, i - start);
+        }
+      } else {
+        i += 1;
+      }
+    }
+
+    if zero_run.1 < 2 {
+      zero_run = (8, 0); // a single zero group isn't compressed
+    }
+    for i in 0..zero_run.0 {
+      if i > 0 {
+        write!(f, ":")?;
+      }
+      write!(f, "{:x}", groups[i])?;
+    }
+    if zero_run.1 > 0 {
+      write!(f, "::")?;
+    }
+    for i in (zero_run.0 + zero_run.1)..8 {
+      if i > zero_run.0 + zero_run.1 {
+        write!(f, ":")?;
+      }
+      write!(f, "{:x}", groups[i])?;
+    }
+    Ok(())
+  }
+}
+
 /////////
 
-pub struct Ipv6In;
+pub struct Ipv6In {
+  forwarded: AtomicU64,
+  dropped_hoplimit_expired: AtomicU64,
+  dropped_scope_violation: AtomicU64,
+  dropped_too_big: AtomicU64,
+  dropped_no_route: AtomicU64,
+  dropped_xmit_failed: AtomicU64,
+  dropped_bad_header: AtomicU64,
+  dropped_reassembly_failed: AtomicU64,
+  reassembled: AtomicU64,
+}
 
 impl Ipv6In {
   pub const fn new() -> Ipv6In {
-    Ipv6In {}
+    Ipv6In {
+      forwarded: AtomicU64::new(0),
+      dropped_hoplimit_expired: AtomicU64::new(0),
+      dropped_scope_violation: AtomicU64::new(0),
+      dropped_too_big: AtomicU64::new(0),
+      dropped_no_route: AtomicU64::new(0),
+      dropped_xmit_failed: AtomicU64::new(0),
+      dropped_bad_header: AtomicU64::new(0),
+      dropped_reassembly_failed: AtomicU64::new(0),
+      reassembled: AtomicU64::new(0),
+    }
+  }
+
+  pub fn get_forwarded_count(&self) -> u64 {
+    self.forwarded.load(AtomicOrdering::Relaxed)
+  }
+
+  pub fn get_dropped_hoplimit_expired_count(&self) -> u64 {
+    self.dropped_hoplimit_expired.load(AtomicOrdering::Relaxed)
+  }
+
+  pub fn get_dropped_scope_violation_count(&self) -> u64 {
+    self.dropped_scope_violation.load(AtomicOrdering::Relaxed)
+  }
+
+  pub fn get_dropped_too_big_count(&self) -> u64 {
+    self.dropped_too_big.load(AtomicOrdering::Relaxed)
+  }
+
+  pub fn get_dropped_no_route_count(&self) -> u64 {
+    self.dropped_no_route.load(AtomicOrdering::Relaxed)
+  }
+
+  pub fn get_dropped_xmit_failed_count(&self) -> u64 {
+    self.dropped_xmit_failed.load(AtomicOrdering::Relaxed)
+  }
+
+  pub fn get_dropped_bad_header_count(&self) -> u64 {
+    self.dropped_bad_header.load(AtomicOrdering::Relaxed)
+  }
+
+  pub fn get_dropped_reassembly_failed_count(&self) -> u64 {
+    self.dropped_reassembly_failed.load(AtomicOrdering::Relaxed)
+  }
+
+  pub fn get_reassembled_count(&self) -> u64 {
+    self.reassembled.load(AtomicOrdering::Relaxed)
+  }
+
+  // report a broken header chain to the source, or drop silently if the option says so
+  fn reject(&self, frame: &DataFromNetif, err: ExtensionHeaderError) {
+    self.dropped_bad_header.fetch_add(1, AtomicOrdering::Relaxed);
+    if let ExtensionHeaderError::ParameterProblem(code, pointer) = err {
+      send_icmpv6_error(frame, ICMPV6_PARAMETER_PROBLEM, code, [(pointer >> 24) as u8, (pointer >> 16) as u8, (pointer >> 8) as u8, pointer as u8]);
+    }
+  }
+
+  // returns the whole packet, without the fragment header, once its last missing fragment arrives
+  fn reassemble(&self, frame: &DataFromNetif, chain: &ExtensionHeaderChain) -> Option<DataFromNetif> {
+    let slice = frame.get_buffer().slice();
+    let ipv6_hdr = unsafe { &*((&slice[14] as *const _) as *const Ipv6Packet) };
+    let length = 40 + ((ipv6_hdr.length[0] as usize) << 8 | ipv6_hdr.length[1] as usize);
+    let frag_hdr_offset = chain.get_fragment_header_offset()?;
+
+    if 14 + length > frame.get_buffer().get_length() || frag_hdr_offset + 8 > length {
+      return None;
+    }
+
+    let frag_hdr = &slice[(14+frag_hdr_offset)..(14+frag_hdr_offset+8)];
+    let offset_and_flags = (frag_hdr[2] as usize) << 8 | frag_hdr[3] as usize;
+    let offset = offset_and_flags & 0xfff8;
+    let more = (offset_and_flags & 0x0001) != 0;
+    let data = &slice[(14+frag_hdr_offset+8)..(14+length)];
+
+    if more && data.len() % 8 != 0 {
+      // every fragment but the last must be a multiple of 8 bytes (RFC 8200 4.5)
+      self.reject(frame, ExtensionHeaderError::ParameterProblem(ICMPV6_CODE_ERRONEOUS_HEADER, 4));
+      return None;
+    }
+    if offset + data.len() > 65535 {
+      self.reject(frame, ExtensionHeaderError::ParameterProblem(ICMPV6_CODE_ERRONEOUS_HEADER, (frag_hdr_offset + 2) as u32));
+      return None;
+    }
+
+    let key = (
+      Ipv6Address::from_array(ipv6_hdr.src_ip).get_prim(),
+      Ipv6Address::from_array(ipv6_hdr.dest_ip).get_prim(),
+      (frag_hdr[4] as u32) << 24 | (frag_hdr[5] as u32) << 16 | (frag_hdr[6] as u32) << 8 | frag_hdr[7] as u32,
+    );
+
+    // the unfragmentable part of the first fragment, pointing to what followed the fragment header
+    let header = if offset == 0 {
+      let mut header = slice[0..(14+frag_hdr_offset)].to_vec();
+      header[14+chain.get_fragment_nexthdr_field()] = frag_hdr[0];
+      Some(header)
+    } else {
+      None
+    };
+
+    let result = IPV6_REASSEMBLY.lock().insert(key, frame.get_netif(), header.as_ref().map(|h| &h[..]), offset, data, more, get_monotonic_time());
+    match result {
+      ReassemblyResult::Complete(netif, mut header, payload) => {
+        let payload_length = header.len() - 14 - 40 + payload.len();
+        if payload_length > 65535 {
+          self.dropped_reassembly_failed.fetch_add(1, AtomicOrdering::Relaxed);
+          return None;
+        }
+        header[14+4] = (payload_length >> 8) as u8;
+        header[14+5] = payload_length as u8;
+
+        self.reassembled.fetch_add(1, AtomicOrdering::Relaxed);
+        build_datagram_frame(netif, &header, &payload)
+      },
+      ReassemblyResult::Incomplete => None,
+      ReassemblyResult::Dropped => {
+        self.dropped_reassembly_failed.fetch_add(1, AtomicOrdering::Relaxed);
+        None
+      },
+    }
+  }
+
+  fn forward(&self, frame: &DataFromNetif, fib: &ForwardInformationBaseIpv6) {
+    let slice = frame.get_buffer().slice();
+    let ipv6_hdr = unsafe { &*((&slice[14] as *const _) as *const Ipv6Packet) };
+    let length = 40 + ((ipv6_hdr.length[0] as usize) << 8 | ipv6_hdr.length[1] as usize);
+
+    if 14 + length > frame.get_buffer().get_length() {
+      // broken length field. drop it.
+      return;
+    }
+
+    let netif = Arc::clone(fib.get_netif());
+
+    // link-local addresses must not be forwarded beyond the link (RFC 4291 2.5.6)
+    let src_ip_addr = Ipv6Address::from_array(ipv6_hdr.src_ip);
+    let dest_ip_addr = Ipv6Address::from_array(ipv6_hdr.dest_ip);
+    if (src_ip_addr.is_link_local() || dest_ip_addr.is_link_local()) && netif.get_id() != frame.get_netif().get_id() {
+      self.dropped_scope_violation.fetch_add(1, AtomicOrdering::Relaxed);
+      if src_ip_addr.is_link_local() {
+        send_icmpv6_error(frame, ICMPV6_DEST_UNREACHABLE, ICMPV6_CODE_BEYOND_SCOPE, [0; 4]);
+      }
+      return;
+    }
+
+    if ipv6_hdr.hoplimit <= 1 {
+      // hop limit exceeded in transit.
+      self.dropped_hoplimit_expired.fetch_add(1, AtomicOrdering::Relaxed);
+      send_icmpv6_error(frame, ICMPV6_TIME_EXCEEDED, ICMPV6_CODE_HOPLIMIT_EXCEEDED, [0; 4]);
+      return;
+    }
+
+    let mtu = netif.get_mtu();
+    if length > mtu {
+      // routers never fragment ipv6 packets. let the source discover the path mtu.
+      self.dropped_too_big.fetch_add(1, AtomicOrdering::Relaxed);
+      send_icmpv6_error(frame, ICMPV6_PACKET_TOO_BIG, 0, [(mtu >> 24) as u8, (mtu >> 16) as u8, (mtu >> 8) as u8, mtu as u8]);
+      return;
+    }
+
+    nd::notify_forwarding(&fib.get_nexthop_address());
+
+    let fwdbuff = netif.pre_xmit(14+length);
+    let fwdslice = fwdbuff.slice_mut();
+
+    fwdslice[0..(14+length)].copy_from_slice(&slice[0..(14+length)]);
+    generate_ether_header(&mut fwdslice[0..], *netif.get_macaddress(), fib.get_nexthop_macaddress(), [0x86, 0xdd]);
+
+    // ipv6 has no header checksum. just decrement hop limit.
+    fwdslice[14+7] = fwdslice[14+7] - 1;
+
+    match netif.xmit(fwdbuff) {
+      Ok(_) => {
+        self.forwarded.fetch_add(1, AtomicOrdering::Relaxed);
+      },
+      Err(_) => {
+        self.dropped_xmit_failed.fetch_add(1, AtomicOrdering::Relaxed);
+      },
+    }
   }
 }
 
@@ -124,11 +436,390 @@ pub fn generate_ipv6_header(buffer: &mut [u8], len: [u8;2], nexthdr: u8, src_ip:
   ipv6_hdr.dest_ip = dest_ip.get_array();
 }
 
+pub fn calc_icmpv6_checksum(src_ip: &Ipv6Address, dest_ip: &Ipv6Address, icmpslice: &[u8]) -> u16 {
+  let mut csum: u32 = 0;
+
+  // pseudo header
+  let src_array = src_ip.get_array();
+  let dest_array = dest_ip.get_array();
+  for i in 0..8 {
+    csum = csum + ((src_array[i*2] as u32) << 8 | src_array[i*2+1] as u32);
+    csum = csum + ((dest_array[i*2] as u32) << 8 | dest_array[i*2+1] as u32);
+  }
+  csum = csum + (icmpslice.len() as u32 >> 16) + (icmpslice.len() as u32 & 0xffff);
+  csum = csum + 58;
+
+  for i in 0..(icmpslice.len()/2) {
+    if i == 1 {
+      continue; // checksum field
+    }
+    csum = csum + ((icmpslice[i*2] as u32) << 8 | (icmpslice[i*2+1] as u32));
+  }
+  if icmpslice.len() % 2 == 1 {
+    csum = csum + ((icmpslice[icmpslice.len()-1] as u32) << 8);
+  }
+  csum = (csum & 0x0000ffff) + (csum >> 16);
+  csum = (csum & 0x0000ffff) + (csum >> 16);
+  !csum as u16
+}
+
+pub const ICMPV6_DEST_UNREACHABLE: u8 = 1;
+pub const ICMPV6_PACKET_TOO_BIG: u8 = 2;
+pub const ICMPV6_TIME_EXCEEDED: u8 = 3;
+pub const ICMPV6_PARAMETER_PROBLEM: u8 = 4;
+
+pub const ICMPV6_CODE_NO_ROUTE: u8 = 0;
+pub const ICMPV6_CODE_BEYOND_SCOPE: u8 = 2;
+pub const ICMPV6_CODE_ADDRESS_UNREACHABLE: u8 = 3;
+pub const ICMPV6_CODE_PORT_UNREACHABLE: u8 = 4;
+pub const ICMPV6_CODE_REJECT_ROUTE: u8 = 6;
+pub const ICMPV6_CODE_HOPLIMIT_EXCEEDED: u8 = 0;
+
+// an icmpv6 error message mustn't exceed the minimum ipv6 mtu (RFC 4443 2.4 (c))
+const ICMPV6_ERROR_MAX_LENGTH: usize = 1280;
+const ICMPV6_ERROR_RATE_LIMIT: u64 = 100; // messages per second
+
+static ICMPV6_ERROR_WINDOW: AtomicU64 = AtomicU64::new(0);
+static ICMPV6_ERROR_COUNT: AtomicU64 = AtomicU64::new(0);
+
+fn is_icmpv6_error_allowed() -> bool {
+  let window = get_monotonic_time() / 1_000_000_000;
+  if ICMPV6_ERROR_WINDOW.swap(window, AtomicOrdering::Relaxed) != window {
+    ICMPV6_ERROR_COUNT.store(0, AtomicOrdering::Relaxed);
+  }
+  ICMPV6_ERROR_COUNT.fetch_add(1, AtomicOrdering::Relaxed) < ICMPV6_ERROR_RATE_LIMIT
+}
+
+// originate an icmpv6 error about the received frame, back through its ingress interface.
+// `param` is the 4 bytes following the checksum (unused, mtu or pointer).
+pub fn send_icmpv6_error(frame: &DataFromNetif, icmp_type: u8, code: u8, param: [u8; 4]) {
+  let slice = frame.get_buffer().slice();
+  let frame_length = frame.get_buffer().get_length();
+  if frame_length < 14 + 40 {
+    return; // too short for an ip header
+  }
+  let ipv6_hdr = unsafe { &*((&slice[14] as *const _) as *const Ipv6Packet) };
+  let src_ip = Ipv6Address::from_array(ipv6_hdr.src_ip);
+  let dest_ip = Ipv6Address::from_array(ipv6_hdr.dest_ip);
+  let length = 40 + ((ipv6_hdr.length[0] as usize) << 8 | ipv6_hdr.length[1] as usize);
+
+  if 14 + length > frame_length {
+    return; // a broken header
+  }
+
+  // never send an error about (RFC 4443 2.4 (e))
+  let is_exempted = icmp_type == ICMPV6_PACKET_TOO_BIG || (icmp_type == ICMPV6_PARAMETER_PROBLEM && code == 2);
+  if !is_exempted && (slice[0] & 0x01 != 0 || dest_ip.is_multicast()) {
+    return; // a multicast, except for packet too big and unrecognized option
+  }
+  if src_ip.is_unspecified() || src_ip.is_multicast() {
+    return; // a packet whose source doesn't define a single node
+  }
+  if let Ok(chain) = walk_extension_headers(&slice[14..(14+length)], true) {
+    let offset = chain.get_upper_offset();
+    // non-first fragments don't carry the icmpv6 header
+    let has_upper_header = match chain.get_fragment_header_offset() {
+      Some(frag) => slice[14+frag+2] == 0 && slice[14+frag+3] & 0xf8 == 0,
+      None => true,
+    };
+    if chain.get_upper_protocol() == 58 && has_upper_header && length > offset && slice[14+offset] < 128 {
+      return; // an icmpv6 error
+    }
+  }
+  if !is_icmpv6_error_allowed() {
+    return;
+  }
+
+  let netif = Arc::clone(frame.get_netif());
+  let src_ip_addr = match find_ipv6_source_address(netif.get_id(), &src_ip) {
+    Some(addr) => addr,
+    None => return, // no address on the ingress interface.
+  };
+
+  // quote as much of the offending packet as fits in the minimum mtu
+  let quote_length = if 40 + 8 + length > ICMPV6_ERROR_MAX_LENGTH { ICMPV6_ERROR_MAX_LENGTH - 40 - 8 } else { length };
+  let icmp_length = 8 + quote_length;
+
+  let respbuff = netif.pre_xmit(14+40+icmp_length);
+  let respslice = respbuff.slice_mut();
+
+  let dest_mac = MacAddress::new(slice[6..12].try_into().unwrap());
+  generate_ether_header(&mut respslice[0..], *netif.get_macaddress(), dest_mac, [0x86, 0xdd]);
+  generate_ipv6_header(&mut respslice[14..], [(icmp_length >> 8) as u8, icmp_length as u8], 58, src_ip_addr, src_ip);
+
+  let icmpslice = &mut respslice[(14+40)..(14+40+icmp_length)];
+  icmpslice[0] = icmp_type;
+  icmpslice[1] = code;
+  icmpslice[2] = 0;
+  icmpslice[3] = 0;
+  icmpslice[4..8].copy_from_slice(&param);
+  icmpslice[8..].copy_from_slice(&slice[14..(14+quote_length)]);
+
+  let csum = calc_icmpv6_checksum(&src_ip_addr, &src_ip, icmpslice);
+  icmpslice[2] = (csum >> 8) as u8;
+  icmpslice[3] = csum as u8;
+
+  let _ = netif.xmit(respbuff);
+}
+
+pub const ICMPV6_CODE_ERRONEOUS_HEADER: u8 = 0;
+pub const ICMPV6_CODE_UNRECOGNIZED_NEXTHDR: u8 = 1;
+pub const ICMPV6_CODE_UNRECOGNIZED_OPTION: u8 = 2;
+pub const ICMPV6_CODE_INCOMPLETE_CHAIN: u8 = 3;
+pub const ICMPV6_CODE_REASSEMBLY_EXCEEDED: u8 = 1;
+
+const IPV6_NEXTHDR_HOP_BY_HOP: u8 = 0;
+const IPV6_NEXTHDR_ROUTING: u8 = 43;
+const IPV6_NEXTHDR_FRAGMENT: u8 = 44;
+const IPV6_NEXTHDR_AUTH: u8 = 51;
+const IPV6_NEXTHDR_DEST_OPTIONS: u8 = 60;
+
+const IPV6_OPTION_PAD1: u8 = 0;
+const IPV6_OPTION_PADN: u8 = 1;
+const IPV6_OPTION_ROUTER_ALERT: u8 = 5;
+const IPV6_ROUTER_ALERT_MLD: u16 = 0;
+
+pub enum ExtensionHeaderError {
+  ParameterProblem(u8, u32), // code, pointer from the beginning of the ipv6 header
+  Discard,
+}
+
+pub struct ExtensionHeaderChain {
+  upper_protocol: u8,
+  upper_offset: usize,
+  upper_nexthdr_field: usize,
+  fragment_header_offset: Option<usize>,
+  fragment_nexthdr_field: usize,
+  router_alert: Option<u16>,
+}
+
+impl ExtensionHeaderChain {
+  // protocol of the header following the chain. when the packet is a non-first fragment this is
+  // what follows the fragment header, and the upper-layer header itself is not in this packet.
+  pub fn get_upper_protocol(&self) -> u8 {
+    self.upper_protocol
+  }
+
+  // offsets are from the beginning of the ipv6 header
+  pub fn get_upper_offset(&self) -> usize {
+    self.upper_offset
+  }
+
+  pub fn get_upper_nexthdr_field(&self) -> usize {
+    self.upper_nexthdr_field
+  }
+
+  pub fn get_fragment_header_offset(&self) -> Option<usize> {
+    self.fragment_header_offset
+  }
+
+  pub fn get_fragment_nexthdr_field(&self) -> usize {
+    self.fragment_nexthdr_field
+  }
+
+  pub fn get_router_alert(&self) -> Option<u16> {
+    self.router_alert
+  }
+}
+
+// process the options of a hop-by-hop or destination options header (RFC 8200 4.2)
+fn process_ipv6_options(ipv6slice: &[u8], start: usize, end: usize, is_multicast: bool) -> Result<Option<u16>, ExtensionHeaderError> {
+  let mut router_alert = None;
+  let mut i = start + 2;
+  while i < end {
+    let option_type = ipv6slice[i];
+    if option_type == IPV6_OPTION_PAD1 {
+      i += 1;
+      continue;
+    }
+    if i + 2 > end || i + 2 + ipv6slice[i+1] as usize > end {
+      return Err(ExtensionHeaderError::ParameterProblem(ICMPV6_CODE_ERRONEOUS_HEADER, (i + 1) as u32));
+    }
+    let option_length = ipv6slice[i+1] as usize;
+
+    match option_type {
+      IPV6_OPTION_PADN => (),
+      IPV6_OPTION_ROUTER_ALERT => {
+        if option_length != 2 {
+          return Err(ExtensionHeaderError::ParameterProblem(ICMPV6_CODE_ERRONEOUS_HEADER, (i + 1) as u32));
+        }
+        router_alert = Some((ipv6slice[i+2] as u16) << 8 | ipv6slice[i+3] as u16);
+      },
+      _ => {
+        // the highest-order two bits tell what to do with an unrecognized option
+        match option_type >> 6 {
+          0 => (),
+          1 => return Err(ExtensionHeaderError::Discard),
+          2 => return Err(ExtensionHeaderError::ParameterProblem(ICMPV6_CODE_UNRECOGNIZED_OPTION, i as u32)),
+          _ => {
+            if is_multicast {
+              return Err(ExtensionHeaderError::Discard);
+            }
+            return Err(ExtensionHeaderError::ParameterProblem(ICMPV6_CODE_UNRECOGNIZED_OPTION, i as u32));
+          },
+        }
+      },
+    }
+    i += 2 + option_length;
+  }
+  Ok(router_alert)
+}
+
+// walk the extension header chain of an ipv6 packet. `ipv6slice` starts at the ipv6 header and ends at its payload.
+// hop-by-hop options are processed by every node, the other headers only when `is_destination`.
+pub fn walk_extension_headers(ipv6slice: &[u8], is_destination: bool) -> Result<ExtensionHeaderChain, ExtensionHeaderError> {
+  let is_multicast = ipv6slice[24] == 0xff;
+  let mut chain = ExtensionHeaderChain {
+    upper_protocol: ipv6slice[6],
+    upper_offset: 40,
+    upper_nexthdr_field: 6,
+    fragment_header_offset: None,
+    fragment_nexthdr_field: 6,
+    router_alert: None,
+  };
+  let mut is_first_fragment = false;
+
+  loop {
+    let nexthdr = chain.upper_protocol;
+    let offset = chain.upper_offset;
+
+    let is_extension_header = match nexthdr {
+      IPV6_NEXTHDR_HOP_BY_HOP | IPV6_NEXTHDR_ROUTING | IPV6_NEXTHDR_FRAGMENT | IPV6_NEXTHDR_AUTH | IPV6_NEXTHDR_DEST_OPTIONS => true,
+      _ => false,
+    };
+    if !is_extension_header || (!is_destination && nexthdr != IPV6_NEXTHDR_HOP_BY_HOP) {
+      // reached the upper-layer header, or something only the destination looks into
+      return Ok(chain);
+    }
+    if nexthdr == IPV6_NEXTHDR_HOP_BY_HOP && offset != 40 {
+      // hop-by-hop options header must immediately follow the ipv6 header
+      return Err(ExtensionHeaderError::ParameterProblem(ICMPV6_CODE_UNRECOGNIZED_NEXTHDR, chain.upper_nexthdr_field as u32));
+    }
+
+    let header_length = if offset + 2 > ipv6slice.len() {
+      0
+    } else if nexthdr == IPV6_NEXTHDR_FRAGMENT {
+      8
+    } else if nexthdr == IPV6_NEXTHDR_AUTH {
+      (ipv6slice[offset+1] as usize + 2) * 4
+    } else {
+      (ipv6slice[offset+1] as usize + 1) * 8
+    };
+    if header_length == 0 || offset + header_length > ipv6slice.len() {
+      if is_first_fragment {
+        // the first fragment must carry the whole header chain (RFC 7112)
+        return Err(ExtensionHeaderError::ParameterProblem(ICMPV6_CODE_INCOMPLETE_CHAIN, 0));
+      }
+      return Err(ExtensionHeaderError::ParameterProblem(ICMPV6_CODE_ERRONEOUS_HEADER, 4));
+    }
+
+    match nexthdr {
+      IPV6_NEXTHDR_HOP_BY_HOP => {
+        chain.router_alert = process_ipv6_options(ipv6slice, offset, offset + header_length, is_multicast)?;
+      },
+      IPV6_NEXTHDR_DEST_OPTIONS => {
+        process_ipv6_options(ipv6slice, offset, offset + header_length, is_multicast)?;
+      },
+      IPV6_NEXTHDR_ROUTING => {
+        // no routing type is supported. it can only be ignored once no segments are left.
+        if ipv6slice[offset+3] != 0 {
+          return Err(ExtensionHeaderError::ParameterProblem(ICMPV6_CODE_ERRONEOUS_HEADER, (offset + 2) as u32));
+        }
+      },
+      IPV6_NEXTHDR_FRAGMENT => {
+        if chain.fragment_header_offset.is_some() {
+          // nested fragmentation
+          return Err(ExtensionHeaderError::ParameterProblem(ICMPV6_CODE_UNRECOGNIZED_NEXTHDR, chain.upper_nexthdr_field as u32));
+        }
+        chain.fragment_header_offset = Some(offset);
+        chain.fragment_nexthdr_field = chain.upper_nexthdr_field;
+        if (ipv6slice[offset+2] as usize) << 8 | (ipv6slice[offset+3] as usize & 0xf8) != 0 {
+          // the rest of the chain is in the first fragment
+          chain.upper_protocol = ipv6slice[offset];
+          chain.upper_nexthdr_field = offset;
+          chain.upper_offset = offset + header_length;
+          return Ok(chain);
+        }
+        is_first_fragment = true;
+      },
+      _ => (),
+    }
+
+    chain.upper_protocol = ipv6slice[offset];
+    chain.upper_nexthdr_field = offset;
+    chain.upper_offset = offset + header_length;
+  }
+}
+
+const IPV6_REASSEMBLY_TIMEOUT: u64 = 60_000_000_000;
+const IPV6_REASSEMBLY_MAX_MEMORY: usize = 4 * 1024 * 1024;
+const IPV6_REASSEMBLY_MAX_DATAGRAMS: usize = 256;
+
+// keyed by source, destination and identification
+static IPV6_REASSEMBLY: Spinlock<Reassembler<(u128, u128, u32)>> = const_spinlock(Reassembler::new(
+  IPV6_REASSEMBLY_MAX_MEMORY, IPV6_REASSEMBLY_MAX_DATAGRAMS, IPV6_REASSEMBLY_TIMEOUT
+));
+
+// discard incomplete packets and notify their sources
+pub async fn expire_reassembly() {
+  loop {
+    TimerFuture::new(Duration::from_secs(1)).await;
+
+    let expired = IPV6_REASSEMBLY.lock().expire(get_monotonic_time());
+    for datagram in expired.iter() {
+      // only reported when the first fragment has arrived (RFC 8200 4.5)
+      if let (Some(header), Some(first_fragment)) = (datagram.get_header(), datagram.get_first_fragment()) {
+        let mut header = header.clone();
+        let payload_length = header.len() - 14 - 40 + first_fragment.len();
+        header[14+4] = (payload_length >> 8) as u8;
+        header[14+5] = payload_length as u8;
+        if let Some(frame) = build_datagram_frame(Arc::clone(datagram.get_netif()), &header, first_fragment) {
+          send_icmpv6_error(&frame, ICMPV6_TIME_EXCEEDED, ICMPV6_CODE_REASSEMBLY_EXCEEDED, [0; 4]);
+        }
+      }
+    }
+  }
+}
+
+// hash of the 5-tuple and the flow label to pick one of equal cost next hops.
+// fragments leave out the ports so that all of them take the same path.
+fn flow_hash(ipv6slice: &[u8]) -> u32 {
+  let ipv6_hdr = unsafe { &*((&ipv6slice[0] as *const _) as *const Ipv6Packet) };
+  let src_ip = Ipv6Address::from_array(ipv6_hdr.src_ip).get_prim();
+  let dest_ip = Ipv6Address::from_array(ipv6_hdr.dest_ip).get_prim();
+  let flow_label = ((ipv6_hdr.tcl_and_flh & 0x0f) as u32) << 16 | (ipv6_hdr.flm as u32) << 8 | ipv6_hdr.fll as u32;
+
+  let (protocol, ports) = match walk_extension_headers(ipv6slice, true) {
+    Ok(chain) => {
+      let offset = chain.get_upper_offset();
+      match chain.get_upper_protocol() {
+        6 | 17 | 132 if chain.get_fragment_header_offset().is_none() && offset + 4 <= ipv6slice.len() => {
+          let p = &ipv6slice[offset..(offset+4)];
+          (chain.get_upper_protocol(), (p[0] as u32) << 24 | (p[1] as u32) << 16 | (p[2] as u32) << 8 | p[3] as u32)
+        },
+        protocol => (protocol, 0),
+      }
+    },
+    Err(_) => (ipv6_hdr.nexthdr, 0),
+  };
+  calc_flow_hash(&[
+    (src_ip >> 96) as u32, (src_ip >> 64) as u32, (src_ip >> 32) as u32, src_ip as u32,
+    (dest_ip >> 96) as u32, (dest_ip >> 64) as u32, (dest_ip >> 32) as u32, dest_ip as u32,
+    flow_label,
+    protocol as u32,
+    ports,
+  ])
+}
+
 impl ProcessingNode for Ipv6In {
   fn process(&self, buff: &[DataFromNetif]) {
     let mut icmp_pkts = Vec::with_capacity(buff.len());
 
     for frame in buff.iter() {
+      if frame.get_buffer().get_length() < 14 + 40 {
+        // too short for an ip header. drop it.
+        continue;
+      }
       let slice = frame.get_buffer().slice();
       let ipv6_hdr = unsafe { &*((&slice[14] as *const _) as *const Ipv6Packet) };
       let dest_ip_addr = Ipv6Address::from_array(ipv6_hdr.dest_ip);
@@ -138,35 +829,102 @@ impl ProcessingNode for Ipv6In {
         continue;
       }
 
-      if let Some(fib) = find_ipv6_fib(&dest_ip_addr, 128) {
+      let length = 40 + ((ipv6_hdr.length[0] as usize) << 8 | ipv6_hdr.length[1] as usize);
+      if 14 + length > frame.get_buffer().get_length() {
+        // broken length field. drop it.
+        continue;
+      }
+      let ipv6slice = &slice[14..(14+length)];
+
+      // hop-by-hop options are examined by every node on the path
+      if ipv6_hdr.nexthdr == IPV6_NEXTHDR_HOP_BY_HOP {
+        match walk_extension_headers(ipv6slice, false) {
+          Ok(chain) => {
+            if chain.get_router_alert() == Some(IPV6_ROUTER_ALERT_MLD) {
+              // multicast listener discovery is for routers on the link. never forwarded.
+              if let Ok(local_chain) = walk_extension_headers(ipv6slice, true) {
+                if local_chain.get_upper_protocol() == 58 {
+                  icmp_pkts.push(frame.clone());
+                }
+              }
+              continue;
+            }
+          },
+          Err(err) => {
+            self.reject(frame, err);
+            continue;
+          },
+        }
+      }
+
+      if let Some(fib) = find_ipv6_fib(&dest_ip_addr, 128, flow_hash(ipv6slice)) {
         //println!("fib is found. {:?} mac={:?}", fib.get_nexthop_address().get_array(), fib.get_nexthop_macaddress().get_array());
         match fib.get_fib_type() {
           FIBType::Local => {
-            match ipv6_hdr.nexthdr {
-              58 => icmp_pkts.push(frame.clone()), //ICMP
-              _ => (),
+            let mut chain = match walk_extension_headers(ipv6slice, true) {
+              Ok(chain) => chain,
+              Err(err) => {
+                self.reject(frame, err);
+                continue;
+              },
+            };
+            let mut local_frame = frame.clone();
+            if chain.get_fragment_header_offset().is_some() {
+              local_frame = match self.reassemble(frame, &chain) {
+                Some(f) => f,
+                None => continue,
+              };
+              let local_slice = local_frame.get_buffer().slice();
+              chain = match walk_extension_headers(&local_slice[14..], true) {
+                Ok(chain) => chain,
+                Err(err) => {
+                  self.reject(&local_frame, err);
+                  continue;
+                },
+              };
+            }
+
+            match chain.get_upper_protocol() {
+              58 => icmp_pkts.push(local_frame), //ICMP
+              17 => send_icmpv6_error(&local_frame, ICMPV6_DEST_UNREACHABLE, ICMPV6_CODE_PORT_UNREACHABLE, [0; 4]), //UDP
+              59 => (), //No Next Header
+              _ => {
+                let pointer = chain.get_upper_nexthdr_field() as u32;
+                self.reject(&local_frame, ExtensionHeaderError::ParameterProblem(ICMPV6_CODE_UNRECOGNIZED_NEXTHDR, pointer));
+              },
             }
           },
           FIBType::Adjacent => {
-            // must resolve mac address using arp.
+            // must resolve mac address using neighbor discovery.
+            nd::enqueue_unresolved(frame.clone(), dest_ip_addr, fib);
           },
-          FIBType::AdjacentResolved => {
-            //forward
+          FIBType::RemoteUnresolved => {
+            // resolve the mac address of the gateway rather than of the destination.
+            nd::enqueue_unresolved(frame.clone(), fib.get_nexthop_address(), fib);
           },
-          FIBType::Remote => {
-            //forward
+          FIBType::AdjacentResolved | FIBType::Remote => {
+            self.forward(frame, fib);
+          },
+          FIBType::Blackhole => {
+            self.dropped_no_route.fetch_add(1, AtomicOrdering::Relaxed);
+          },
+          FIBType::Reject => {
+            self.dropped_no_route.fetch_add(1, AtomicOrdering::Relaxed);
+            send_icmpv6_error(frame, ICMPV6_DEST_UNREACHABLE, ICMPV6_CODE_REJECT_ROUTE, [0; 4]);
           },
         }
       } else {
         // fib not found. cannot handle this packet.
+        self.dropped_no_route.fetch_add(1, AtomicOrdering::Relaxed);
+        send_icmpv6_error(frame, ICMPV6_DEST_UNREACHABLE, ICMPV6_CODE_NO_ROUTE, [0; 4]);
       }
+      //println!("IPv6 payload={} nexthdr={}", (ipv6_hdr.length[0] as u16) << 8 | (ipv6_hdr.length[1] as u16), ipv6_hdr.nexthdr);
+    }
 
-      if icmp_pkts.len() > 0 {
-        if let Some(node_ref) = unsafe { PROC_NODES.get("icmpv6-in-local") } {
-          node_ref.process(&icmp_pkts);
-        }
+    if icmp_pkts.len() > 0 {
+      if let Some(node_ref) = unsafe { PROC_NODES.get("icmpv6-in-local") } {
+        node_ref.process(&icmp_pkts);
       }
-      //println!("IPv6 payload={} nexthdr={}", (ipv6_hdr.length[0] as u16) << 8 | (ipv6_hdr.length[1] as u16), ipv6_hdr.nexthdr);
     }
   }
 }
@@ -194,9 +952,22 @@ impl ProcessingNode for Icmpv6InLocal {
     for frame in buff.iter() {
       let slice = frame.get_buffer().slice();
       let ipv6_hdr = unsafe { &*((&slice[14] as *const _) as *const Ipv6Packet) };
-      let icmpv6_hdr = unsafe { &*((&slice[14+40] as *const _) as *const Icmpv6Packet) };
       let src_ip_addr = Ipv6Address::from_array(ipv6_hdr.src_ip);
       let dest_ip_addr = Ipv6Address::from_array(ipv6_hdr.dest_ip);
+      let length = 40 + ((ipv6_hdr.length[0] as usize) << 8 | ipv6_hdr.length[1] as usize);
+
+      if 14 + length > frame.get_buffer().get_length() {
+        // broken length field. drop it.
+        continue;
+      }
+
+      // find the icmpv6 header behind the extension headers
+      let icmp_offset = match walk_extension_headers(&slice[14..(14+length)], true) {
+        Ok(chain) if chain.get_upper_protocol() == 58 && chain.get_upper_offset() + 8 <= length => 14 + chain.get_upper_offset(),
+        _ => continue,
+      };
+      let icmp_length = 14 + length - icmp_offset;
+      let icmpv6_hdr = unsafe { &*((&slice[icmp_offset] as *const _) as *const Icmpv6Packet) };
 
       //println!("ICMPv6 Type={}", icmpv6_hdr.icmp_type);
       match icmpv6_hdr.icmp_type {
@@ -204,59 +975,28 @@ impl ProcessingNode for Icmpv6InLocal {
           //println!("ICMPv6 Echo Request");
           //Echo Request
           //reply
-          let length = (ipv6_hdr.length[0] as usize) << 8 | ipv6_hdr.length[1] as usize;
           let netif = Arc::clone(frame.get_netif());
-          let respbuff = netif.pre_xmit(14+40+length);
-          let respslice = respbuff.slice_mut();
-
-          let dest_mac = MacAddress::new(slice[6..12].try_into().unwrap());
-          generate_ether_header(&mut respslice[0..], *netif.get_macaddress(), dest_mac, [0x86, 0xdd]);
-          generate_ipv6_header(&mut respslice[14..], ipv6_hdr.length, 58, Ipv6Address::from_array([0x02; 16]), Ipv6Address::from_array(ipv6_hdr.src_ip));
-
-          let mut icmpv6_send_hdr = unsafe { &mut *((&mut respslice[14+40] as *mut _) as *mut Icmpv6Packet) };
-          icmpv6_send_hdr.icmp_type = 0x81;
-          icmpv6_send_hdr.code = icmpv6_hdr.code;
-          icmpv6_send_hdr.checksum = 0;
-          icmpv6_send_hdr.identifier = icmpv6_hdr.identifier;
-          icmpv6_send_hdr.sequence = icmpv6_hdr.sequence;
-          for i in (14+40+8)..(14+40+length) {
-            respslice[i] = slice[i];
+          if 40 + icmp_length > netif.get_mtu() {
+            // the reply of a reassembled request doesn't fit the link, and isn't fragmented.
+            continue;
           }
 
-          let mut csum_icmp: u32 = 0;
-          for i in 0..8 {
-            csum_icmp = csum_icmp + (0x2 << 8 | 0x02);
-          }
-          for i in 0..8 {
-            csum_icmp = csum_icmp + ((ipv6_hdr.src_ip[i*2] as u32) << 8 | ipv6_hdr.src_ip[i*2+1] as u32);
-          }
-          csum_icmp = csum_icmp + length as u32;
-          csum_icmp = csum_icmp + 58;
-
-          for i in ((14+40)/2)..((14+40+length)/2) {
-            csum_icmp = csum_icmp + ((respslice[i*2] as u32) << 8 | (respslice[i*2+1] as u32))
-          }
-          csum_icmp = (csum_icmp & 0x0000ffff) + (csum_icmp >> 16);
-          csum_icmp = (csum_icmp & 0x0000ffff) + (csum_icmp >> 16);
-          csum_icmp = !csum_icmp;
-          icmpv6_send_hdr.checksum = ((csum_icmp & 0xff) << 8 | (csum_icmp & 0xff00) >> 8) as u16;
+          // reply from the address the request was sent to, or from link-local if it was multicast.
+          let reply_src_ip = if dest_ip_addr.is_multicast() {
+            match find_ipv6_link_local_address(netif.get_id()) {
+              Some(addr) => addr,
+              None => continue,
+            }
+          } else {
+            dest_ip_addr
+          };
 
-          println!("ICMPv6 Echo Request {:08x}", csum_icmp);
-            
-          netif.xmit(respbuff);
-        },
-        /*
-        0x87 => {
-          //neighbor solicitation
-          //reply
-          let netif = Arc::clone(frame.get_netif());
-          let respbuff = netif.pre_xmit(14+40+32);
+          let respbuff = netif.pre_xmit(14+40+icmp_length);
           let respslice = respbuff.slice_mut();
 
           let dest_mac = MacAddress::new(slice[6..12].try_into().unwrap());
           generate_ether_header(&mut respslice[0..], *netif.get_macaddress(), dest_mac, [0x86, 0xdd]);
-          //todo: source ip
-          generate_ipv6_header(&mut respslice[14..], 32, 58, Ipv6Address::from_array([0x02; 16]), Ipv6Address::from_array(ipv6_hdr.src_ip));
+          generate_ipv6_header(&mut respslice[14..], [(icmp_length >> 8) as u8, icmp_length as u8], 58, reply_src_ip, src_ip_addr);
 
           let mut icmpv6_send_hdr = unsafe { &mut *((&mut respslice[14+40] as *mut _) as *mut Icmpv6Packet) };
           icmpv6_send_hdr.icmp_type = 0x81;
@@ -264,54 +1004,25 @@ impl ProcessingNode for Icmpv6InLocal {
           icmpv6_send_hdr.checksum = 0;
           icmpv6_send_hdr.identifier = icmpv6_hdr.identifier;
           icmpv6_send_hdr.sequence = icmpv6_hdr.sequence;
-          for i in (14+40+8)..(14+40+length) {
-            respslice[i] = slice[i];
-          }
+          respslice[(14+40+8)..(14+40+icmp_length)].copy_from_slice(&slice[(icmp_offset+8)..(14+length)]);
 
-          let mut csum_icmp: u32 = 0;
-          for i in 0..8 {
-            csum_icmp = csum_icmp + (0x2 << 8 | 0x02);
-          }
-          for i in 0..8 {
-            csum_icmp = csum_icmp + ((ipv6_hdr.src_ip[i*2] as u32) << 8 | ipv6_hdr.src_ip[i*2+1] as u32);
-          }
-          csum_icmp = csum_icmp + length as u32;
-          csum_icmp = csum_icmp + 58;
-
-          for i in ((14+40)/2)..((14+40+length)/2) {
-            csum_icmp = csum_icmp + ((respslice[i*2] as u32) << 8 | (respslice[i*2+1] as u32))
-          }
-          csum_icmp = (csum_icmp & 0x0000ffff) + (csum_icmp >> 16);
-          csum_icmp = (csum_icmp & 0x0000ffff) + (csum_icmp >> 16);
-          csum_icmp = !csum_icmp;
-          icmpv6_send_hdr.checksum = ((csum_icmp & 0xff) << 8 | (csum_icmp & 0xff00) >> 8) as u16;
+          let csum_icmp = calc_icmpv6_checksum(&reply_src_ip, &src_ip_addr, &respslice[(14+40)..(14+40+icmp_length)]);
+          respslice[14+40+2] = (csum_icmp >> 8) as u8;
+          respslice[14+40+3] = csum_icmp as u8;
 
-          println!("ICMPv6 Echo Request {:08x}", csum_icmp);
-            
           netif.xmit(respbuff);
         },
-        */
+        0x81 => {
+          //echo reply to our ping
+          ping::notify_echo_reply(u16::from_be(icmpv6_hdr.identifier), u16::from_be(icmpv6_hdr.sequence));
+        },
+        0x87 => {
+          //neighbor solicitation
+          nd::process_neighbor_solicitation(frame, src_ip_addr, &slice[icmp_offset..(14+length)], ipv6_hdr.hoplimit);
+        },
         0x88 => {
           //neighbor advertisement
-          //register source to fib and adj
-          let flags = &slice[14+40+8..14+40+12];
-          let src_mac = MacAddress::new([
-            slice[14+40+30],
-            slice[14+40+31],
-            slice[14+40+32],
-            slice[14+40+33],
-            slice[14+40+34],
-            slice[14+40+35],
-          ]);
-          let src_ip = Ipv6Address::from_array([
-            slice[14+40+12], slice[14+40+13], slice[14+40+14], slice[14+40+15], slice[14+40+16], slice[14+40+17], slice[14+40+18], slice[14+40+19],
-            slice[14+40+20], slice[14+40+21], slice[14+40+22], slice[14+40+23], slice[14+40+24], slice[14+40+25], slice[14+40+26], slice[14+40+27],
-          ]);
-
-          register_ipv6_fib(src_ip, 0xffffffff, 
-            src_mac, src_ip, Arc::clone(frame.get_netif()), FIBType::AdjacentResolved
-          );
-          register_ipv6_adjacent(src_ip, src_mac, Arc::clone(frame.get_netif()), false, None);
+          nd::process_neighbor_advertisement(frame, &slice[icmp_offset..(14+length)], ipv6_hdr.hoplimit);
         },
         _ => (),
       }
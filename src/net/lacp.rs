@@ -0,0 +1,351 @@
+// link aggregation control protocol (IEEE 802.3ad, now 802.1AX) on the members of a bond.
+// LACP is active with the short timeout: LACPDUs are sent every second, and a member whose partner
+// says nothing for three seconds leaves the aggregation until it speaks again.
+//
+// the bond aggregates the members whose partners are the same system with the same key, the one of the
+// first member which has a partner. a selected member is in sync, and it collects and distributes
+// once its partner is in sync too. the bond transmits through the members which are distributing and
+// whose partners are collecting.
+
+use core::time::Duration;
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::spinlock::{Spinlock, const_spinlock};
+use crate::arch::x86_64::kvmclock::get_monotonic_time;
+use crate::asynchronous::timer::TimerFuture;
+use crate::devices::netif::Netif;
+use crate::devices::bond::BondNetif;
+use crate::net::DataFromNetif;
+use crate::net::ethernet::MacAddress;
+
+pub const LACP_MACADDRESS: [u8; 6] = [0x01, 0x80, 0xc2, 0x00, 0x00, 0x02]; // slow protocols
+pub const ETHERTYPE_SLOW_PROTOCOLS: [u8; 2] = [0x88, 0x09];
+pub const SUBTYPE_LACP: u8 = 0x01;
+
+const LACPDU_LENGTH: usize = 110;
+
+const SYSTEM_PRIORITY: u16 = 32768;
+const PORT_PRIORITY: u16 = 32768;
+
+const PERIODIC_TIME: Duration = Duration::from_secs(1);
+const SHORT_TIMEOUT: u64 = 3_000_000_000; // nanoseconds
+
+pub const STATE_ACTIVITY: u8 = 0x01;
+pub const STATE_TIMEOUT: u8 = 0x02;
+pub const STATE_AGGREGATION: u8 = 0x04;
+pub const STATE_SYNCHRONIZATION: u8 = 0x08;
+pub const STATE_COLLECTING: u8 = 0x10;
+pub const STATE_DISTRIBUTING: u8 = 0x20;
+pub const STATE_DEFAULTED: u8 = 0x40;
+pub const STATE_EXPIRED: u8 = 0x80;
+
+const STATE_NAMES: [&str; 8] = ["active", "short-timeout", "aggregatable", "in-sync", "collecting", "distributing", "defaulted", "expired"];
+
+// names of the bits set in a state
+pub fn get_state_names(state: u8) -> Vec<&'static str> {
+  STATE_NAMES.iter().enumerate().filter(|(i, _)| (state & (1 << i)) != 0).map(|(_, name)| *name).collect()
+}
+
+// the actor or the partner information of a LACPDU
+#[derive(Copy, Clone, PartialEq)]
+pub struct LacpInfo {
+  system_priority: u16,
+  system: MacAddress,
+  key: u16,
+  port_priority: u16,
+  port: u16,
+  state: u8,
+}
+
+impl LacpInfo {
+  fn parse(bytes: &[u8]) -> LacpInfo {
+    let mut system = [0u8; 6];
+    system.copy_from_slice(&bytes[2..8]);
+    LacpInfo {
+      system_priority: (bytes[0] as u16) << 8 | bytes[1] as u16,
+      system: MacAddress::new(system),
+      key: (bytes[8] as u16) << 8 | bytes[9] as u16,
+      port_priority: (bytes[10] as u16) << 8 | bytes[11] as u16,
+      port: (bytes[12] as u16) << 8 | bytes[13] as u16,
+      state: bytes[14],
+    }
+  }
+
+  fn write(&self, bytes: &mut [u8]) {
+    bytes[0..2].copy_from_slice(&self.system_priority.to_be_bytes());
+    bytes[2..8].copy_from_slice(&self.system.get_array());
+    bytes[8..10].copy_from_slice(&self.key.to_be_bytes());
+    bytes[10..12].copy_from_slice(&self.port_priority.to_be_bytes());
+    bytes[12..14].copy_from_slice(&self.port.to_be_bytes());
+    bytes[14] = self.state;
+  }
+
+  // the same port of the same aggregation, whatever the state
+  fn is_same_port(&self, other: &LacpInfo) -> bool {
+    self.system_priority == other.system_priority && self.system == other.system && self.key == other.key
+      && self.port_priority == other.port_priority && self.port == other.port
+  }
+
+  pub fn get_system_priority(&self) -> u16 {
+    self.system_priority
+  }
+
+  pub fn get_system(&self) -> MacAddress {
+    self.system
+  }
+
+  pub fn get_key(&self) -> u16 {
+    self.key
+  }
+
+  pub fn get_port(&self) -> u16 {
+    self.port
+  }
+
+  pub fn get_state(&self) -> u8 {
+    self.state
+  }
+}
+
+#[derive(Clone)]
+pub struct LacpMember {
+  netif: Arc<dyn Netif>,
+  actor: LacpInfo,
+  partner: Option<LacpInfo>,
+  partner_expire: u64,
+  partner_agrees: bool, // the partner knows us as we are
+  selected: bool,
+  ntt: bool, // need to transmit
+}
+
+impl LacpMember {
+  pub fn get_netif(&self) -> &Arc<dyn Netif> {
+    &self.netif
+  }
+
+  pub fn get_actor(&self) -> &LacpInfo {
+    &self.actor
+  }
+
+  pub fn get_partner(&self) -> Option<&LacpInfo> {
+    self.partner.as_ref()
+  }
+
+  pub fn is_selected(&self) -> bool {
+    self.selected
+  }
+
+  fn is_active(&self) -> bool {
+    let partner_collecting = self.partner.map(|partner| (partner.state & STATE_COLLECTING) != 0).unwrap_or(false);
+    (self.actor.state & STATE_DISTRIBUTING) != 0 && partner_collecting
+  }
+}
+
+#[derive(Clone)]
+pub struct LacpBond {
+  bond: Arc<BondNetif>,
+  members: Vec<LacpMember>,
+}
+
+impl LacpBond {
+  fn new(bond: Arc<BondNetif>) -> LacpBond {
+    let system = *bond.get_macaddress();
+    let key = bond.get_id() as u16;
+    let members = bond.get_members().iter().map(|netif| LacpMember {
+      netif: Arc::clone(netif),
+      actor: LacpInfo {
+        system_priority: SYSTEM_PRIORITY,
+        system: system,
+        key: key,
+        port_priority: PORT_PRIORITY,
+        port: (netif.get_id() + 1) as u16,
+        state: STATE_ACTIVITY | STATE_TIMEOUT | STATE_AGGREGATION | STATE_DEFAULTED,
+      },
+      partner: None,
+      partner_expire: 0,
+      partner_agrees: false,
+      selected: false,
+      ntt: true,
+    }).collect();
+    LacpBond { bond: bond, members: members }
+  }
+
+  pub fn get_bond(&self) -> &Arc<BondNetif> {
+    &self.bond
+  }
+
+  pub fn get_members(&self) -> &Vec<LacpMember> {
+    &self.members
+  }
+
+  // select the members, derive their states, and tell the bond which members carry frames
+  fn update(&mut self, now: u64) {
+    for member in self.members.iter_mut() {
+      if member.partner.is_some() && now >= member.partner_expire {
+        member.partner = None;
+        member.partner_agrees = false;
+      }
+    }
+
+    let aggregation = self.members.iter()
+      .filter_map(|member| member.partner)
+      .find(|partner| (partner.state & STATE_AGGREGATION) != 0)
+      .map(|partner| (partner.system_priority, partner.system, partner.key));
+
+    for member in self.members.iter_mut() {
+      member.selected = match (member.partner, aggregation) {
+        (Some(partner), Some(aggregation)) => (partner.state & STATE_AGGREGATION) != 0 && (partner.system_priority, partner.system, partner.key) == aggregation,
+        _ => false,
+      };
+
+      let mut state = STATE_ACTIVITY | STATE_TIMEOUT | STATE_AGGREGATION;
+      if member.partner.is_none() {
+        state |= STATE_DEFAULTED;
+      }
+      if member.selected {
+        state |= STATE_SYNCHRONIZATION;
+        let partner_in_sync = member.partner.map(|partner| (partner.state & STATE_SYNCHRONIZATION) != 0).unwrap_or(false);
+        if partner_in_sync && member.partner_agrees {
+          state |= STATE_COLLECTING | STATE_DISTRIBUTING;
+        }
+      }
+      if state != member.actor.state {
+        member.actor.state = state;
+        member.ntt = true;
+      }
+
+      let active = member.is_active();
+      if active != self.bond.is_active(member.netif.get_id()) {
+        self.bond.set_active(member.netif.get_id(), active);
+      }
+    }
+  }
+
+  fn build_lacpdu(&self, i: usize) -> [u8; LACPDU_LENGTH] {
+    let member = &self.members[i];
+    let mut lacpdu = [0u8; LACPDU_LENGTH];
+    lacpdu[0] = SUBTYPE_LACP;
+    lacpdu[1] = 0x01; // version
+    lacpdu[2] = 0x01; // actor information
+    lacpdu[3] = 20;
+    member.actor.write(&mut lacpdu[4..22]);
+    lacpdu[22] = 0x02; // partner information
+    lacpdu[23] = 20;
+    if let Some(partner) = member.partner {
+      partner.write(&mut lacpdu[24..42]);
+    }
+    lacpdu[42] = 0x03; // collector information
+    lacpdu[43] = 16;
+    // the maximum delay and the terminator are zero
+    lacpdu
+  }
+
+  // the members which have something new to tell, or all of them
+  fn transmit(&mut self, all: bool, lacpdus: &mut Vec<(Arc<dyn Netif>, [u8; LACPDU_LENGTH])>) {
+    for i in 0..self.members.len() {
+      if all || self.members[i].ntt {
+        lacpdus.push((Arc::clone(&self.members[i].netif), self.build_lacpdu(i)));
+        self.members[i].ntt = false;
+      }
+    }
+  }
+
+  fn receive(&mut self, i: usize, actor: LacpInfo, partner: LacpInfo, now: u64) {
+    let member = &mut self.members[i];
+    member.partner = Some(actor);
+    member.partner_expire = now + SHORT_TIMEOUT;
+    member.partner_agrees = partner.is_same_port(&member.actor);
+    if !member.partner_agrees || (partner.state & !STATE_EXPIRED) != (member.actor.state & !STATE_EXPIRED) {
+      // what the partner thinks of us is out of date
+      member.ntt = true;
+    }
+    self.update(now);
+  }
+}
+
+// by bond interface id
+static LACP_BONDS: Spinlock<BTreeMap<usize, LacpBond>> = const_spinlock(BTreeMap::new());
+
+// the actor and the partner information of a LACPDU of a frame
+fn parse_lacpdu(slice: &[u8], length: usize) -> Option<(LacpInfo, LacpInfo)> {
+  if length < 14 + LACPDU_LENGTH || slice[12..14] != ETHERTYPE_SLOW_PROTOCOLS {
+    return None;
+  }
+  let lacpdu = &slice[14..(14 + LACPDU_LENGTH)];
+  if lacpdu[0] != SUBTYPE_LACP || lacpdu[2] != 0x01 || lacpdu[3] != 20 || lacpdu[22] != 0x02 || lacpdu[23] != 20 {
+    return None;
+  }
+  Some((LacpInfo::parse(&lacpdu[4..22]), LacpInfo::parse(&lacpdu[24..42])))
+}
+
+fn send_lacpdu(netif: &Arc<dyn Netif>, lacpdu: &[u8; LACPDU_LENGTH]) {
+  let length = 14 + LACPDU_LENGTH;
+  let buffer = netif.pre_xmit(length);
+  let slice = buffer.slice_mut();
+  slice[0..6].copy_from_slice(&LACP_MACADDRESS);
+  slice[6..12].copy_from_slice(&netif.get_macaddress().get_array());
+  slice[12..14].copy_from_slice(&ETHERTYPE_SLOW_PROTOCOLS);
+  slice[14..length].copy_from_slice(lacpdu);
+  let _ = netif.xmit(buffer);
+}
+
+// negotiate the members of the bond. no member carries frames before its partner agrees.
+pub fn enable(bond: Arc<BondNetif>) {
+  let mut lacpdus = Vec::new();
+  {
+    let mut lacp_bond = LacpBond::new(bond);
+    lacp_bond.transmit(true, &mut lacpdus);
+    LACP_BONDS.lock().insert(lacp_bond.bond.get_id(), lacp_bond);
+  }
+  for (netif, lacpdu) in lacpdus.iter() {
+    send_lacpdu(netif, lacpdu);
+  }
+}
+
+// a LACPDU received on a member of the bond
+pub fn receive(bond_id: usize, frame: &DataFromNetif) {
+  let buffer = frame.get_buffer();
+  let (actor, partner) = match parse_lacpdu(buffer.slice(), buffer.get_length()) {
+    Some(info) => info,
+    None => return,
+  };
+
+  let netif_id = frame.get_netif().get_id();
+  let mut lacpdus = Vec::new();
+  {
+    let mut lacp_bonds = LACP_BONDS.lock();
+    if let Some(lacp_bond) = lacp_bonds.get_mut(&bond_id) {
+      if let Some(i) = lacp_bond.members.iter().position(|member| member.netif.get_id() == netif_id) {
+        lacp_bond.receive(i, actor, partner, get_monotonic_time());
+        lacp_bond.transmit(false, &mut lacpdus);
+      }
+    }
+  }
+  for (netif, lacpdu) in lacpdus.iter() {
+    send_lacpdu(netif, lacpdu);
+  }
+}
+
+pub fn get_lacp_bonds() -> Vec<LacpBond> {
+  LACP_BONDS.lock().values().map(|lacp_bond| lacp_bond.clone()).collect()
+}
+
+// periodic LACPDUs, and members whose partners went silent
+pub async fn periodic_transmission() {
+  loop {
+    TimerFuture::new(PERIODIC_TIME).await;
+
+    let now = get_monotonic_time();
+    let mut lacpdus = Vec::new();
+    for lacp_bond in LACP_BONDS.lock().values_mut() {
+      lacp_bond.update(now);
+      lacp_bond.transmit(true, &mut lacpdus);
+    }
+    for (netif, lacpdu) in lacpdus.iter() {
+      send_lacpdu(netif, lacpdu);
+    }
+  }
+}
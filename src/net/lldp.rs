@@ -0,0 +1,364 @@
+// link layer discovery protocol (IEEE 802.1AB).
+// every interface but the null one and bonds, whose members speak for them, sends an LLDPDU every 30 seconds
+// with the chassis id, the port id, the system name and the management addresses of the interface, or of its bond.
+// LLDPDUs received on an interface make its neighbor table, and neighbors are forgotten when their TTL runs out.
+//
+//   urchin.hostname=<system name>
+//
+// the chassis id is the mac address of the first interface, and the port id is the interface name.
+
+use core::fmt;
+use core::time::Duration;
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::spinlock::{Spinlock, const_spinlock};
+use crate::arch::x86_64::kvmclock::get_monotonic_time;
+use crate::asynchronous::timer::TimerFuture;
+use crate::devices::netif::Netif;
+use crate::net::DataFromNetif;
+use crate::net::ethernet::MacAddress;
+use crate::net::ipv4::Ipv4Address;
+use crate::net::ipv6::Ipv6Address;
+use crate::net::address;
+use crate::net::bond;
+use crate::cmdline::Cmdline;
+use crate::NET_IFACES;
+
+pub const LLDP_MACADDRESS: [u8; 6] = [0x01, 0x80, 0xc2, 0x00, 0x00, 0x0e]; // nearest bridge
+pub const ETHERTYPE_LLDP: [u8; 2] = [0x88, 0xcc];
+
+const TX_INTERVAL: u64 = 30; // seconds
+const TX_HOLD: u64 = 4;
+const TICK: Duration = Duration::from_secs(1);
+const MAX_NEIGHBORS: usize = 256;
+const DEFAULT_SYSTEM_NAME: &str = "urchin";
+
+const TLV_END: u8 = 0;
+const TLV_CHASSIS_ID: u8 = 1;
+const TLV_PORT_ID: u8 = 2;
+const TLV_TTL: u8 = 3;
+const TLV_PORT_DESCRIPTION: u8 = 4;
+const TLV_SYSTEM_NAME: u8 = 5;
+const TLV_MANAGEMENT_ADDRESS: u8 = 8;
+
+const CHASSIS_SUBTYPE_MACADDRESS: u8 = 4;
+const CHASSIS_SUBTYPE_ADDRESS: u8 = 5;
+const PORT_SUBTYPE_MACADDRESS: u8 = 3;
+const PORT_SUBTYPE_ADDRESS: u8 = 4;
+const PORT_SUBTYPE_NAME: u8 = 5;
+
+const ADDRESS_FAMILY_IPV4: u8 = 1;
+const ADDRESS_FAMILY_IPV6: u8 = 2;
+const IFNUMBERING_IFINDEX: u8 = 2;
+
+static SYSTEM_NAME: Spinlock<Option<String>> = const_spinlock(None);
+
+// a chassis id or a port id
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LldpId {
+  MacAddress(MacAddress),
+  Address(String),
+  Text(String),
+  Other(u8, Vec<u8>), // subtype and value
+}
+
+impl LldpId {
+  fn parse(value: &[u8], mac_subtype: u8, address_subtype: u8) -> LldpId {
+    let (subtype, id) = (value[0], &value[1..]);
+    if subtype == mac_subtype && id.len() == 6 {
+      let mut macaddr = [0u8; 6];
+      macaddr.copy_from_slice(id);
+      return LldpId::MacAddress(MacAddress::new(macaddr));
+    }
+    if subtype == address_subtype {
+      if let Some(addr) = format_address(id) {
+        return LldpId::Address(addr);
+      }
+    }
+    match core::str::from_utf8(id) {
+      Ok(text) if text.chars().all(|c| !c.is_control()) => LldpId::Text(String::from(text)),
+      _ => LldpId::Other(subtype, id.to_vec()),
+    }
+  }
+}
+
+impl fmt::Display for LldpId {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      LldpId::MacAddress(macaddr) => write!(f, "{}", macaddr),
+      LldpId::Address(addr) | LldpId::Text(addr) => write!(f, "{}", addr),
+      LldpId::Other(subtype, id) => {
+        write!(f, "{}:", subtype)?;
+        for octet in id.iter() {
+          write!(f, "{:02x}", octet)?;
+        }
+        Ok(())
+      },
+    }
+  }
+}
+
+// an address family number and an address
+fn format_address(value: &[u8]) -> Option<String> {
+  match (value.get(0).map(|family| *family), value.len()) {
+    (Some(ADDRESS_FAMILY_IPV4), 5) => {
+      let mut addr = [0u8; 4];
+      addr.copy_from_slice(&value[1..]);
+      Some(format!("{}", Ipv4Address::from_array(addr)))
+    },
+    (Some(ADDRESS_FAMILY_IPV6), 17) => {
+      let mut addr = [0u8; 16];
+      addr.copy_from_slice(&value[1..]);
+      Some(format!("{}", Ipv6Address::from_array(addr)))
+    },
+    _ => None,
+  }
+}
+
+#[derive(Clone)]
+pub struct LldpNeighbor {
+  netif_id: usize,
+  chassis_id: LldpId,
+  port_id: LldpId,
+  system_name: Option<String>,
+  port_description: Option<String>,
+  management_addresses: Vec<String>,
+  expire_time: u64,
+}
+
+impl LldpNeighbor {
+  pub fn get_netif_id(&self) -> usize {
+    self.netif_id
+  }
+
+  pub fn get_chassis_id(&self) -> &LldpId {
+    &self.chassis_id
+  }
+
+  pub fn get_port_id(&self) -> &LldpId {
+    &self.port_id
+  }
+
+  pub fn get_system_name(&self) -> Option<&str> {
+    self.system_name.as_ref().map(|name| name.as_str())
+  }
+
+  pub fn get_port_description(&self) -> Option<&str> {
+    self.port_description.as_ref().map(|description| description.as_str())
+  }
+
+  pub fn get_management_addresses(&self) -> &Vec<String> {
+    &self.management_addresses
+  }
+
+  pub fn get_expire_time(&self) -> u64 {
+    self.expire_time
+  }
+}
+
+// by interface, chassis id and port id
+static NEIGHBORS: Spinlock<BTreeMap<(usize, LldpId, LldpId), LldpNeighbor>> = const_spinlock(BTreeMap::new());
+
+pub fn get_neighbors() -> Vec<LldpNeighbor> {
+  NEIGHBORS.lock().values().map(|neighbor| neighbor.clone()).collect()
+}
+
+pub fn get_system_name() -> String {
+  SYSTEM_NAME.lock().clone().unwrap_or(String::from(DEFAULT_SYSTEM_NAME))
+}
+
+fn push_tlv(lldpdu: &mut Vec<u8>, tlv_type: u8, value: &[u8]) {
+  lldpdu.push((tlv_type << 1) | ((value.len() >> 8) as u8 & 0x01));
+  lldpdu.push(value.len() as u8);
+  lldpdu.extend_from_slice(value);
+}
+
+fn push_management_address(lldpdu: &mut Vec<u8>, family: u8, addr: &[u8], netif_id: usize) {
+  let mut value = Vec::with_capacity(2 + addr.len() + 7);
+  value.push(1 + addr.len() as u8);
+  value.push(family);
+  value.extend_from_slice(addr);
+  value.push(IFNUMBERING_IFINDEX);
+  value.extend_from_slice(&(netif_id as u32 + 1).to_be_bytes());
+  value.push(0); // no object identifier
+  push_tlv(lldpdu, TLV_MANAGEMENT_ADDRESS, &value);
+}
+
+fn build_lldpdu(netif: &Arc<dyn Netif>, chassis_id: &MacAddress, system_name: &str, ttl: u16) -> Vec<u8> {
+  let mut lldpdu = Vec::new();
+  let mut chassis = [CHASSIS_SUBTYPE_MACADDRESS; 7];
+  chassis[1..].copy_from_slice(&chassis_id.get_array());
+  push_tlv(&mut lldpdu, TLV_CHASSIS_ID, &chassis);
+  let mut port = Vec::from([PORT_SUBTYPE_NAME]);
+  port.extend_from_slice(format!("eth{}", netif.get_id()).as_bytes());
+  push_tlv(&mut lldpdu, TLV_PORT_ID, &port);
+  push_tlv(&mut lldpdu, TLV_TTL, &ttl.to_be_bytes());
+  push_tlv(&mut lldpdu, TLV_PORT_DESCRIPTION, netif.get_drivername().as_bytes());
+  let name = system_name.as_bytes();
+  push_tlv(&mut lldpdu, TLV_SYSTEM_NAME, &name[..name.len().min(255)]);
+
+  // members of a bond are reached at the addresses of the bond
+  let addressed_id = bond::find_member_bond(netif.get_id()).map(|bond| bond.get_id()).unwrap_or(netif.get_id());
+  for a in address::get_ipv4_addresses(addressed_id).iter() {
+    push_management_address(&mut lldpdu, ADDRESS_FAMILY_IPV4, &a.get_address().get_array(), addressed_id);
+  }
+  for a in address::get_ipv6_addresses(addressed_id).iter().filter(|a| !a.get_address().is_link_local()) {
+    push_management_address(&mut lldpdu, ADDRESS_FAMILY_IPV6, &a.get_address().get_array(), addressed_id);
+  }
+  push_tlv(&mut lldpdu, TLV_END, &[]);
+  lldpdu
+}
+
+fn send_lldpdu(netif: &Arc<dyn Netif>, lldpdu: &[u8]) {
+  let length = 14 + lldpdu.len();
+  if lldpdu.len() > netif.get_mtu() {
+    return;
+  }
+  let buffer = netif.pre_xmit(length.max(60));
+  let slice = buffer.slice_mut();
+  slice[0..6].copy_from_slice(&LLDP_MACADDRESS);
+  slice[6..12].copy_from_slice(&netif.get_macaddress().get_array());
+  slice[12..14].copy_from_slice(&ETHERTYPE_LLDP);
+  slice[14..length].copy_from_slice(lldpdu);
+  for b in slice[length..60.max(length)].iter_mut() {
+    *b = 0;
+  }
+  let _ = netif.xmit(buffer);
+}
+
+// LLDPDUs on every interface which speaks LLDP
+fn transmit(ttl: u16) {
+  let netifs: Vec<Arc<dyn Netif>> = unsafe { NET_IFACES.iter() }
+    .filter(|netif| netif.get_drivername() != "null" && netif.get_drivername() != "bond")
+    .map(|netif| Arc::clone(netif))
+    .collect();
+  let chassis_id = match netifs.first() {
+    Some(netif) => *netif.get_macaddress(),
+    None => return,
+  };
+  let system_name = get_system_name();
+  for netif in netifs.iter() {
+    let lldpdu = build_lldpdu(netif, &chassis_id, &system_name, ttl);
+    send_lldpdu(netif, &lldpdu);
+  }
+}
+
+// the TLVs of an LLDPDU. the first three are the chassis id, the port id and the TTL.
+fn parse_tlvs(lldpdu: &[u8]) -> Option<Vec<(u8, &[u8])>> {
+  let mut tlvs = Vec::new();
+  let mut pos = 0;
+  while pos + 2 <= lldpdu.len() {
+    let tlv_type = lldpdu[pos] >> 1;
+    let length = ((lldpdu[pos] as usize & 0x01) << 8) | lldpdu[pos + 1] as usize;
+    pos += 2;
+    if pos + length > lldpdu.len() {
+      return None;
+    }
+    if tlv_type == TLV_END {
+      break;
+    }
+    tlvs.push((tlv_type, &lldpdu[pos..(pos + length)]));
+    pos += length;
+  }
+  match tlvs.as_slice() {
+    [(TLV_CHASSIS_ID, chassis), (TLV_PORT_ID, port), (TLV_TTL, ttl), ..] if chassis.len() >= 2 && port.len() >= 2 && ttl.len() >= 2 => Some(tlvs),
+    _ => None,
+  }
+}
+
+fn parse_text(value: &[u8]) -> Option<String> {
+  core::str::from_utf8(value).ok().map(|text| String::from(text))
+}
+
+pub fn is_lldpdu(frame: &DataFromNetif) -> bool {
+  let slice = frame.get_buffer().slice();
+  frame.get_buffer().get_length() >= 14 && slice[0..6] == LLDP_MACADDRESS && slice[12..14] == ETHERTYPE_LLDP
+}
+
+// an LLDPDU received on an interface
+pub fn receive(frame: &DataFromNetif) {
+  let buffer = frame.get_buffer();
+  let length = buffer.get_length();
+  if length < 14 {
+    return;
+  }
+  let tlvs = match parse_tlvs(&buffer.slice()[14..length]) {
+    Some(tlvs) => tlvs,
+    None => return,
+  };
+
+  let netif_id = frame.get_netif().get_id();
+  let chassis_id = LldpId::parse(tlvs[0].1, CHASSIS_SUBTYPE_MACADDRESS, CHASSIS_SUBTYPE_ADDRESS);
+  let port_id = LldpId::parse(tlvs[1].1, PORT_SUBTYPE_MACADDRESS, PORT_SUBTYPE_ADDRESS);
+  let ttl = (tlvs[2].1[0] as u64) << 8 | tlvs[2].1[1] as u64;
+  let key = (netif_id, chassis_id.clone(), port_id.clone());
+
+  let mut neighbors = NEIGHBORS.lock();
+  if ttl == 0 {
+    // the neighbor is shutting down
+    neighbors.remove(&key);
+    return;
+  }
+  if !neighbors.contains_key(&key) && neighbors.len() >= MAX_NEIGHBORS {
+    return;
+  }
+
+  let mut neighbor = LldpNeighbor {
+    netif_id: netif_id,
+    chassis_id: chassis_id,
+    port_id: port_id,
+    system_name: None,
+    port_description: None,
+    management_addresses: Vec::new(),
+    expire_time: get_monotonic_time() + ttl * 1_000_000_000,
+  };
+  for (tlv_type, value) in tlvs[3..].iter() {
+    match *tlv_type {
+      TLV_SYSTEM_NAME => neighbor.system_name = parse_text(value),
+      TLV_PORT_DESCRIPTION => neighbor.port_description = parse_text(value),
+      TLV_MANAGEMENT_ADDRESS if value.len() >= 2 => {
+        let addr_length = value[0] as usize;
+        if let Some(addr) = value.get(1..(1 + addr_length)).and_then(format_address) {
+          neighbor.management_addresses.push(addr);
+        }
+      },
+      _ => (),
+    }
+  }
+  neighbors.insert(key, neighbor);
+}
+
+pub fn set_system_name(name: &str) -> Result<(), &'static str> {
+  if name.len() == 0 || name.len() > 255 {
+    return Err("system name must be 1 to 255 bytes");
+  }
+  *SYSTEM_NAME.lock() = Some(String::from(name));
+  Ok(())
+}
+
+// the system name of urchin.hostname=
+pub fn configure_system_name(cmdline: &Cmdline) {
+  if let Some(option) = cmdline.get("urchin.hostname") {
+    option.parse_value(set_system_name);
+  }
+}
+
+// periodic LLDPDUs, and neighbors whose TTL ran out
+pub async fn lldp() {
+  let mut next_transmit = 0;
+  loop {
+    TimerFuture::new(TICK).await;
+
+    let now = get_monotonic_time();
+    NEIGHBORS.lock().retain(|_, neighbor| neighbor.expire_time >= now);
+    if now >= next_transmit {
+      next_transmit = now + TX_INTERVAL * 1_000_000_000;
+      transmit((TX_INTERVAL * TX_HOLD) as u16);
+    }
+  }
+}
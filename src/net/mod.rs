@@ -3,6 +3,18 @@ pub mod arp;
 pub mod ipv4;
 pub mod ipv6;
 pub mod fib;
+pub mod rib;
+pub mod address;
+pub mod bond;
+pub mod bridge;
+pub mod lacp;
+pub mod lldp;
+pub mod rstp;
+pub mod vlan;
+pub mod static_route;
+pub mod ping;
+pub mod nd;
+pub mod reassembly;
 
 use core::future::Future;
 
@@ -40,3 +52,20 @@ pub trait ProcessingNode {
   fn process(&self, buff: &[DataFromNetif]);
   fn proc(&self) -> impl Future<Output = ()>;
 }
+
+// processing nodes which can be enabled, by name
+pub const PROCESSING_NODE_NAMES: [&str; 6] = ["ethernet-in", "arp-in", "ipv4-in", "icmpv4-in-local", "ipv6-in", "icmpv6-in-local"];
+
+pub fn create_processing_node(name: &str) -> Option<(&'static str, Arc<dyn ProcessingNode>)> {
+  let name = *PROCESSING_NODE_NAMES.iter().find(|n| **n == name)?;
+  let node: Arc<dyn ProcessingNode> = match name {
+    "ethernet-in" => Arc::new(ethernet::EthernetIn::new()),
+    "arp-in" => Arc::new(arp::ArpIn::new()),
+    "ipv4-in" => Arc::new(ipv4::Ipv4In::new()),
+    "icmpv4-in-local" => Arc::new(ipv4::Icmpv4InLocal::new()),
+    "ipv6-in" => Arc::new(ipv6::Ipv6In::new()),
+    "icmpv6-in-local" => Arc::new(ipv6::Icmpv6InLocal::new()),
+    _ => return None,
+  };
+  Some((name, node))
+}
@@ -0,0 +1,382 @@
+// IPv6 Neighbor Discovery (RFC 4861)
+
+use core::convert::TryInto;
+use core::time::Duration;
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::devices::netif::Netif;
+use crate::net::DataFromNetif;
+use crate::net::ethernet::{MacAddress, generate_ether_header};
+use crate::net::ipv6::{Ipv6Address, generate_ipv6_header, calc_icmpv6_checksum, send_icmpv6_error, ICMPV6_DEST_UNREACHABLE, ICMPV6_CODE_ADDRESS_UNREACHABLE};
+use crate::net::fib::{FIBType, NeighborState, AdjacentInformation, ForwardInformationBaseIpv6, IPV6_ADJACENT, find_ipv6_link_local_address, ipv6_adjacent_expire_time};
+use crate::net::rib;
+use crate::net::rib::{RouteSource, RibRoute, RibNexthop, add_ipv6_route, withdraw_ipv6_route};
+use crate::net::address;
+use crate::spinlock::{Spinlock, const_spinlock};
+use crate::asynchronous::timer::TimerFuture;
+use crate::arch::x86_64::kvmclock::get_monotonic_time;
+use crate::PROC_NODES;
+
+const MAX_MULTICAST_SOLICIT: usize = 3;
+const MAX_UNICAST_SOLICIT: usize = 3;
+const RETRANS_TIMER: u64 = 1_000_000_000;
+const REACHABLE_TIME: u64 = 30_000_000_000;
+const DELAY_FIRST_PROBE_TIME: u64 = 5_000_000_000;
+const ND_MAX_PENDING_PACKETS: usize = 32;
+// bounds of the resolutions in progress, against scans of a connected /64 exhausting the memory
+const ND_MAX_INCOMPLETE: usize = 256;
+const ND_MAX_PENDING_DESTINATIONS: usize = 64;
+
+const ND_FLAG_ROUTER: u8 = 0x80;
+const ND_FLAG_SOLICITED: u8 = 0x40;
+const ND_FLAG_OVERRIDE: u8 = 0x20;
+
+const ND_OPT_SOURCE_LINKADDR: u8 = 1;
+const ND_OPT_TARGET_LINKADDR: u8 = 2;
+
+// packets waiting for their next hop to leave INCOMPLETE state
+static ND_PENDING: Spinlock<BTreeMap<Ipv6Address, Vec<DataFromNetif>>> = const_spinlock(BTreeMap::new());
+
+fn solicited_node_multicast(target: &Ipv6Address) -> (Ipv6Address, MacAddress) {
+  let target_array = target.get_array();
+  let ip_address = Ipv6Address::from_array([
+    0xff, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x01, 0xff, target_array[13], target_array[14], target_array[15],
+  ]);
+  let mac_address = MacAddress::new([0x33, 0x33, 0xff, target_array[13], target_array[14], target_array[15]]);
+  (ip_address, mac_address)
+}
+
+fn find_link_layer_option(options: &[u8], opt_type: u8) -> Option<MacAddress> {
+  let mut offset = 0;
+  while offset + 2 <= options.len() {
+    let opt_length = options[offset+1] as usize * 8;
+    if opt_length == 0 || offset + opt_length > options.len() {
+      // malformed option
+      return None;
+    }
+    if options[offset] == opt_type && opt_length >= 8 {
+      return Some(MacAddress::new(options[(offset+2)..(offset+8)].try_into().unwrap()));
+    }
+    offset += opt_length;
+  }
+  None
+}
+
+fn send_neighbor_solicitation(netif: &Arc<dyn Netif>, target: Ipv6Address, unicast_mac: Option<MacAddress>) {
+  let src_ip = match find_ipv6_link_local_address(netif.get_id()) {
+    Some(addr) => addr,
+    None => return,
+  };
+  let (dest_ip, dest_mac) = match unicast_mac {
+    Some(mac) => (target, mac),
+    None => solicited_node_multicast(&target),
+  };
+
+  let length = 32;
+  let reqbuff = netif.pre_xmit(14+40+length);
+  let reqslice = reqbuff.slice_mut();
+
+  generate_ether_header(&mut reqslice[0..], *netif.get_macaddress(), dest_mac, [0x86, 0xdd]);
+  generate_ipv6_header(&mut reqslice[14..], [0, length as u8], 58, src_ip, dest_ip);
+  reqslice[14+7] = 255; // hop limit must be 255 for nd
+
+  let icmpslice = &mut reqslice[(14+40)..(14+40+length)];
+  icmpslice[0] = 135; // neighbor solicitation
+  icmpslice[1] = 0;
+  for i in 2..8 {
+    icmpslice[i] = 0;
+  }
+  icmpslice[8..24].copy_from_slice(&target.get_array());
+  icmpslice[24] = ND_OPT_SOURCE_LINKADDR;
+  icmpslice[25] = 1;
+  icmpslice[26..32].copy_from_slice(&netif.get_macaddress().get_array());
+
+  let csum = calc_icmpv6_checksum(&src_ip, &dest_ip, icmpslice);
+  icmpslice[2] = (csum >> 8) as u8;
+  icmpslice[3] = csum as u8;
+
+  let _ = netif.xmit(reqbuff);
+}
+
+fn send_neighbor_advertisement(netif: &Arc<dyn Netif>, target: Ipv6Address, dest_ip: Ipv6Address, dest_mac: MacAddress, flags: u8) {
+  let length = 32;
+  let respbuff = netif.pre_xmit(14+40+length);
+  let respslice = respbuff.slice_mut();
+
+  generate_ether_header(&mut respslice[0..], *netif.get_macaddress(), dest_mac, [0x86, 0xdd]);
+  generate_ipv6_header(&mut respslice[14..], [0, length as u8], 58, target, dest_ip);
+  respslice[14+7] = 255; // hop limit must be 255 for nd
+
+  let icmpslice = &mut respslice[(14+40)..(14+40+length)];
+  icmpslice[0] = 136; // neighbor advertisement
+  icmpslice[1] = 0;
+  icmpslice[2] = 0;
+  icmpslice[3] = 0;
+  icmpslice[4] = flags;
+  for i in 5..8 {
+    icmpslice[i] = 0;
+  }
+  icmpslice[8..24].copy_from_slice(&target.get_array());
+  icmpslice[24] = ND_OPT_TARGET_LINKADDR;
+  icmpslice[25] = 1;
+  icmpslice[26..32].copy_from_slice(&netif.get_macaddress().get_array());
+
+  let csum = calc_icmpv6_checksum(&target, &dest_ip, icmpslice);
+  icmpslice[2] = (csum >> 8) as u8;
+  icmpslice[3] = csum as u8;
+
+  let _ = netif.xmit(respbuff);
+}
+
+fn flush_pending(ip_address: Ipv6Address) {
+  let packets = ND_PENDING.lock().remove(&ip_address);
+  if let Some(packets) = packets {
+    if let Some(node_ref) = unsafe { PROC_NODES.get("ipv6-in") } {
+      node_ref.process(&packets);
+    }
+  }
+}
+
+// queue a packet towards an on-link address whose mac address isn't known yet.
+// the packet is re-injected to ipv6-in once the neighbor leaves INCOMPLETE state.
+// it is dropped when too many neighbors are being resolved already.
+pub fn enqueue_unresolved(frame: DataFromNetif, dest_ip: Ipv6Address, fib: &ForwardInformationBaseIpv6) {
+  let netif = Arc::clone(fib.get_netif());
+  let need_solicit = {
+    let mut adj_table = IPV6_ADJACENT.lock();
+    if adj_table.contains_key(&dest_ip) {
+      false
+    } else {
+      let incomplete = adj_table.values().filter(|adj| match adj.get_state() {
+        NeighborState::Incomplete(_) => true,
+        _ => false,
+      }).count();
+      if incomplete >= ND_MAX_INCOMPLETE {
+        return;
+      }
+      let mut adj = AdjacentInformation::new(MacAddress::new([0; 6]), Arc::clone(&netif), false, ipv6_adjacent_expire_time());
+      adj.set_state(NeighborState::Incomplete(1), get_monotonic_time());
+      adj_table.insert(dest_ip, adj);
+      true
+    }
+  };
+
+  {
+    let mut pending = ND_PENDING.lock();
+    if pending.contains_key(&dest_ip) || pending.len() < ND_MAX_PENDING_DESTINATIONS {
+      let packets = pending.entry(dest_ip).or_insert_with(Vec::new);
+      if packets.len() < ND_MAX_PENDING_PACKETS {
+        packets.push(frame);
+      }
+    }
+  }
+
+  if need_solicit {
+    send_neighbor_solicitation(&netif, dest_ip, None);
+  }
+}
+
+// a packet is about to be sent to this neighbor. start reachability confirmation if it's stale.
+pub fn notify_forwarding(ip_address: &Ipv6Address) {
+  let mut adj_table = IPV6_ADJACENT.lock();
+  if let Some(adj) = adj_table.get_mut(ip_address) {
+    if adj.get_state() == NeighborState::Stale {
+      adj.set_state(NeighborState::Delay, get_monotonic_time());
+    }
+  }
+}
+
+fn update_neighbor(ip_address: Ipv6Address, mac_address: MacAddress, netif: &Arc<dyn Netif>, state: NeighborState) {
+  add_ipv6_route(ip_address, 128, RibRoute::new(
+    RouteSource::Neighbor, 0, FIBType::AdjacentResolved,
+    vec![RibNexthop::new(ip_address, mac_address, Arc::clone(netif), 1)],
+  ));
+  let mut adj_table = IPV6_ADJACENT.lock();
+  match adj_table.get_mut(&ip_address) {
+    Some(adj) => {
+      adj.set_mac_address(mac_address);
+      adj.set_state(state, get_monotonic_time());
+      adj.set_expire_time(ipv6_adjacent_expire_time());
+    },
+    None => {
+      let mut adj = AdjacentInformation::new(mac_address, Arc::clone(netif), false, ipv6_adjacent_expire_time());
+      adj.set_state(state, get_monotonic_time());
+      adj_table.insert(ip_address, adj);
+    },
+  }
+  drop(adj_table);
+
+  // routes via this gateway follow its link-layer address
+  rib::notify_ipv6_neighbor(ip_address);
+}
+
+// icmpv6 type 135
+pub fn process_neighbor_solicitation(frame: &DataFromNetif, src_ip: Ipv6Address, icmpslice: &[u8], hoplimit: u8) {
+  if hoplimit != 255 || icmpslice.len() < 24 {
+    return;
+  }
+  let target = Ipv6Address::from_array(icmpslice[8..24].try_into().unwrap());
+  if target.is_multicast() {
+    return;
+  }
+
+  let netif = Arc::clone(frame.get_netif());
+  let is_our_target = match IPV6_ADJACENT.lock().get(&target) {
+    Some(adj) => adj.is_local() && adj.get_netif().get_id() == netif.get_id(),
+    None => false,
+  };
+  if !is_our_target {
+    return;
+  }
+
+  let slice = frame.get_buffer().slice();
+  let src_mac = MacAddress::new(slice[6..12].try_into().unwrap());
+
+  if src_ip.is_unspecified() {
+    // duplicate address detection. reply to all-nodes.
+    let allnodes = Ipv6Address::from_array([0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01]);
+    let allnodes_mac = MacAddress::new([0x33, 0x33, 0x00, 0x00, 0x00, 0x01]);
+    send_neighbor_advertisement(&netif, target, allnodes, allnodes_mac, ND_FLAG_ROUTER | ND_FLAG_OVERRIDE);
+    return;
+  }
+
+  // learn the solicitor (RFC 4861 7.2.3), if it is on a subnet of the interface
+  let lladdr = match address::is_ipv6_on_link(netif.get_id(), &src_ip) {
+    true => find_link_layer_option(&icmpslice[24..], ND_OPT_SOURCE_LINKADDR),
+    false => None,
+  };
+  if let Some(lladdr) = lladdr {
+    let current = IPV6_ADJACENT.lock().get(&src_ip).map(|adj| (adj.is_local(), adj.get_state(), adj.get_mac_address()));
+    match current {
+      Some((true, _, _)) => (),
+      Some((false, NeighborState::Incomplete(_), _)) => {
+        update_neighbor(src_ip, lladdr, &netif, NeighborState::Stale);
+        flush_pending(src_ip);
+      },
+      Some((false, _, mac)) if mac == lladdr => (),
+      _ => update_neighbor(src_ip, lladdr, &netif, NeighborState::Stale),
+    }
+  }
+
+  send_neighbor_advertisement(&netif, target, src_ip, src_mac, ND_FLAG_ROUTER | ND_FLAG_SOLICITED | ND_FLAG_OVERRIDE);
+}
+
+// icmpv6 type 136
+pub fn process_neighbor_advertisement(frame: &DataFromNetif, icmpslice: &[u8], hoplimit: u8) {
+  if hoplimit != 255 || icmpslice.len() < 24 {
+    return;
+  }
+  let flags = icmpslice[4];
+  let target = Ipv6Address::from_array(icmpslice[8..24].try_into().unwrap());
+  if target.is_multicast() {
+    return;
+  }
+  let lladdr = find_link_layer_option(&icmpslice[24..], ND_OPT_TARGET_LINKADDR);
+  let solicited = flags & ND_FLAG_SOLICITED != 0;
+  let override_flag = flags & ND_FLAG_OVERRIDE != 0;
+  let netif = Arc::clone(frame.get_netif());
+
+  // RFC 4861 7.2.5
+  let current = IPV6_ADJACENT.lock().get(&target).map(|adj| (adj.is_local(), adj.get_state(), adj.get_mac_address()));
+  match current {
+    None | Some((true, _, _)) => (), // unsolicited for unknown neighbor, or our own address
+    Some((false, NeighborState::Incomplete(_), _)) => {
+      if let Some(mac) = lladdr {
+        update_neighbor(target, mac, &netif, if solicited { NeighborState::Reachable } else { NeighborState::Stale });
+        flush_pending(target);
+      }
+    },
+    Some((false, state, mac)) => {
+      let is_different = match lladdr {
+        Some(newmac) => newmac != mac,
+        None => false,
+      };
+      if !override_flag && is_different {
+        if state == NeighborState::Reachable {
+          let mut adj_table = IPV6_ADJACENT.lock();
+          if let Some(adj) = adj_table.get_mut(&target) {
+            adj.set_state(NeighborState::Stale, get_monotonic_time());
+          }
+        }
+      } else {
+        let newmac = lladdr.unwrap_or(mac);
+        let newstate = if solicited {
+          NeighborState::Reachable
+        } else if is_different {
+          NeighborState::Stale
+        } else {
+          state
+        };
+        update_neighbor(target, newmac, &netif, newstate);
+      }
+    },
+  }
+}
+
+// neighbor unreachability detection. drives the timers of all non-local neighbor cache entries.
+pub async fn neighbor_unreachability_detection() {
+  loop {
+    TimerFuture::new(Duration::from_nanos(RETRANS_TIMER)).await;
+
+    let now = get_monotonic_time();
+    let mut solicits: Vec<(Arc<dyn Netif>, Ipv6Address, Option<MacAddress>)> = Vec::new();
+    let mut failed: Vec<Ipv6Address> = Vec::new();
+    {
+      let mut adj_table = IPV6_ADJACENT.lock();
+      for (ip_address, adj) in adj_table.iter_mut() {
+        if adj.is_local() {
+          continue;
+        }
+        let elapsed = now.saturating_sub(adj.get_state_time());
+        match adj.get_state() {
+          NeighborState::Incomplete(count) if elapsed >= RETRANS_TIMER => {
+            if count >= MAX_MULTICAST_SOLICIT {
+              failed.push(*ip_address);
+            } else {
+              adj.set_state(NeighborState::Incomplete(count+1), now);
+              solicits.push((Arc::clone(adj.get_netif()), *ip_address, None));
+            }
+          },
+          NeighborState::Reachable if elapsed >= REACHABLE_TIME => {
+            adj.set_state(NeighborState::Stale, now);
+          },
+          NeighborState::Delay if elapsed >= DELAY_FIRST_PROBE_TIME => {
+            adj.set_state(NeighborState::Probe(1), now);
+            solicits.push((Arc::clone(adj.get_netif()), *ip_address, Some(adj.get_mac_address())));
+          },
+          NeighborState::Probe(count) if elapsed >= RETRANS_TIMER => {
+            if count >= MAX_UNICAST_SOLICIT {
+              failed.push(*ip_address);
+            } else {
+              adj.set_state(NeighborState::Probe(count+1), now);
+              solicits.push((Arc::clone(adj.get_netif()), *ip_address, Some(adj.get_mac_address())));
+            }
+          },
+          _ => (),
+        }
+      }
+      for ip_address in failed.iter() {
+        adj_table.remove(ip_address);
+      }
+    }
+
+    for ip_address in failed.iter() {
+      withdraw_ipv6_route(*ip_address, 128, RouteSource::Neighbor);
+      rib::notify_ipv6_neighbor(*ip_address);
+      let packets = ND_PENDING.lock().remove(ip_address);
+      if let Some(packets) = packets {
+        for frame in packets.iter() {
+          send_icmpv6_error(frame, ICMPV6_DEST_UNREACHABLE, ICMPV6_CODE_ADDRESS_UNREACHABLE, [0; 4]);
+        }
+      }
+    }
+    for (netif, target, unicast_mac) in solicits.iter() {
+      send_neighbor_solicitation(netif, *target, *unicast_mac);
+    }
+  }
+}
@@ -0,0 +1,131 @@
+// echo requests originated by this router, and the replies to them.
+// requests are handed to ipv4-in / ipv6-in as if they were received, so that they take the same route lookup,
+// address resolution and fragmentation as forwarded packets. they leave with the ttl decremented once.
+
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+
+use crate::spinlock::{Spinlock, const_spinlock};
+use crate::arch::x86_64::kvmclock::get_monotonic_time;
+use crate::devices::netif::Netif;
+use crate::devices::null::NULL_NETIF;
+use crate::net::DataFromNetif;
+use crate::net::ethernet::generate_ether_header;
+use crate::net::ipv4::{Ipv4Address, generate_ipv4_header, calc_checksum};
+use crate::net::ipv6::{Ipv6Address, generate_ipv6_header, calc_icmpv6_checksum};
+use crate::net::fib::{find_ipv4_fib, find_ipv6_fib, find_ipv6_source_address};
+use crate::net::address;
+use crate::PROC_NODES;
+
+// replies kept for an identifier until the sender looks at them
+const MAX_PENDING_REPLIES: usize = 1024;
+// replies the sender didn't look at are dropped after this long (nanoseconds)
+const REPLY_LIFETIME: u64 = 10_000_000_000;
+
+static NEXT_IDENTIFIER: AtomicU16 = AtomicU16::new(0x5500);
+
+// identifier of a running series -> (sequence -> monotonic time of arrival)
+// replies to identifiers which aren't registered are not ours, and are ignored.
+static ECHO_REPLIES: Spinlock<BTreeMap<u16, BTreeMap<u16, u64>>> = const_spinlock(BTreeMap::new());
+
+// register an identifier for a series of echo requests. it must be released by release_identifier().
+pub fn new_identifier() -> u16 {
+  let mut replies = ECHO_REPLIES.lock();
+  loop {
+    let identifier = NEXT_IDENTIFIER.fetch_add(1, Ordering::Relaxed);
+    if !replies.contains_key(&identifier) {
+      replies.insert(identifier, BTreeMap::new());
+      return identifier;
+    }
+  }
+}
+
+pub fn notify_echo_reply(identifier: u16, sequence: u16) {
+  let now = get_monotonic_time();
+  let mut table = ECHO_REPLIES.lock();
+  if let Some(replies) = table.get_mut(&identifier) {
+    replies.retain(|_, received_time| now - *received_time < REPLY_LIFETIME);
+    if replies.len() < MAX_PENDING_REPLIES {
+      replies.insert(sequence, now);
+    }
+  }
+}
+
+// the arrival time of the reply, if it came
+pub fn take_echo_reply(identifier: u16, sequence: u16) -> Option<u64> {
+  ECHO_REPLIES.lock().get_mut(&identifier)?.remove(&sequence)
+}
+
+// end a series, and forget the replies which came too late
+pub fn release_identifier(identifier: u16) {
+  ECHO_REPLIES.lock().remove(&identifier);
+}
+
+fn fill_echo_request(icmpslice: &mut [u8], icmp_type: u8, identifier: u16, sequence: u16) {
+  icmpslice[0] = icmp_type;
+  icmpslice[1] = 0;
+  icmpslice[2] = 0;
+  icmpslice[3] = 0;
+  icmpslice[4] = (identifier >> 8) as u8;
+  icmpslice[5] = identifier as u8;
+  icmpslice[6] = (sequence >> 8) as u8;
+  icmpslice[7] = sequence as u8;
+  for (i, b) in icmpslice[8..].iter_mut().enumerate() {
+    *b = i as u8;
+  }
+}
+
+fn inject(node: &str, buffer: Arc<crate::devices::buffer::Buffer>) -> Result<(), &'static str> {
+  let frame = DataFromNetif::new(Arc::clone(&NULL_NETIF) as Arc<dyn Netif>, buffer);
+  match unsafe { PROC_NODES.get(node) } {
+    Some(node_ref) => {
+      node_ref.process(&[frame]);
+      Ok(())
+    },
+    None => Err("processing node is disabled"),
+  }
+}
+
+pub fn send_ipv4_echo_request(dest: Ipv4Address, identifier: u16, sequence: u16, payload_length: usize) -> Result<(), &'static str> {
+  let fib = find_ipv4_fib(&dest, 0xffffffff, 0).ok_or("no route to host")?;
+  let netif = Arc::clone(fib.get_netif());
+  let src = address::find_ipv4_source_address(netif.get_id(), &fib.get_nexthop_address())
+    .ok_or("no address on the outgoing interface")?;
+
+  let length = 20 + 8 + payload_length;
+  let buffer = netif.pre_xmit(14+length);
+  let slice = buffer.slice_mut();
+  generate_ether_header(&mut slice[0..], *netif.get_macaddress(), *netif.get_macaddress(), [0x08, 0x00]);
+  generate_ipv4_header(&mut slice[14..], [(length >> 8) as u8, length as u8], 0x01, src, dest);
+  let csum = calc_checksum(&slice[14..(14+20)]);
+  slice[14+10] = (csum >> 8) as u8;
+  slice[14+11] = csum as u8;
+
+  fill_echo_request(&mut slice[(14+20)..(14+length)], 8, identifier, sequence);
+  let csum = calc_checksum(&slice[(14+20)..(14+length)]);
+  slice[14+20+2] = (csum >> 8) as u8;
+  slice[14+20+3] = csum as u8;
+
+  inject("ipv4-in", buffer)
+}
+
+pub fn send_ipv6_echo_request(dest: Ipv6Address, identifier: u16, sequence: u16, payload_length: usize) -> Result<(), &'static str> {
+  let fib = find_ipv6_fib(&dest, 128, 0).ok_or("no route to host")?;
+  let netif = Arc::clone(fib.get_netif());
+  let src = find_ipv6_source_address(netif.get_id(), &dest).ok_or("no address on the outgoing interface")?;
+
+  let length = 40 + 8 + payload_length;
+  let buffer = netif.pre_xmit(14+length);
+  let slice = buffer.slice_mut();
+  generate_ether_header(&mut slice[0..], *netif.get_macaddress(), *netif.get_macaddress(), [0x86, 0xdd]);
+  generate_ipv6_header(&mut slice[14..], [((length - 40) >> 8) as u8, (length - 40) as u8], 58, src, dest);
+
+  fill_echo_request(&mut slice[(14+40)..(14+length)], 128, identifier, sequence);
+  let csum = calc_icmpv6_checksum(&src, &dest, &slice[(14+40)..(14+length)]);
+  slice[14+40+2] = (csum >> 8) as u8;
+  slice[14+40+3] = csum as u8;
+
+  inject("ipv6-in", buffer)
+}
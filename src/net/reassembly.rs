@@ -0,0 +1,219 @@
+// fragment reassembly shared by ipv4 and ipv6.
+// fragments are copied out of the receive buffers, so a datagram in progress only costs its own bytes.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::devices::netif::Netif;
+use crate::devices::buffer::Buffer;
+use crate::net::DataFromNetif;
+
+const MAX_DATAGRAM_LENGTH: usize = 65535;
+
+pub struct PartialDatagram {
+  netif: Arc<dyn Netif>,
+  header: Option<Vec<u8>>, // headers of the first fragment (offset 0)
+  fragments: BTreeMap<usize, Vec<u8>>,
+  total_length: Option<usize>,
+  memory_usage: usize,
+  expire_time: u64,
+}
+
+impl PartialDatagram {
+  pub fn get_netif(&self) -> &Arc<dyn Netif> {
+    &self.netif
+  }
+
+  pub fn get_header(&self) -> Option<&Vec<u8>> {
+    self.header.as_ref()
+  }
+
+  pub fn get_first_fragment(&self) -> Option<&Vec<u8>> {
+    self.fragments.get(&0)
+  }
+
+  fn is_complete(&self) -> bool {
+    let total_length = match self.total_length {
+      Some(len) => len,
+      None => return false,
+    };
+    if self.header.is_none() {
+      return false;
+    }
+    let mut next_offset = 0;
+    for (offset, data) in self.fragments.iter() {
+      if *offset != next_offset {
+        return false;
+      }
+      next_offset = offset + data.len();
+    }
+    next_offset == total_length
+  }
+
+  // false if the fragment overlaps another one or contradicts the known length
+  fn accepts(&self, offset: usize, length: usize, more: bool) -> bool {
+    if let Some(total_length) = self.total_length {
+      if offset + length > total_length || (!more && offset + length != total_length) {
+        return false;
+      }
+    }
+    if !more {
+      if let Some((last_offset, last_data)) = self.fragments.iter().next_back() {
+        if last_offset + last_data.len() > offset + length {
+          return false;
+        }
+      }
+    }
+    if let Some((prev_offset, prev_data)) = self.fragments.range(..(offset+1)).next_back() {
+      if prev_offset + prev_data.len() > offset {
+        return false;
+      }
+    }
+    if let Some((next_offset, _)) = self.fragments.range(offset..).next() {
+      if offset + length > *next_offset {
+        return false;
+      }
+    }
+    true
+  }
+}
+
+pub enum ReassemblyResult {
+  Incomplete,
+  Complete(Arc<dyn Netif>, Vec<u8>, Vec<u8>), // ingress interface, headers of the first fragment, payload
+  Dropped,
+}
+
+pub struct Reassembler<K: Ord + Copy> {
+  datagrams: BTreeMap<K, PartialDatagram>,
+  memory_usage: usize,
+  max_memory: usize,
+  max_datagrams: usize,
+  timeout: u64,
+}
+
+impl<K: Ord + Copy> Reassembler<K> {
+  pub const fn new(max_memory: usize, max_datagrams: usize, timeout: u64) -> Reassembler<K> {
+    Reassembler {
+      datagrams: BTreeMap::new(),
+      memory_usage: 0,
+      max_memory: max_memory,
+      max_datagrams: max_datagrams,
+      timeout: timeout,
+    }
+  }
+
+  pub fn get_memory_usage(&self) -> usize {
+    self.memory_usage
+  }
+
+  fn remove(&mut self, key: &K) -> Option<PartialDatagram> {
+    let datagram = self.datagrams.remove(key);
+    if let Some(d) = datagram.as_ref() {
+      self.memory_usage -= d.memory_usage;
+    }
+    datagram
+  }
+
+  // make room for `length` more bytes by evicting the datagrams closest to their expiry
+  fn evict(&mut self, length: usize, is_new: bool) {
+    while self.datagrams.len() > 0 && (self.memory_usage + length > self.max_memory || (is_new && self.datagrams.len() >= self.max_datagrams)) {
+      let oldest = self.datagrams.iter().min_by_key(|(_, d)| d.expire_time).map(|(k, _)| *k);
+      if let Some(key) = oldest {
+        self.remove(&key);
+      }
+    }
+  }
+
+  // `offset` and `data` are relative to the fragmentable part. `header` must be given with the first fragment.
+  pub fn insert(&mut self, key: K, netif: &Arc<dyn Netif>, header: Option<&[u8]>, offset: usize, data: &[u8], more: bool, now: u64) -> ReassemblyResult {
+    let length = data.len();
+    let header_length = header.map(|h| h.len()).unwrap_or(0);
+    if offset + length > MAX_DATAGRAM_LENGTH || (length == 0 && more) || header_length + length > self.max_memory {
+      self.remove(&key);
+      return ReassemblyResult::Dropped;
+    }
+
+    match self.datagrams.get(&key) {
+      Some(datagram) => {
+        if let Some(existing) = datagram.fragments.get(&offset) {
+          if existing.len() == length {
+            // duplicated fragment
+            return ReassemblyResult::Incomplete;
+          }
+        }
+        if !datagram.accepts(offset, length, more) {
+          // overlapping fragments. discard whole datagram (RFC 5722)
+          self.remove(&key);
+          return ReassemblyResult::Dropped;
+        }
+        self.evict(header_length + length, false);
+      },
+      None => {
+        self.evict(header_length + length, true);
+      },
+    }
+
+    let timeout = self.timeout;
+    let datagram = self.datagrams.entry(key).or_insert_with(|| PartialDatagram {
+      netif: Arc::clone(netif),
+      header: None,
+      fragments: BTreeMap::new(),
+      total_length: None,
+      memory_usage: 0,
+      expire_time: now + timeout,
+    });
+    if let Some(h) = header {
+      datagram.header = Some(h.to_vec());
+    }
+    if !more {
+      datagram.total_length = Some(offset + length);
+    }
+    datagram.fragments.insert(offset, data.to_vec());
+    datagram.memory_usage += header_length + length;
+    self.memory_usage += header_length + length;
+
+    if !datagram.is_complete() {
+      return ReassemblyResult::Incomplete;
+    }
+
+    match self.remove(&key) {
+      Some(datagram) => {
+        let mut payload = Vec::with_capacity(datagram.total_length.unwrap_or(0));
+        for (_, data) in datagram.fragments.iter() {
+          payload.extend_from_slice(data);
+        }
+        ReassemblyResult::Complete(datagram.netif, datagram.header.unwrap_or_else(Vec::new), payload)
+      },
+      None => ReassemblyResult::Dropped,
+    }
+  }
+
+  // remove and return the datagrams which weren't completed in time
+  pub fn expire(&mut self, now: u64) -> Vec<PartialDatagram> {
+    let expired: Vec<K> = self.datagrams.iter().filter(|(_, d)| now > d.expire_time).map(|(k, _)| *k).collect();
+    let mut ret = Vec::with_capacity(expired.len());
+    for key in expired.iter() {
+      if let Some(datagram) = self.remove(key) {
+        ret.push(datagram);
+      }
+    }
+    ret
+  }
+}
+
+// wrap a reassembled datagram into a frame so that it can go through the processing nodes again
+pub fn build_datagram_frame(netif: Arc<dyn Netif>, header: &[u8], payload: &[u8]) -> Option<DataFromNetif> {
+  let length = header.len() + payload.len();
+  match Buffer::new(length, 8) {
+    Ok(rawbuffer) => {
+      let buffer = Arc::new(rawbuffer);
+      let slice = buffer.slice_mut();
+      slice[0..header.len()].copy_from_slice(header);
+      slice[header.len()..length].copy_from_slice(payload);
+      Some(DataFromNetif::new(netif, buffer))
+    },
+    Err(_) => None,
+  }
+}
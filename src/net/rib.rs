@@ -0,0 +1,612 @@
+// routing information base.
+// every source (connected, static, routing protocols) keeps its own candidate route per prefix,
+// and the best one by administrative distance then metric is installed into the fib.
+//
+// a next hop given without an interface is recursive. it is resolved through the other routes of the rib
+// down to on-link gateways, and re-resolved whenever a route covering it or the gateway's neighbor entry changes.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::spinlock::{Spinlock, const_spinlock};
+use crate::devices::netif::Netif;
+use crate::net::ethernet::MacAddress;
+use crate::net::ipv4::Ipv4Address;
+use crate::net::ipv6::Ipv6Address;
+use crate::net::fib::{
+  FIBType, NeighborState, NexthopGroup, ForwardInformationBaseIpv4, ForwardInformationBaseIpv6, IPV4_ADJACENT, IPV6_ADJACENT,
+  install_ipv4_fib, unregister_ipv4_fib, install_ipv6_fib, unregister_ipv6_fib, ipv4_mask_to_prefixlen,
+};
+
+// chains of recursive next hops longer than this are treated as unresolvable
+const MAX_RECURSION_DEPTH: usize = 8;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RouteSource {
+  Local, // addresses of our own interfaces
+  Connected,
+  Static,
+  Ebgp,
+  Ospf,
+  Isis,
+  Rip,
+  Ibgp,
+  Neighbor, // host routes of neighbors learned by ARP and ND
+}
+
+impl RouteSource {
+  pub fn get_default_distance(&self) -> u8 {
+    match self {
+      RouteSource::Local => 0,
+      RouteSource::Connected => 0,
+      RouteSource::Static => 1,
+      RouteSource::Ebgp => 20,
+      RouteSource::Ospf => 110,
+      RouteSource::Isis => 115,
+      RouteSource::Rip => 120,
+      RouteSource::Ibgp => 200,
+      // below every other source, so that learning a neighbor never overrides a configured route
+      RouteSource::Neighbor => 250,
+    }
+  }
+
+  pub fn get_name(&self) -> &'static str {
+    match self {
+      RouteSource::Local => "local",
+      RouteSource::Connected => "connected",
+      RouteSource::Static => "static",
+      RouteSource::Ebgp => "ebgp",
+      RouteSource::Ospf => "ospf",
+      RouteSource::Isis => "isis",
+      RouteSource::Rip => "rip",
+      RouteSource::Ibgp => "ibgp",
+      RouteSource::Neighbor => "neighbor",
+    }
+  }
+}
+
+#[derive(Clone)]
+pub struct RibNexthop<A> {
+  address: A,
+  macaddress: MacAddress,
+  netif: Option<Arc<dyn Netif>>, // None for a recursive next hop
+  weight: u32,
+}
+
+impl<A: Copy> RibNexthop<A> {
+  pub fn new(address: A, macaddress: MacAddress, netif: Arc<dyn Netif>, weight: u32) -> RibNexthop<A> {
+    RibNexthop {
+      address: address,
+      macaddress: macaddress,
+      netif: Some(netif),
+      weight: weight,
+    }
+  }
+
+  // a next hop to be resolved through the other routes, e.g. a loopback address of a remote router
+  pub fn recursive(address: A, weight: u32) -> RibNexthop<A> {
+    RibNexthop {
+      address: address,
+      macaddress: MacAddress::new([0; 6]),
+      netif: None,
+      weight: weight,
+    }
+  }
+
+  pub fn get_address(&self) -> A {
+    self.address
+  }
+
+  pub fn get_macaddress(&self) -> MacAddress {
+    self.macaddress
+  }
+
+  pub fn get_netif(&self) -> Option<&Arc<dyn Netif>> {
+    self.netif.as_ref()
+  }
+
+  pub fn get_weight(&self) -> u32 {
+    self.weight
+  }
+
+  pub fn is_recursive(&self) -> bool {
+    self.netif.is_none()
+  }
+}
+
+#[derive(Clone)]
+pub struct RibRoute<A> {
+  source: RouteSource,
+  distance: u8,
+  metric: u32,
+  fib_type: FIBType,
+  nexthops: Vec<RibNexthop<A>>,
+}
+
+impl<A: Copy> RibRoute<A> {
+  // the distance defaults to the one of the source
+  pub fn new(source: RouteSource, metric: u32, fib_type: FIBType, nexthops: Vec<RibNexthop<A>>) -> RibRoute<A> {
+    RibRoute {
+      source: source,
+      distance: source.get_default_distance(),
+      metric: metric,
+      fib_type: fib_type,
+      nexthops: nexthops,
+    }
+  }
+
+  pub fn get_source(&self) -> RouteSource {
+    self.source
+  }
+
+  pub fn get_distance(&self) -> u8 {
+    self.distance
+  }
+
+  // e.g. a floating static route that only takes over when the dynamic one goes away
+  pub fn set_distance(&mut self, distance: u8) {
+    self.distance = distance;
+  }
+
+  pub fn get_metric(&self) -> u32 {
+    self.metric
+  }
+
+  pub fn get_fib_type(&self) -> FIBType {
+    self.fib_type
+  }
+
+  pub fn get_nexthops(&self) -> &Vec<RibNexthop<A>> {
+    &self.nexthops
+  }
+
+  fn preference(&self) -> (u8, u32, RouteSource) {
+    (self.distance, self.metric, self.source)
+  }
+}
+
+// what the rib needs from an address family
+pub trait RibAddress: Copy + Ord {
+  type Fib;
+  const MAX_PREFIX_LENGTH: u32;
+
+  fn prefix_start(&self, prefix_length: u32) -> Self;
+  fn prefix_end(&self, prefix_length: u32) -> Self;
+  fn find_neighbor(&self, netif: &Arc<dyn Netif>) -> Option<MacAddress>;
+  fn new_fib(macaddress: MacAddress, address: Self, netif: Arc<dyn Netif>, fib_type: FIBType) -> Self::Fib;
+  fn install_fib(prefix: Self, prefix_length: u32, group: NexthopGroup<Self::Fib>);
+  fn unregister_fib(prefix: Self, prefix_length: u32);
+}
+
+fn ipv4_prefix_mask(prefix_length: u32) -> u32 {
+  0xffffffffu32.checked_shl(32 - prefix_length).unwrap_or(0)
+}
+
+impl RibAddress for Ipv4Address {
+  type Fib = ForwardInformationBaseIpv4;
+  const MAX_PREFIX_LENGTH: u32 = 32;
+
+  fn prefix_start(&self, prefix_length: u32) -> Ipv4Address {
+    self.masked(prefix_length)
+  }
+
+  fn prefix_end(&self, prefix_length: u32) -> Ipv4Address {
+    Ipv4Address::from_prim(self.get_prim() | !ipv4_prefix_mask(prefix_length))
+  }
+
+  fn find_neighbor(&self, netif: &Arc<dyn Netif>) -> Option<MacAddress> {
+    let adj_table = IPV4_ADJACENT.lock();
+    match adj_table.get(self) {
+      Some(adj) if !adj.is_local() && adj.get_netif().get_id() == netif.get_id() => Some(adj.get_mac_address()),
+      _ => None,
+    }
+  }
+
+  fn new_fib(macaddress: MacAddress, address: Ipv4Address, netif: Arc<dyn Netif>, fib_type: FIBType) -> ForwardInformationBaseIpv4 {
+    ForwardInformationBaseIpv4::new(macaddress, address, netif, fib_type)
+  }
+
+  fn install_fib(prefix: Ipv4Address, prefix_length: u32, group: NexthopGroup<ForwardInformationBaseIpv4>) {
+    install_ipv4_fib(prefix, ipv4_prefix_mask(prefix_length), group);
+  }
+
+  fn unregister_fib(prefix: Ipv4Address, prefix_length: u32) {
+    unregister_ipv4_fib(prefix, ipv4_prefix_mask(prefix_length));
+  }
+}
+
+impl RibAddress for Ipv6Address {
+  type Fib = ForwardInformationBaseIpv6;
+  const MAX_PREFIX_LENGTH: u32 = 128;
+
+  fn prefix_start(&self, prefix_length: u32) -> Ipv6Address {
+    self.masked(prefix_length)
+  }
+
+  fn prefix_end(&self, prefix_length: u32) -> Ipv6Address {
+    let hostmask = 0xffffffff_ffffffff_ffffffff_ffffffffu128.checked_shr(prefix_length).unwrap_or(0);
+    Ipv6Address::from_prim(self.get_prim() | hostmask)
+  }
+
+  fn find_neighbor(&self, netif: &Arc<dyn Netif>) -> Option<MacAddress> {
+    let adj_table = IPV6_ADJACENT.lock();
+    match adj_table.get(self) {
+      Some(adj) if !adj.is_local() && adj.get_netif().get_id() == netif.get_id() => match adj.get_state() {
+        NeighborState::Incomplete(_) => None,
+        _ => Some(adj.get_mac_address()),
+      },
+      _ => None,
+    }
+  }
+
+  fn new_fib(macaddress: MacAddress, address: Ipv6Address, netif: Arc<dyn Netif>, fib_type: FIBType) -> ForwardInformationBaseIpv6 {
+    ForwardInformationBaseIpv6::new(macaddress, address, netif, fib_type)
+  }
+
+  fn install_fib(prefix: Ipv6Address, prefix_length: u32, group: NexthopGroup<ForwardInformationBaseIpv6>) {
+    install_ipv6_fib(prefix, prefix_length, group);
+  }
+
+  fn unregister_fib(prefix: Ipv6Address, prefix_length: u32) {
+    unregister_ipv6_fib(prefix, prefix_length);
+  }
+}
+
+// next hop resolved down to an on-link gateway
+struct ResolvedNexthop<A> {
+  address: A,
+  macaddress: Option<MacAddress>,
+  netif: Arc<dyn Netif>,
+  fib_type: FIBType,
+  weight: u32,
+}
+
+fn push_resolved<A: RibAddress>(resolved: &mut Vec<ResolvedNexthop<A>>, nexthop: ResolvedNexthop<A>) {
+  let is_duplicated = resolved.iter().any(|r| r.address == nexthop.address && r.netif.get_id() == nexthop.netif.get_id());
+  if !is_duplicated {
+    resolved.push(nexthop);
+  }
+}
+
+pub struct Rib<A: RibAddress> {
+  routes: BTreeMap<(A, u32), Vec<RibRoute<A>>>, // candidates per (masked prefix, length), one per source
+  tracking: BTreeMap<A, BTreeSet<(A, u32)>>, // recursive or unresolved next hop -> prefixes with a candidate using it
+}
+
+impl<A: RibAddress> Rib<A> {
+  pub const fn new() -> Rib<A> {
+    Rib {
+      routes: BTreeMap::new(),
+      tracking: BTreeMap::new(),
+    }
+  }
+
+  fn sorted_candidates(&self, key: &(A, u32)) -> Vec<&RibRoute<A>> {
+    let mut candidates: Vec<&RibRoute<A>> = match self.routes.get(key) {
+      Some(c) => c.iter().filter(|r| r.nexthops.len() > 0).collect(),
+      None => Vec::new(),
+    };
+    candidates.sort_by_key(|r| r.preference());
+    candidates
+  }
+
+  fn tracked_nexthops(&self, key: &(A, u32)) -> BTreeSet<A> {
+    let mut nexthops = BTreeSet::new();
+    if let Some(candidates) = self.routes.get(key) {
+      for route in candidates.iter() {
+        let is_unresolved = match route.fib_type { FIBType::RemoteUnresolved => true, _ => false };
+        for nexthop in route.nexthops.iter().filter(|n| n.is_recursive() || is_unresolved) {
+          nexthops.insert(nexthop.address);
+        }
+      }
+    }
+    nexthops
+  }
+
+  fn retrack(&mut self, key: (A, u32), before: BTreeSet<A>) {
+    let after = self.tracked_nexthops(&key);
+    for nexthop in before.difference(&after) {
+      let is_empty = match self.tracking.get_mut(nexthop) {
+        Some(dependents) => {
+          dependents.remove(&key);
+          dependents.len() == 0
+        },
+        None => false,
+      };
+      if is_empty {
+        self.tracking.remove(nexthop);
+      }
+    }
+    for nexthop in after.difference(&before) {
+      self.tracking.entry(*nexthop).or_insert_with(BTreeSet::new).insert(key);
+    }
+  }
+
+  // the next hops of the first candidate of the prefix that resolves
+  fn resolve_prefix(&self, key: &(A, u32), stack: &mut Vec<(A, u32)>) -> Vec<ResolvedNexthop<A>> {
+    if stack.contains(key) || stack.len() >= MAX_RECURSION_DEPTH {
+      return Vec::new(); // routing loop
+    }
+    stack.push(*key);
+    let mut resolved = Vec::new();
+    for route in self.sorted_candidates(key).iter() {
+      resolved = self.resolve_route(route, stack);
+      if resolved.len() > 0 {
+        break;
+      }
+    }
+    stack.pop();
+    resolved
+  }
+
+  fn resolve_route(&self, route: &RibRoute<A>, stack: &mut Vec<(A, u32)>) -> Vec<ResolvedNexthop<A>> {
+    let mut resolved = Vec::new();
+    for nexthop in route.nexthops.iter() {
+      match nexthop.netif.as_ref() {
+        Some(netif) => {
+          // an on-link gateway given without its mac address follows the neighbor table
+          let (macaddress, fib_type) = match route.fib_type {
+            FIBType::RemoteUnresolved => match nexthop.address.find_neighbor(netif) {
+              Some(macaddress) => (Some(macaddress), FIBType::Remote),
+              None => (None, FIBType::RemoteUnresolved),
+            },
+            fib_type => (Some(nexthop.macaddress), fib_type),
+          };
+          push_resolved(&mut resolved, ResolvedNexthop {
+            address: nexthop.address,
+            macaddress: macaddress,
+            netif: Arc::clone(netif),
+            fib_type: fib_type,
+            weight: nexthop.weight,
+          });
+        },
+        None => {
+          for mut r in self.resolve_gateway(nexthop.address, stack).into_iter() {
+            r.weight = nexthop.weight;
+            push_resolved(&mut resolved, r);
+          }
+        },
+      }
+    }
+    resolved
+  }
+
+  // a recursive next hop goes the way of the longest usable prefix covering it
+  fn resolve_gateway(&self, gateway: A, stack: &mut Vec<(A, u32)>) -> Vec<ResolvedNexthop<A>> {
+    for prefix_length in (0..(A::MAX_PREFIX_LENGTH+1)).rev() {
+      let key = (gateway.prefix_start(prefix_length), prefix_length);
+      if !self.routes.contains_key(&key) || stack.contains(&key) {
+        continue;
+      }
+      if stack.len() >= MAX_RECURSION_DEPTH {
+        return Vec::new();
+      }
+      stack.push(key);
+      let mut resolved = Vec::new();
+      for route in self.sorted_candidates(&key).iter() {
+        resolved = match route.fib_type {
+          // the next hop is one of our addresses, or goes nowhere
+          FIBType::Local | FIBType::Blackhole | FIBType::Reject => Vec::new(),
+          FIBType::Adjacent => {
+            // the gateway is on this link
+            let mut on_link = Vec::new();
+            for nexthop in route.nexthops.iter() {
+              if let Some(netif) = nexthop.netif.as_ref() {
+                let macaddress = gateway.find_neighbor(netif);
+                push_resolved(&mut on_link, ResolvedNexthop {
+                  address: gateway,
+                  macaddress: macaddress,
+                  netif: Arc::clone(netif),
+                  fib_type: if macaddress.is_some() { FIBType::Remote } else { FIBType::RemoteUnresolved },
+                  weight: 1,
+                });
+              }
+            }
+            on_link
+          },
+          // reached through the route of another prefix, e.g. the host route of a neighbor
+          _ => self.resolve_route(route, stack).into_iter().map(|mut r| {
+            if let FIBType::AdjacentResolved = r.fib_type {
+              r.fib_type = FIBType::Remote;
+            }
+            r
+          }).collect(),
+        };
+        if resolved.len() > 0 {
+          break;
+        }
+      }
+      stack.pop();
+      if resolved.len() > 0 {
+        return resolved;
+      }
+    }
+    Vec::new()
+  }
+
+  // install what the prefix resolves to into the fib
+  fn sync_fib(&self, key: (A, u32)) {
+    let mut stack = Vec::with_capacity(MAX_RECURSION_DEPTH);
+    let resolved = self.resolve_prefix(&key, &mut stack);
+
+    let mut group: Option<NexthopGroup<A::Fib>> = None;
+    for r in resolved.into_iter() {
+      let fib = A::new_fib(r.macaddress.unwrap_or(MacAddress::new([0; 6])), r.address, r.netif, r.fib_type);
+      match group.as_mut() {
+        Some(g) => g.push(fib, r.weight),
+        None => group = Some(NexthopGroup::new(fib, r.weight)),
+      }
+    }
+    match group {
+      Some(g) => A::install_fib(key.0, key.1, g),
+      None => A::unregister_fib(key.0, key.1),
+    }
+  }
+
+  // re-install `first` and every prefix whose next hops depend on it, transitively
+  fn refresh(&self, first: Vec<(A, u32)>) {
+    let mut queue = first;
+    let mut done = BTreeSet::new();
+    while let Some(key) = queue.pop() {
+      if !done.insert(key) {
+        continue;
+      }
+      self.sync_fib(key);
+
+      // the next hops inside the changed prefix may resolve differently now
+      let (prefix, prefix_length) = key;
+      for (_, dependents) in self.tracking.range(prefix..=prefix.prefix_end(prefix_length)) {
+        for dependent in dependents.iter() {
+          if !done.contains(dependent) {
+            queue.push(*dependent);
+          }
+        }
+      }
+    }
+  }
+
+  pub fn add(&mut self, prefix: A, prefix_length: u32, route: RibRoute<A>) {
+    let key = (prefix.prefix_start(prefix_length), prefix_length);
+    let before = self.tracked_nexthops(&key);
+    let candidates = self.routes.entry(key).or_insert_with(Vec::new);
+    match candidates.iter_mut().find(|r| r.source == route.source) {
+      Some(existing) => *existing = route,
+      None => candidates.push(route),
+    }
+    self.retrack(key, before);
+    self.refresh(vec![key]);
+  }
+
+  pub fn withdraw(&mut self, prefix: A, prefix_length: u32, source: RouteSource) {
+    let key = (prefix.prefix_start(prefix_length), prefix_length);
+    let before = self.tracked_nexthops(&key);
+    let is_empty = match self.routes.get_mut(&key) {
+      Some(candidates) => {
+        candidates.retain(|r| r.source != source);
+        candidates.len() == 0
+      },
+      None => return,
+    };
+    if is_empty {
+      self.routes.remove(&key);
+    }
+    self.retrack(key, before);
+    self.refresh(vec![key]);
+  }
+
+  pub fn withdraw_by_source(&mut self, source: RouteSource) {
+    let keys: Vec<(A, u32)> = self.routes.iter()
+      .filter(|(_, candidates)| candidates.iter().any(|r| r.source == source))
+      .map(|(key, _)| *key)
+      .collect();
+    for key in keys.iter() {
+      let before = self.tracked_nexthops(key);
+      let is_empty = match self.routes.get_mut(key) {
+        Some(candidates) => {
+          candidates.retain(|r| r.source != source);
+          candidates.len() == 0
+        },
+        None => false,
+      };
+      if is_empty {
+        self.routes.remove(key);
+      }
+      self.retrack(*key, before);
+    }
+    self.refresh(keys);
+  }
+
+  // the link-layer address of a gateway was learned, changed or lost
+  pub fn notify_neighbor(&self, address: A) {
+    let dependents: Vec<(A, u32)> = match self.tracking.get(&address) {
+      Some(dependents) => dependents.iter().cloned().collect(),
+      None => return,
+    };
+    self.refresh(dependents);
+  }
+
+  pub fn get_routes(&self, prefix: A, prefix_length: u32) -> Vec<RibRoute<A>> {
+    self.routes.get(&(prefix.prefix_start(prefix_length), prefix_length)).cloned().unwrap_or_else(Vec::new)
+  }
+
+  // the candidate which is installed into the fib
+  pub fn get_best_route(&self, prefix: A, prefix_length: u32) -> Option<RibRoute<A>> {
+    let key = (prefix.prefix_start(prefix_length), prefix_length);
+    let mut stack = Vec::with_capacity(MAX_RECURSION_DEPTH);
+    stack.push(key);
+    for route in self.sorted_candidates(&key).iter() {
+      if self.resolve_route(route, &mut stack).len() > 0 {
+        return Some((*route).clone());
+      }
+    }
+    None
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = (&(A, u32), &Vec<RibRoute<A>>)> {
+    self.routes.iter()
+  }
+}
+
+/////////
+
+pub static IPV4_RIB: Spinlock<Rib<Ipv4Address>> = const_spinlock(Rib::new());
+
+// add or replace the route of `route.get_source()` for the prefix
+pub fn add_ipv4_route(prefix: Ipv4Address, mask: u32, route: RibRoute<Ipv4Address>) {
+  IPV4_RIB.lock().add(prefix, ipv4_mask_to_prefixlen(mask), route);
+}
+
+// withdraw the route of `source` for the prefix. the next best one, if any, takes over.
+pub fn withdraw_ipv4_route(prefix: Ipv4Address, mask: u32, source: RouteSource) {
+  IPV4_RIB.lock().withdraw(prefix, ipv4_mask_to_prefixlen(mask), source);
+}
+
+// withdraw every route learned from `source`, e.g. when a routing protocol goes down
+pub fn withdraw_ipv4_routes_by_source(source: RouteSource) {
+  IPV4_RIB.lock().withdraw_by_source(source);
+}
+
+pub fn notify_ipv4_neighbor(address: Ipv4Address) {
+  IPV4_RIB.lock().notify_neighbor(address);
+}
+
+pub fn get_ipv4_routes(prefix: Ipv4Address, mask: u32) -> Vec<RibRoute<Ipv4Address>> {
+  IPV4_RIB.lock().get_routes(prefix, ipv4_mask_to_prefixlen(mask))
+}
+
+pub fn get_ipv4_best_route(prefix: Ipv4Address, mask: u32) -> Option<RibRoute<Ipv4Address>> {
+  IPV4_RIB.lock().get_best_route(prefix, ipv4_mask_to_prefixlen(mask))
+}
+
+/////////
+
+pub static IPV6_RIB: Spinlock<Rib<Ipv6Address>> = const_spinlock(Rib::new());
+
+// add or replace the route of `route.get_source()` for the prefix
+pub fn add_ipv6_route(prefix: Ipv6Address, prefix_length: u32, route: RibRoute<Ipv6Address>) {
+  IPV6_RIB.lock().add(prefix, prefix_length, route);
+}
+
+// withdraw the route of `source` for the prefix. the next best one, if any, takes over.
+pub fn withdraw_ipv6_route(prefix: Ipv6Address, prefix_length: u32, source: RouteSource) {
+  IPV6_RIB.lock().withdraw(prefix, prefix_length, source);
+}
+
+// withdraw every route learned from `source`, e.g. when a routing protocol goes down
+pub fn withdraw_ipv6_routes_by_source(source: RouteSource) {
+  IPV6_RIB.lock().withdraw_by_source(source);
+}
+
+pub fn notify_ipv6_neighbor(address: Ipv6Address) {
+  IPV6_RIB.lock().notify_neighbor(address);
+}
+
+pub fn get_ipv6_routes(prefix: Ipv6Address, prefix_length: u32) -> Vec<RibRoute<Ipv6Address>> {
+  IPV6_RIB.lock().get_routes(prefix, prefix_length)
+}
+
+pub fn get_ipv6_best_route(prefix: Ipv6Address, prefix_length: u32) -> Option<RibRoute<Ipv6Address>> {
+  IPV6_RIB.lock().get_best_route(prefix, prefix_length)
+}
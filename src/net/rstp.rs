@@ -0,0 +1,648 @@
+// rapid spanning tree protocol (IEEE 802.1w, as in 802.1D-2004) on the ports of a bridge.
+// one tree is computed for all the vlans of a bridge. the roles and the states of the ports are handed to
+// net::bridge, which learns only on learning and forwarding ports and forwards only between forwarding ports.
+//
+// designated ports become forwarding on the agreement of the port downstream, or after twice the forward delay.
+// ports which receive no BPDU shortly after becoming designated are taken as edge ports and forward at once.
+// STP configuration and TCN BPDUs are understood, but only RST BPDUs are sent.
+
+use core::time::Duration;
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::spinlock::{Spinlock, const_spinlock};
+use crate::arch::x86_64::kvmclock::get_monotonic_time;
+use crate::asynchronous::timer::TimerFuture;
+use crate::devices::netif::Netif;
+use crate::net::DataFromNetif;
+use crate::net::ethernet::MacAddress;
+use crate::net::bridge;
+use crate::net::bridge::PortState;
+
+pub const BPDU_MACADDRESS: [u8; 6] = [0x01, 0x80, 0xc2, 0x00, 0x00, 0x00];
+
+pub const DEFAULT_BRIDGE_PRIORITY: u16 = 32768;
+const DEFAULT_PORT_PRIORITY: u16 = 128;
+const DEFAULT_PATH_COST: u32 = 20000; // 1Gb/s
+
+// in seconds
+const HELLO_TIME: u16 = 2;
+const MAX_AGE: u16 = 20;
+const FORWARD_DELAY: u16 = 15;
+const EDGE_DELAY: u16 = 3;
+
+const TICK: Duration = Duration::from_secs(1);
+
+const FLAG_TOPOLOGY_CHANGE: u8 = 0x01;
+const FLAG_PROPOSAL: u8 = 0x02;
+const FLAG_LEARNING: u8 = 0x10;
+const FLAG_FORWARDING: u8 = 0x20;
+const FLAG_AGREEMENT: u8 = 0x40;
+
+const BPDU_TYPE_CONFIG: u8 = 0x00;
+const BPDU_TYPE_RST: u8 = 0x02;
+const BPDU_TYPE_TCN: u8 = 0x80;
+
+fn seconds(s: u16) -> u64 {
+  s as u64 * 1_000_000_000
+}
+
+// smaller is better, field by field
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PriorityVector {
+  root_id: u64,
+  root_path_cost: u32,
+  designated_bridge_id: u64,
+  designated_port_id: u16,
+}
+
+impl PriorityVector {
+  pub fn get_root_id(&self) -> u64 {
+    self.root_id
+  }
+
+  pub fn get_root_path_cost(&self) -> u32 {
+    self.root_path_cost
+  }
+}
+
+#[derive(Copy, Clone)]
+struct Times {
+  message_age: u16,
+  max_age: u16,
+  hello_time: u16,
+  forward_delay: u16,
+}
+
+const BRIDGE_TIMES: Times = Times { message_age: 0, max_age: MAX_AGE, hello_time: HELLO_TIME, forward_delay: FORWARD_DELAY };
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PortRole {
+  Disabled,
+  Root,
+  Designated,
+  Alternate,
+  Backup,
+}
+
+impl PortRole {
+  pub fn get_name(&self) -> &'static str {
+    match self {
+      PortRole::Disabled => "disabled",
+      PortRole::Root => "root",
+      PortRole::Designated => "designated",
+      PortRole::Alternate => "alternate",
+      PortRole::Backup => "backup",
+    }
+  }
+
+  // the port role field of the flags
+  fn get_bits(&self) -> u8 {
+    match self {
+      PortRole::Disabled => 0,
+      PortRole::Alternate | PortRole::Backup => 1,
+      PortRole::Root => 2,
+      PortRole::Designated => 3,
+    }
+  }
+}
+
+struct Bpdu {
+  flags: u8,
+  role: PortRole, // designated for STP configuration BPDUs
+  vector: PriorityVector,
+  times: Times,
+  tcn: bool,
+}
+
+#[derive(Clone)]
+pub struct RstpPort {
+  netif: Arc<dyn Netif>,
+  port_id: u16,
+  path_cost: u32,
+  role: PortRole,
+  state: PortState,
+  received: Option<(PriorityVector, Times)>, // from the designated port of the segment
+  received_expire: u64,
+  edge: bool,
+  last_bpdu: u64,
+  designated_since: u64,
+  proposing: bool,
+  agreed: bool,
+  agree: bool, // send an agreement
+  fd_while: u64,
+  tc_while: u64,
+}
+
+impl RstpPort {
+  pub fn get_netif(&self) -> &Arc<dyn Netif> {
+    &self.netif
+  }
+
+  pub fn get_port_id(&self) -> u16 {
+    self.port_id
+  }
+
+  pub fn get_path_cost(&self) -> u32 {
+    self.path_cost
+  }
+
+  pub fn get_role(&self) -> PortRole {
+    self.role
+  }
+
+  pub fn get_state(&self) -> PortState {
+    self.state
+  }
+
+  pub fn is_edge(&self) -> bool {
+    self.edge
+  }
+}
+
+// what the state machines want done once the lock is released
+struct Actions {
+  states: Vec<(usize, PortState)>, // netif id and state
+  flushes: Vec<usize>, // flush what was learned on the ports but this netif id
+  bpdus: Vec<(Arc<dyn Netif>, [u8; 36])>,
+}
+
+impl Actions {
+  fn new() -> Actions {
+    Actions { states: Vec::new(), flushes: Vec::new(), bpdus: Vec::new() }
+  }
+}
+
+#[derive(Clone)]
+pub struct RstpBridge {
+  bridge_id: usize,
+  id: u64, // priority and mac address
+  root: PriorityVector,
+  root_times: Times,
+  root_port: Option<usize>,
+  ports: Vec<RstpPort>,
+  next_hello: u64,
+}
+
+impl RstpBridge {
+  fn new(bridge_id: usize, netifs: Vec<Arc<dyn Netif>>, priority: u16) -> RstpBridge {
+    let macaddr = netifs[0].get_macaddress().get_array();
+    let id = macaddr.iter().fold(priority as u64, |id, octet| (id << 8) | *octet as u64);
+    let ports = netifs.into_iter().enumerate().map(|(i, netif)| RstpPort {
+      netif: netif,
+      port_id: (DEFAULT_PORT_PRIORITY << 8) | ((i + 1) as u16 & 0x0fff),
+      path_cost: DEFAULT_PATH_COST,
+      role: PortRole::Disabled,
+      state: PortState::Discarding,
+      received: None,
+      received_expire: 0,
+      edge: false,
+      last_bpdu: 0,
+      designated_since: 0,
+      proposing: false,
+      agreed: false,
+      agree: false,
+      fd_while: 0,
+      tc_while: 0,
+    }).collect();
+
+    RstpBridge {
+      bridge_id: bridge_id,
+      id: id,
+      root: PriorityVector { root_id: id, root_path_cost: 0, designated_bridge_id: id, designated_port_id: 0 },
+      root_times: BRIDGE_TIMES,
+      root_port: None,
+      ports: ports,
+      next_hello: 0,
+    }
+  }
+
+  pub fn get_bridge_id(&self) -> usize {
+    self.bridge_id
+  }
+
+  pub fn get_id(&self) -> u64 {
+    self.id
+  }
+
+  pub fn get_root(&self) -> &PriorityVector {
+    &self.root
+  }
+
+  pub fn get_root_port(&self) -> Option<&RstpPort> {
+    self.root_port.map(|i| &self.ports[i])
+  }
+
+  pub fn get_ports(&self) -> &Vec<RstpPort> {
+    &self.ports
+  }
+
+  fn designated_vector(&self, i: usize) -> PriorityVector {
+    PriorityVector {
+      root_id: self.root.root_id,
+      root_path_cost: self.root.root_path_cost,
+      designated_bridge_id: self.id,
+      designated_port_id: self.ports[i].port_id,
+    }
+  }
+
+  // choose the root port from what the ports received, then the roles of the others
+  fn update_roles(&mut self, now: u64, actions: &mut Actions) {
+    let mut best = (PriorityVector { root_id: self.id, root_path_cost: 0, designated_bridge_id: self.id, designated_port_id: 0 }, 0);
+    let mut best_times = BRIDGE_TIMES;
+    let mut root_port = None;
+    for (i, port) in self.ports.iter().enumerate() {
+      if let Some((vector, times)) = port.received {
+        if vector.designated_bridge_id == self.id {
+          // ours, come back through another port
+          continue;
+        }
+        let candidate = (PriorityVector { root_path_cost: vector.root_path_cost.saturating_add(port.path_cost), ..vector }, port.port_id);
+        if candidate < best {
+          best = candidate;
+          best_times = Times { message_age: times.message_age + 1, ..times };
+          root_port = Some(i);
+        }
+      }
+    }
+    self.root = best.0;
+    self.root_times = best_times;
+    self.root_port = root_port;
+
+    for i in 0..self.ports.len() {
+      let designated = self.designated_vector(i);
+      let role = match (root_port == Some(i), self.ports[i].received) {
+        (true, _) => PortRole::Root,
+        (false, Some((vector, _))) if vector < designated => {
+          if vector.designated_bridge_id == self.id { PortRole::Backup } else { PortRole::Alternate }
+        },
+        (false, _) => PortRole::Designated,
+      };
+      if role != self.ports[i].role {
+        self.set_role(i, role, now, actions);
+      }
+    }
+  }
+
+  fn set_role(&mut self, i: usize, role: PortRole, now: u64, actions: &mut Actions) {
+    self.ports[i].role = role;
+    self.ports[i].agreed = false;
+    self.ports[i].agree = false;
+    self.ports[i].proposing = false;
+    match role {
+      PortRole::Root => {
+        // the ports downstream wait until they agree again
+        self.sync(i, now, actions);
+        self.set_state(i, PortState::Forwarding, now, actions);
+      },
+      PortRole::Designated => {
+        self.ports[i].designated_since = now;
+        if self.ports[i].edge {
+          self.set_state(i, PortState::Forwarding, now, actions);
+        } else {
+          self.set_state(i, PortState::Discarding, now, actions);
+          self.ports[i].proposing = true;
+          self.ports[i].fd_while = now + seconds(self.root_times.forward_delay);
+        }
+      },
+      PortRole::Alternate | PortRole::Backup | PortRole::Disabled => {
+        self.set_state(i, PortState::Discarding, now, actions);
+      },
+    }
+  }
+
+  // put the designated ports but the edge ones back to discarding, and propose to them
+  fn sync(&mut self, except: usize, now: u64, actions: &mut Actions) {
+    for j in 0..self.ports.len() {
+      if j != except && self.ports[j].role == PortRole::Designated && !self.ports[j].edge {
+        self.set_state(j, PortState::Discarding, now, actions);
+        self.ports[j].agreed = false;
+        self.ports[j].proposing = true;
+        self.ports[j].fd_while = now + seconds(self.root_times.forward_delay);
+      }
+    }
+  }
+
+  fn set_state(&mut self, i: usize, state: PortState, now: u64, actions: &mut Actions) {
+    if self.ports[i].state == state {
+      return;
+    }
+    self.ports[i].state = state;
+    actions.states.push((self.ports[i].netif.get_id(), state));
+    if state == PortState::Forwarding && !self.ports[i].edge {
+      // topology change. addresses behind the other ports may have moved.
+      self.start_topology_change(None, now);
+      actions.flushes.push(self.ports[i].netif.get_id());
+    }
+  }
+
+  // tell the topology change through the root and designated ports but the one it came from
+  fn start_topology_change(&mut self, except: Option<usize>, now: u64) {
+    let tc_while = now + seconds(2 * self.root_times.hello_time);
+    for (j, port) in self.ports.iter_mut().enumerate() {
+      if Some(j) != except && !port.edge && (port.role == PortRole::Root || port.role == PortRole::Designated) {
+        port.tc_while = tc_while;
+      }
+    }
+  }
+
+  fn build_bpdu(&self, i: usize, now: u64) -> [u8; 36] {
+    let port = &self.ports[i];
+    let mut flags = port.role.get_bits() << 2;
+    if port.tc_while > now {
+      flags |= FLAG_TOPOLOGY_CHANGE;
+    }
+    if port.proposing && port.role == PortRole::Designated && port.state != PortState::Forwarding {
+      flags |= FLAG_PROPOSAL;
+    }
+    if port.state != PortState::Discarding {
+      flags |= FLAG_LEARNING;
+    }
+    if port.state == PortState::Forwarding {
+      flags |= FLAG_FORWARDING;
+    }
+    if port.agree {
+      flags |= FLAG_AGREEMENT;
+    }
+
+    let mut bpdu = [0u8; 36];
+    bpdu[2] = 0x02; // version
+    bpdu[3] = BPDU_TYPE_RST;
+    bpdu[4] = flags;
+    bpdu[5..13].copy_from_slice(&self.root.root_id.to_be_bytes());
+    bpdu[13..17].copy_from_slice(&self.root.root_path_cost.to_be_bytes());
+    bpdu[17..25].copy_from_slice(&self.id.to_be_bytes());
+    bpdu[25..27].copy_from_slice(&port.port_id.to_be_bytes());
+    bpdu[27..29].copy_from_slice(&(self.root_times.message_age << 8).to_be_bytes());
+    bpdu[29..31].copy_from_slice(&(self.root_times.max_age << 8).to_be_bytes());
+    bpdu[31..33].copy_from_slice(&(self.root_times.hello_time << 8).to_be_bytes());
+    bpdu[33..35].copy_from_slice(&(self.root_times.forward_delay << 8).to_be_bytes());
+    bpdu[35] = 0; // version 1 length
+    bpdu
+  }
+
+  fn send_bpdu(&mut self, i: usize, now: u64, actions: &mut Actions) {
+    let bpdu = self.build_bpdu(i, now);
+    actions.bpdus.push((Arc::clone(&self.ports[i].netif), bpdu));
+    self.ports[i].agree = false;
+  }
+
+  // designated ports send every hello time, and root ports while a topology change goes on
+  fn send_hello(&mut self, now: u64, actions: &mut Actions) {
+    for i in 0..self.ports.len() {
+      let port = &self.ports[i];
+      if port.role == PortRole::Designated || (port.role == PortRole::Root && port.tc_while > now) {
+        self.send_bpdu(i, now, actions);
+      }
+    }
+  }
+
+  fn receive(&mut self, i: usize, bpdu: &Bpdu, now: u64, actions: &mut Actions) {
+    self.ports[i].last_bpdu = now;
+    self.ports[i].edge = false;
+
+    if bpdu.tcn || (bpdu.flags & FLAG_TOPOLOGY_CHANGE) != 0 {
+      self.start_topology_change(Some(i), now);
+      actions.flushes.push(self.ports[i].netif.get_id());
+    }
+    if bpdu.tcn {
+      return;
+    }
+
+    match bpdu.role {
+      PortRole::Designated => {
+        if bpdu.times.message_age >= bpdu.times.max_age {
+          return;
+        }
+        let before: Vec<PortRole> = self.ports.iter().map(|port| port.role).collect();
+        self.ports[i].received = Some((bpdu.vector, bpdu.times));
+        self.ports[i].received_expire = now + seconds(3 * bpdu.times.hello_time.max(1));
+        self.update_roles(now, actions);
+
+        if (bpdu.flags & FLAG_PROPOSAL) != 0 {
+          match self.ports[i].role {
+            PortRole::Root => {
+              self.sync(i, now, actions);
+              self.ports[i].agree = true;
+              self.send_bpdu(i, now, actions);
+            },
+            PortRole::Alternate | PortRole::Backup => {
+              // discarding anyway
+              self.ports[i].agree = true;
+              self.send_bpdu(i, now, actions);
+            },
+            PortRole::Designated | PortRole::Disabled => (),
+          }
+        }
+        if self.ports.iter().map(|port| port.role).zip(before.into_iter()).any(|(role, before)| role != before) {
+          self.send_hello(now, actions);
+        }
+      },
+      PortRole::Root | PortRole::Alternate | PortRole::Backup => {
+        // the port on the other side agrees to our proposal
+        if (bpdu.flags & FLAG_AGREEMENT) != 0 && self.ports[i].role == PortRole::Designated && bpdu.vector.root_id == self.root.root_id {
+          self.ports[i].agreed = true;
+          self.ports[i].proposing = false;
+          self.set_state(i, PortState::Forwarding, now, actions);
+        }
+      },
+      PortRole::Disabled => (),
+    }
+  }
+
+  fn tick(&mut self, now: u64, actions: &mut Actions) {
+    let mut expired = false;
+    for port in self.ports.iter_mut() {
+      if port.received.is_some() && now >= port.received_expire {
+        port.received = None;
+        expired = true;
+      }
+    }
+    if expired {
+      self.update_roles(now, actions);
+    }
+
+    for i in 0..self.ports.len() {
+      if self.ports[i].role != PortRole::Designated || self.ports[i].state == PortState::Forwarding {
+        continue;
+      }
+      let port = &self.ports[i];
+      if !port.edge && port.last_bpdu < port.designated_since && now >= port.designated_since + seconds(EDGE_DELAY) {
+        // nobody speaks spanning tree there
+        self.ports[i].edge = true;
+        self.set_state(i, PortState::Forwarding, now, actions);
+      } else if now >= port.fd_while {
+        let state = match port.state {
+          PortState::Discarding => PortState::Learning,
+          _ => PortState::Forwarding,
+        };
+        self.ports[i].fd_while = now + seconds(self.root_times.forward_delay);
+        self.set_state(i, state, now, actions);
+      }
+    }
+
+    if now >= self.next_hello {
+      self.next_hello = now + seconds(self.root_times.hello_time);
+      self.send_hello(now, actions);
+    }
+  }
+}
+
+// by bridge id
+static RSTP_BRIDGES: Spinlock<BTreeMap<usize, RstpBridge>> = const_spinlock(BTreeMap::new());
+
+fn read_u16(bytes: &[u8]) -> u16 {
+  (bytes[0] as u16) << 8 | bytes[1] as u16
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+  bytes[0..4].iter().fold(0, |value, octet| (value << 8) | *octet as u32)
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+  bytes[0..8].iter().fold(0, |value, octet| (value << 8) | *octet as u64)
+}
+
+// the BPDU of an 802.3 frame with the LLC header
+fn parse_bpdu(slice: &[u8], length: usize) -> Option<Bpdu> {
+  if length < 14 + 3 + 4 || slice[14..17] != [0x42, 0x42, 0x03] {
+    return None;
+  }
+  let bpdu = &slice[17..length];
+  if bpdu[0..2] != [0x00, 0x00] {
+    return None;
+  }
+  let role = match bpdu[3] {
+    BPDU_TYPE_TCN => return Some(Bpdu {
+      flags: 0, role: PortRole::Disabled, vector: PriorityVector { root_id: 0, root_path_cost: 0, designated_bridge_id: 0, designated_port_id: 0 },
+      times: BRIDGE_TIMES, tcn: true,
+    }),
+    BPDU_TYPE_CONFIG if bpdu.len() >= 35 => PortRole::Designated,
+    BPDU_TYPE_RST if bpdu.len() >= 36 => match (bpdu[4] >> 2) & 0x03 {
+      1 => PortRole::Alternate,
+      2 => PortRole::Root,
+      3 => PortRole::Designated,
+      _ => return None,
+    },
+    _ => return None,
+  };
+  Some(Bpdu {
+    flags: bpdu[4],
+    role: role,
+    vector: PriorityVector {
+      root_id: read_u64(&bpdu[5..13]),
+      root_path_cost: read_u32(&bpdu[13..17]),
+      designated_bridge_id: read_u64(&bpdu[17..25]),
+      designated_port_id: read_u16(&bpdu[25..27]),
+    },
+    times: Times {
+      message_age: read_u16(&bpdu[27..29]) >> 8,
+      max_age: read_u16(&bpdu[29..31]) >> 8,
+      hello_time: read_u16(&bpdu[31..33]) >> 8,
+      forward_delay: read_u16(&bpdu[33..35]) >> 8,
+    },
+    tcn: false,
+  })
+}
+
+fn send_bpdu(netif: &Arc<dyn Netif>, bpdu: &[u8; 36]) {
+  let length = 14 + 3 + bpdu.len();
+  let buffer = netif.pre_xmit(60);
+  let slice = buffer.slice_mut();
+  for b in slice[0..60].iter_mut() {
+    *b = 0;
+  }
+  slice[0..6].copy_from_slice(&BPDU_MACADDRESS);
+  slice[6..12].copy_from_slice(&netif.get_macaddress().get_array());
+  slice[12..14].copy_from_slice(&((length - 14) as u16).to_be_bytes());
+  slice[14..17].copy_from_slice(&[0x42, 0x42, 0x03]);
+  slice[17..length].copy_from_slice(bpdu);
+  let _ = netif.xmit(buffer);
+}
+
+fn run_actions(bridge_id: usize, actions: Actions) {
+  for (netif_id, state) in actions.states.iter() {
+    let _ = bridge::set_port_state(*netif_id, *state);
+  }
+  for netif_id in actions.flushes.iter() {
+    bridge::flush_fdb(bridge_id, *netif_id);
+  }
+  for (netif, bpdu) in actions.bpdus.iter() {
+    send_bpdu(netif, bpdu);
+  }
+}
+
+// run the spanning tree on the ports of the bridge. they are discarding until the tree says otherwise.
+pub fn enable(bridge_id: usize, netifs: Vec<Arc<dyn Netif>>, priority: u16) {
+  let now = get_monotonic_time();
+  let mut actions = Actions::new();
+  {
+    let mut rstp_bridge = RstpBridge::new(bridge_id, netifs, priority);
+    for port in rstp_bridge.ports.iter() {
+      actions.states.push((port.netif.get_id(), PortState::Discarding));
+    }
+    rstp_bridge.update_roles(now, &mut actions);
+    RSTP_BRIDGES.lock().insert(bridge_id, rstp_bridge);
+  }
+  run_actions(bridge_id, actions);
+}
+
+// a BPDU received on a port
+pub fn receive(frame: &DataFromNetif) {
+  let buffer = frame.get_buffer();
+  let bpdu = match parse_bpdu(buffer.slice(), buffer.get_length()) {
+    Some(bpdu) => bpdu,
+    None => return,
+  };
+
+  let netif_id = frame.get_netif().get_id();
+  let now = get_monotonic_time();
+  let mut actions = Actions::new();
+  let bridge_id = {
+    let mut rstp_bridges = RSTP_BRIDGES.lock();
+    let rstp_bridge = match rstp_bridges.values_mut().find(|rstp_bridge| rstp_bridge.ports.iter().any(|port| port.netif.get_id() == netif_id)) {
+      Some(rstp_bridge) => rstp_bridge,
+      None => return,
+    };
+    if let Some(i) = rstp_bridge.ports.iter().position(|port| port.netif.get_id() == netif_id) {
+      rstp_bridge.receive(i, &bpdu, now, &mut actions);
+    }
+    rstp_bridge.bridge_id
+  };
+  run_actions(bridge_id, actions);
+}
+
+pub fn get_spanning_trees() -> Vec<RstpBridge> {
+  RSTP_BRIDGES.lock().values().map(|rstp_bridge| rstp_bridge.clone()).collect()
+}
+
+// bridge identifiers as <priority>.<mac address>
+pub fn format_bridge_id(id: u64) -> (u16, MacAddress) {
+  let mut macaddr = [0u8; 6];
+  for (i, octet) in macaddr.iter_mut().enumerate() {
+    *octet = (id >> (8 * (5 - i))) as u8;
+  }
+  ((id >> 48) as u16, MacAddress::new(macaddr))
+}
+
+// timers of every bridge running the spanning tree
+pub async fn spanning_tree() {
+  loop {
+    TimerFuture::new(TICK).await;
+
+    let now = get_monotonic_time();
+    let all_actions: Vec<(usize, Actions)> = {
+      let mut rstp_bridges = RSTP_BRIDGES.lock();
+      rstp_bridges.values_mut().map(|rstp_bridge| {
+        let mut actions = Actions::new();
+        rstp_bridge.tick(now, &mut actions);
+        (rstp_bridge.bridge_id, actions)
+      }).collect()
+    };
+    for (bridge_id, actions) in all_actions.into_iter() {
+      run_actions(bridge_id, actions);
+    }
+  }
+}
@@ -0,0 +1,99 @@
+// static routes given on the kernel cmdline or in the startup configuration.
+//
+//   urchin.route=<prefix>/<length>,<next hop>[,<distance>]
+//
+// the next hop is one of
+//   <address>            resolved through the other routes, usually a connected one
+//   <address>%<ifindex>  a gateway on the link of the interface. link-local ipv6 gateways need this form.
+//   blackhole            drop silently
+//   reject               drop and return destination unreachable
+//
+// e.g. urchin.route=0.0.0.0/0,192.168.0.1 urchin.route=::/0,fe80::1%0 urchin.route=10.0.0.0/8,blackhole,250
+
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::devices::netif::Netif;
+use crate::devices::null::NULL_NETIF;
+use crate::net::ethernet::MacAddress;
+use crate::net::ipv4::Ipv4Address;
+use crate::net::ipv6::Ipv6Address;
+use crate::net::fib::FIBType;
+use crate::net::rib::{RouteSource, RibRoute, RibNexthop, RibAddress, add_ipv4_route, add_ipv6_route};
+use crate::cmdline;
+use crate::cmdline::{Cmdline, IpPrefix};
+use crate::NET_IFACES;
+
+fn find_netif(index: usize) -> Option<Arc<dyn Netif>> {
+  unsafe { NET_IFACES.iter().find(|netif| netif.get_id() == index).map(|netif| Arc::clone(netif)) }
+}
+
+fn build_route<A: RibAddress>(nexthop: &str, unspecified: A, parse: fn(&str) -> Result<A, &'static str>) -> Result<RibRoute<A>, &'static str> {
+  let null_nexthop = || vec![RibNexthop::new(unspecified, MacAddress::new([0; 6]), Arc::clone(&NULL_NETIF) as Arc<dyn Netif>, 1)];
+  match nexthop {
+    "blackhole" => Ok(RibRoute::new(RouteSource::Static, 0, FIBType::Blackhole, null_nexthop())),
+    "reject" => Ok(RibRoute::new(RouteSource::Static, 0, FIBType::Reject, null_nexthop())),
+    _ => match nexthop.find('%') {
+      Some(idx) => {
+        let address = parse(&nexthop[..idx])?;
+        let index = cmdline::parse_integer(&nexthop[(idx+1)..])? as usize;
+        let netif = find_netif(index).ok_or("no such interface")?;
+        Ok(RibRoute::new(
+          RouteSource::Static, 0, FIBType::RemoteUnresolved,
+          vec![RibNexthop::new(address, MacAddress::new([0; 6]), netif, 1)],
+        ))
+      },
+      None => {
+        let address = parse(nexthop)?;
+        Ok(RibRoute::new(RouteSource::Static, 0, FIBType::Remote, vec![RibNexthop::recursive(address, 1)]))
+      },
+    },
+  }
+}
+
+// the fields are the same as those of the cmdline option: prefix, next hop and optional distance
+pub fn add_static_route(prefix: &str, nexthop: &str, distance: Option<&str>) -> Result<(), &'static str> {
+  let distance = match distance {
+    Some(distance) => match cmdline::parse_integer(distance)? {
+      distance if distance <= 255 => Some(distance as u8),
+      _ => return Err("invalid distance"),
+    },
+    None => None,
+  };
+
+  match cmdline::parse_ip_prefix(prefix)? {
+    IpPrefix::V4(prefix, prefix_length) => {
+      let mut route = build_route(nexthop, Ipv4Address::from_prim(0), cmdline::parse_ipv4_address)?;
+      if let Some(distance) = distance {
+        route.set_distance(distance);
+      }
+      add_ipv4_route(prefix.masked(prefix_length), 0xffffffffu32.checked_shl(32 - prefix_length).unwrap_or(0), route);
+    },
+    IpPrefix::V6(prefix, prefix_length) => {
+      let mut route = build_route(nexthop, Ipv6Address::from_prim(0), cmdline::parse_ipv6_address)?;
+      if let Some(distance) = distance {
+        route.set_distance(distance);
+      }
+      add_ipv6_route(prefix.masked(prefix_length), prefix_length, route);
+    },
+  }
+  Ok(())
+}
+
+fn configure_static_route(arg: &str) -> Result<(), &'static str> {
+  let fields: Vec<&str> = arg.split(',').collect();
+  if fields.len() < 2 || fields.len() > 3 {
+    return Err("expected <prefix>/<length>,<next hop>[,<distance>]");
+  }
+  add_static_route(fields[0], fields[1], fields.get(2).map(|distance| *distance))
+}
+
+// install every urchin.route= of the cmdline. interfaces must be set up already.
+pub fn configure_static_routes(cmdline: &Cmdline) {
+  for option in cmdline.get_all("urchin.route") {
+    if let Some(_) = option.parse_value(configure_static_route) {
+      println!("Static route: {}", option.get_value().unwrap_or(""));
+    }
+  }
+}
@@ -0,0 +1,124 @@
+// 802.1Q vlan sub-interfaces, optionally in an 802.1ad QinQ outer tag.
+// a sub-interface is an interface of its own on top of a parent interface, with its own addresses and routes.
+// tagged frames on the parent which match a sub-interface lose their tags and are processed as the sub-interface's.
+// the others stay with the parent, e.g. to be switched by its bridge.
+//
+//   urchin.vlan=<parent interface>,<vlan id>[,<outer vlan id>]
+//
+// sub-interfaces take the next interface index, and can be named <parent>.<vlan id> or
+// <parent>.<outer vlan id>.<vlan id> wherever an interface is expected.
+// e.g. urchin.vlan=0,100 urchin.addr=0.100,10.100.0.1/24 urchin.vlan=1,20,300 urchin.addr=1.300.20,10.20.0.1/24
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::spinlock::{Spinlock, const_spinlock};
+use crate::devices::netif::Netif;
+use crate::devices::vlan::{VlanNetif, ETHERTYPE_VLAN, ETHERTYPE_QINQ};
+use crate::net::DataFromNetif;
+use crate::net::address;
+use crate::cmdline;
+use crate::cmdline::Cmdline;
+use crate::NET_IFACES;
+
+static VLANS: Spinlock<Vec<Arc<VlanNetif>>> = const_spinlock(Vec::new());
+
+pub fn parse_vlan_id(s: &str) -> Result<u16, &'static str> {
+  match cmdline::parse_integer(s)? {
+    vid if vid >= 1 && vid <= 4094 => Ok(vid as u16),
+    _ => Err("invalid vlan id"),
+  }
+}
+
+// the vlan id of a tag control information
+pub fn get_tci_vid(tci: &[u8]) -> u16 {
+  ((tci[0] as u16 & 0x0f) << 8) | tci[1] as u16
+}
+
+pub fn find_vlan(parent_id: usize, outer_vid: Option<u16>, vid: u16) -> Option<Arc<VlanNetif>> {
+  VLANS.lock().iter()
+    .find(|vlan| vlan.get_parent().get_id() == parent_id && vlan.get_outer_vid() == outer_vid && vlan.get_vid() == vid)
+    .map(|vlan| Arc::clone(vlan))
+}
+
+// the sub-interface of the interface id, if it is one
+pub fn get_vlan(netif_id: usize) -> Option<Arc<VlanNetif>> {
+  VLANS.lock().iter().find(|vlan| vlan.get_id() == netif_id).map(|vlan| Arc::clone(vlan))
+}
+
+pub fn create_vlan(parent: Arc<dyn Netif>, vid: u16, outer_vid: Option<u16>) -> Result<Arc<dyn Netif>, &'static str> {
+  let vlan = {
+    let mut vlans = VLANS.lock();
+    if vlans.iter().any(|vlan| vlan.get_parent().get_id() == parent.get_id() && vlan.get_outer_vid() == outer_vid && vlan.get_vid() == vid) {
+      return Err("vlan exists already");
+    }
+    let vlan = Arc::new(VlanNetif::new(address::next_netif_id(), parent, vid, outer_vid));
+    unsafe {
+      NET_IFACES.push(Arc::clone(&vlan) as Arc<dyn Netif>);
+    }
+    vlans.push(Arc::clone(&vlan));
+    vlan
+  };
+
+  let netif = vlan as Arc<dyn Netif>;
+  address::attach_netif(&netif);
+  Ok(netif)
+}
+
+// strip the tags of a frame for a sub-interface, and hand the frame over as the sub-interface's.
+// the double tagged frames are looked for with both tags first, then with the outer one.
+pub fn demux(frame: &DataFromNetif) -> Option<DataFromNetif> {
+  let buffer = frame.get_buffer();
+  let slice = buffer.slice();
+  let length = buffer.get_length();
+  if length < 18 {
+    return None;
+  }
+  let frame_type = [slice[12], slice[13]];
+  if frame_type != ETHERTYPE_VLAN && frame_type != ETHERTYPE_QINQ {
+    return None;
+  }
+
+  let parent_id = frame.get_netif().get_id();
+  let outer_vid = get_tci_vid(&slice[14..16]);
+  let double_tagged = length >= 22 && [slice[16], slice[17]] == ETHERTYPE_VLAN;
+  let (vlan, tag_length) = match double_tagged {
+    true => match find_vlan(parent_id, Some(outer_vid), get_tci_vid(&slice[18..20])) {
+      Some(vlan) => (vlan, 8),
+      None => (find_vlan(parent_id, None, outer_vid)?, 4),
+    },
+    false => (find_vlan(parent_id, None, outer_vid)?, 4),
+  };
+
+  buffer.slice_mut().copy_within(0..12, tag_length);
+  buffer.slide_position(tag_length);
+  Some(DataFromNetif::new(vlan as Arc<dyn Netif>, Arc::clone(buffer)))
+}
+
+// the parent by index or mac address, the vlan id and the optional outer vlan id
+pub fn add_vlan(parent: &str, vid: &str, outer_vid: Option<&str>) -> Result<Arc<dyn Netif>, &'static str> {
+  let parent = address::find_netif(parent).ok_or("no such interface")?;
+  let vid = parse_vlan_id(vid)?;
+  let outer_vid = match outer_vid {
+    Some(outer_vid) => Some(parse_vlan_id(outer_vid)?),
+    None => None,
+  };
+  create_vlan(parent, vid, outer_vid)
+}
+
+fn configure_vlan(arg: &str) -> Result<Arc<dyn Netif>, &'static str> {
+  let fields: Vec<&str> = arg.split(',').collect();
+  if fields.len() < 2 || fields.len() > 3 {
+    return Err("expected <parent interface>,<vlan id>[,<outer vlan id>]");
+  }
+  add_vlan(fields[0], fields[1], fields.get(2).map(|outer_vid| *outer_vid))
+}
+
+// create every urchin.vlan= of the cmdline. parents must be probed already.
+pub fn configure_vlans(cmdline: &Cmdline) {
+  for option in cmdline.get_all("urchin.vlan") {
+    if let Some(netif) = option.parse_value(configure_vlan) {
+      println!("VLAN: {} is interface {}", option.get_value().unwrap_or(""), netif.get_id());
+    }
+  }
+}